@@ -2,27 +2,41 @@
 
 use std::path::Path;
 
-use anyhow::Result;
-use base64::prelude::*;
+use anyhow::{bail, Result};
 use textwrap::{fill, Options};
 
+use crate::charx;
+use crate::embedded_card;
+use crate::placeholders::PlaceholderOptions;
 use crate::tavern_card_v2::{TavernCardV2, TEXT_KEY_PNG};
-use crate::tools;
+use crate::tavern_card_v3::{TavernCardV3, TEXT_KEY_PNG_V3};
+use crate::tools::{self, WriteOptions};
 
 /// Prints the content of tavern card from a given file path
 pub fn print_tavern_card_from_path(path: &Path) -> Result<()> {
     let image = tools::read_image_from_file(path)?;
-    let card = TavernCardV2::from_png_image(&image)?;
+    if charx::is_charx(&image) {
+        return charx::print_charx(&image);
+    }
+    let image = embedded_card::to_png_card(&image)?;
+    let (card, legacy_report) =
+        TavernCardV2::from_png_image_with_report(&image)?;
     println!("{}", card);
+    if let Some(legacy_report) = legacy_report {
+        print!("{}", legacy_report);
+    }
 
     Ok(())
 }
 
 /// Prints the JSON of the tavern card from path
 pub fn print_json_from_path(path: &Path) -> Result<()> {
-    let image = tools::read_image_from_file(path)?;
-    let tag = tools::read_text_chunk(&image, TEXT_KEY_PNG)?;
-    let tag = tag.map(|x| BASE64_STANDARD.decode(x).unwrap_or_default());
+    let image = tools::read_card_image_from_file(path)?;
+    let mut tag = tools::read_text_chunk(&image, TEXT_KEY_PNG_V3)?;
+    if tag.is_none() {
+        tag = tools::read_text_chunk(&image, TEXT_KEY_PNG)?;
+    }
+    let tag = tag.map(|x| tools::decode_card_payload(&x).unwrap_or_default());
     let text = tag.map(|x| String::from_utf8_lossy(&x).to_string());
     let mut text = text.unwrap_or_else(|| "NO TEXT".to_string());
     let options = Options::new(textwrap::termwidth());
@@ -32,7 +46,76 @@ pub fn print_json_from_path(path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn pretty_json(text: &str) -> Result<String> {
+/// Converts V2 card into V3 card. Saves the result to v3.<old_name.png>
+///
+/// V1 cards are converted too, with their placeholders normalized.
+pub fn upgrade_tavern_file(
+    path: &Path,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    if tools::read_text_chunk(&image, TEXT_KEY_PNG_V3)?.is_some() {
+        bail!("{} already contains a V3 card", path.display());
+    }
+    let (mut card, legacy_report) =
+        TavernCardV2::from_png_image_with_report(&image)?;
+    if let Some(legacy_report) = &legacy_report {
+        print!("{}", legacy_report);
+    }
+    // V1 cards come from apps with their own placeholders
+    if legacy_report.is_some_and(|x| x.is_v1()) {
+        let report = placeholders.normalize_v2(&mut card.data);
+        if !report.is_empty() {
+            print!("{}", report);
+        }
+    }
+    let (mut card, report) = TavernCardV3::upgrade(card);
+    print!("{}", report);
+    card.sync_v1_fields(options.v1_fields);
+
+    let fields = card.name_fields();
+    let new_image = card.into_png_image_with_compression(options.compress)?;
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(path, "v3.{stem}", fields)? else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&new_image, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Converts V3 card into V2 card. Saves the result to v2.<old_name.png>
+pub fn downgrade_tavern_file(
+    path: &Path,
+    options: &WriteOptions,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    if tools::read_text_chunk(&image, TEXT_KEY_PNG_V3)?.is_none() {
+        bail!("{} does not contain a V3 card", path.display());
+    }
+    let card = TavernCardV3::from_png_image(&image)?;
+    let (mut card, report) = card.downgrade();
+    print!("{}", report);
+    card.sync_v1_fields(options.v1_fields);
+    // Remove V3 chunk, otherwise it would be kept in sync with the V2 card.
+    card.image_data =
+        Some(tools::remove_text_from_png(TEXT_KEY_PNG_V3, &image)?);
+
+    let fields = card.name_fields();
+    let new_image = card.into_png_image_with_compression(options.compress)?;
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(path, "v2.{stem}", fields)? else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&new_image, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+pub fn pretty_json(text: &str) -> Result<String> {
     // A JSON deserializer. You can use any Serde Deserializer here.
     let mut deserializer = serde_json::Deserializer::from_str(text);
 
@@ -0,0 +1,40 @@
+//! Backyard AI data kept in card `extensions`.
+//!
+//! Cards downloaded from Backyard AI keep the character id and its model
+//! settings here, so that exporting the card back restores them.
+
+/// Key in card `extensions` for the Backyard AI settings of the character
+pub const BACKYARD_EXTENSION_KEY: &str = "backyard";
+
+/// Model and sampler settings that the author set for the character on
+/// Backyard AI
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+pub struct BackyardSettings {
+    /// Id of the character on Backyard AI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub character_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_family: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_nsfw: Option<bool>,
+}
@@ -1,25 +1,43 @@
-//! Tools to download a character from Backyard AI
+//! Tools to download a character from Backyard AI, and to export cards
+//! into its format.
 
 use std::{
+    collections::{HashMap, HashSet},
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
     thread,
     time::Duration,
 };
 
 use crate::{
+    backyard_extension::{BackyardSettings, BACKYARD_EXTENSION_KEY},
+    cookie_jar::CookieJar,
+    http_client::HttpClient,
+    json_card,
+    output_naming::{self, IfExists, NameFields, OutputOptions},
+    placeholders::PlaceholderOptions,
+    png_chunks,
     tavern_card_v2::*,
-    tools::{self, write_image_to_file},
+    tavern_card_v3::{
+        Asset, ConversionReport, TavernCardV3, DEFAULT_ASSET_URI,
+        PNG_ASSET_URI_PREFIX, TEXT_KEY_PNG_V3,
+    },
+    tools::{self, write_image_to_file, ExportOptions},
 };
 
 use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use log::info;
+use regex::Regex;
+use serde_json::Value;
 use soup::prelude::*;
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct BayaCharacter {
+    id: Option<String>,
     aiName: Option<String>,
     aiDisplayName: Option<String>,
     description: Option<String>,
@@ -31,13 +49,13 @@ pub struct BayaCharacter {
     customDialogue: Option<String>,
     firstMessage: Option<String>,
     scenario: Option<String>,
-    temperature: Option<f32>,
+    temperature: Option<f64>,
     repeatLastN: Option<i32>,
-    repeatPenalty: Option<f32>,
+    repeatPenalty: Option<f64>,
     isNsfw: Option<bool>,
     grammar: Option<String>,
-    topP: Option<f32>,
-    minP: Option<f32>,
+    topP: Option<f64>,
+    minP: Option<f64>,
     minPEnabled: Option<bool>,
     topK: Option<i32>,
     promptTemplate: Option<String>,
@@ -49,13 +67,13 @@ pub struct BayaCharacter {
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Lorebook {
     LorebookItems: Vec<LoreBookItem>,
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct LoreBookItem {
     key: String,
     order: String,
@@ -63,123 +81,1081 @@ struct LoreBookItem {
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Image {
     imageUrl: String,
     label: Option<String>,
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Tag {
     name: String,
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct Author {
     username: String,
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct ModelFamily {
     displayName: String,
     promptFormat: String,
 }
 
-pub fn download_card_from_baya_url(url: &str) -> Result<()> {
-    // Forcibly flush stdout before blocking operations, otherwise the line before long operations does not display.
-    let flush = || io::stdout().flush().unwrap();
+impl From<&BayaCharacter> for BackyardSettings {
+    fn from(character: &BayaCharacter) -> Self {
+        let family = character.ModelFamily.as_ref();
+        BackyardSettings {
+            character_id: character.id.clone(),
+            model_family: family.map(|x| x.displayName.clone()),
+            prompt_format: family.map(|x| x.promptFormat.clone()),
+            prompt_template: character
+                .promptTemplate
+                .clone()
+                .filter(|x| !x.is_empty()),
+            temperature: character.temperature,
+            top_p: character.topP,
+            min_p: character.minP,
+            min_p_enabled: character.minPEnabled,
+            top_k: character.topK,
+            repeat_penalty: character.repeatPenalty,
+            repeat_last_n: character.repeatLastN,
+            grammar: character.grammar.clone().filter(|x| !x.is_empty()),
+            is_nsfw: character.isNsfw,
+        }
+    }
+}
+
+/// Where to keep character images other than the avatar
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExtraImages {
+    /// Save them next to the card, as <name>.<label>.png
+    #[default]
+    Files,
+    /// Embed them into the card as V3 assets
+    Assets,
+}
+
+/// Options of downloading from Backyard AI
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct BayaOptions {
+    /// Image to use as the card avatar: its number (starting from 0) or
+    /// label. The first image is used by default
+    #[arg(long)]
+    pub avatar: Option<String>,
+
+    /// Where to keep the other images of the character
+    #[arg(long, value_enum, default_value_t)]
+    pub images: ExtraImages,
+
+    /// cookies.txt exported from a browser logged in to Backyard AI, for
+    /// characters that need an account
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub cookies: Option<PathBuf>,
+
+    /// Backyard AI session token: the value of its session cookie
+    #[arg(long, env = SESSION_TOKEN_VARIABLE, hide_env_values = true)]
+    pub session_token: Option<String>,
+
+    /// How many times to retry requests that failed with a network error,
+    /// 429 or 5xx status
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Milliseconds to wait between the starts of requests
+    #[arg(long, default_value_t = 500)]
+    pub delay: u64,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+
+    #[command(flatten)]
+    pub placeholders: PlaceholderOptions,
+}
+
+/// Environment variable with the Backyard AI session token
+const SESSION_TOKEN_VARIABLE: &str = "BACKYARD_SESSION_TOKEN";
+const SESSION_COOKIE: &str = "__Secure-next-auth.session-token";
+const BACKYARD_DOMAIN: &str = "backyard.ai";
+
+const LOGIN_REQUIRED: &str = "This character needs a logged in Backyard AI \
+    account. Pass cookies.txt exported from your browser with --cookies, or \
+    the session token with --session-token or BACKYARD_SESSION_TOKEN";
+
+impl BayaOptions {
+    fn cookie_jar(&self) -> Result<CookieJar> {
+        let mut jar = match &self.cookies {
+            Some(path) => CookieJar::read_file(path)?,
+            None => CookieJar::default(),
+        };
+        if let Some(token) = &self.session_token {
+            jar.add(BACKYARD_DOMAIN, SESSION_COOKIE, token.trim());
+        }
+        Ok(jar)
+    }
 
-    print!("Downloading web page: ");
+    fn http_client(&self) -> Result<HttpClient> {
+        Ok(HttpClient::new(self.cookie_jar()?)
+            .with_interval(Duration::from_millis(self.delay))
+            .with_retries(self.retries, Duration::from_secs(1)))
+    }
+}
+
+/// Makes a tavern card from a Backyard AI character
+///
+/// `source` is a URL of the character page, a path to a saved copy of the
+/// page or to the character JSON, or `-` to read either of them from stdin.
+pub fn download_card_from_baya(
+    source: &str,
+    options: &BayaOptions,
+) -> Result<()> {
+    let flush = || io::stdout().flush().unwrap();
+    let client = options.http_client()?;
+    let baya_character = read_character(source, &client, true)?;
+    let target = BatchTarget {
+        template: "{name}",
+        if_exists: IfExists::Number,
+        ..Default::default()
+    };
+    if write_character(&baya_character, &client, options, &target, true)?
+        .is_none()
+    {
+        return Ok(());
+    }
+    print!("Fap away!");
+    flush();
+    thread::sleep(Duration::from_millis(150));
+    println!("\rAll done!");
     flush();
-    let body = tools::download_page(url)?;
-    println!("Done!");
+    Ok(())
+}
+
+/// Prints a step of the download, if output is verbose
+fn progress(verbose: bool, text: &str) {
+    if verbose {
+        print!("{}", text);
+        // Forcibly flush stdout before blocking operations, otherwise the line before long operations does not display.
+        io::stdout().flush().unwrap();
+    }
+}
 
-    print!("Parsing downloaded page: ");
-    let baya_character =
-        parse_page(&body).context("Could not parse character JSON")?;
-    println!("Done!");
+/// Downloads or reads the character and parses it
+fn read_character(
+    source: &str,
+    client: &HttpClient,
+    verbose: bool,
+) -> Result<BayaCharacter> {
+    let body = if is_url(source) {
+        progress(verbose, "Downloading web page: ");
+        let body = download_character_page(source, client)?;
+        progress(verbose, "Done!\n");
+        body
+    } else if source == "-" {
+        io::read_to_string(io::stdin()).context("Could not read stdin")?
+    } else {
+        std::fs::read_to_string(source)
+            .with_context(|| format!("Could not read {}", source))?
+    };
+
+    progress(verbose, "Parsing character: ");
+    let mut baya_character = parse_input(&body)?;
+    progress(verbose, "Done!\n");
+    if baya_character.id.is_none() {
+        baya_character.id = character_id_from_url(source);
+    }
+    Ok(baya_character)
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
 
+/// Downloads images of the character and writes its card
+///
+/// Returns the path of the card.
+fn write_character(
+    baya_character: &BayaCharacter,
+    client: &HttpClient,
+    options: &BayaOptions,
+    target: &BatchTarget,
+    verbose: bool,
+) -> Result<Option<PathBuf>> {
     let display_char_name: String = baya_character
         .aiDisplayName
         .clone()
         .unwrap_or_else(|| "NO_NAME_SET".to_string());
-    println!("Character name is: {}", display_char_name);
+    progress(verbose, &format!("Character name is: {}\n", display_char_name));
+
+    let fields = NameFields {
+        name: Some(display_char_name.clone()),
+        id: baya_character.id.clone(),
+        creator: baya_character.Author.as_ref().map(|x| x.username.clone()),
+        stem: None,
+    };
+    let avatar_index =
+        select_avatar(&baya_character.Images, options.avatar.as_deref())?;
+    let mut extensions = vec!["png".to_string()];
+    if options.images == ExtraImages::Files {
+        extensions.extend(side_image_extensions(
+            &baya_character.Images,
+            avatar_index,
+        ));
+    }
+    let extensions: Vec<&str> = extensions.iter().map(|x| x.as_str()).collect();
+    let paths = options.output.claim_all(
+        &target.dir,
+        target.template,
+        &fields,
+        &extensions,
+        target.if_exists,
+    )?;
+    let Some(paths) = paths else {
+        return Ok(None);
+    };
+    let result = write_card_files(
+        baya_character,
+        client,
+        options,
+        avatar_index,
+        &paths,
+        verbose,
+    );
+    // Names may be held by empty files, of failed downloads or of the card
+    // that could not be written. Free them for the next try.
+    for path in &paths {
+        output_naming::release_path(path);
+    }
+    result.map(|_| Some(paths[0].clone()))
+}
 
+/// Downloads images of the character, and writes the card
+///
+/// The card goes to the first of `paths`. With `--images files`, the rest
+/// are for the other images, in order.
+fn write_card_files(
+    baya_character: &BayaCharacter,
+    client: &HttpClient,
+    options: &BayaOptions,
+    avatar_index: usize,
+    paths: &[PathBuf],
+    verbose: bool,
+) -> Result<()> {
+    let display_char_name =
+        baya_character.aiDisplayName.as_deref().unwrap_or("NO_NAME_SET");
     info!("\nCHARACTER INFO:\n{:#?}", &baya_character);
 
-    // Download the image, if it is linked on the page. Otherwise, use default image.
+    // Download all images linked on the page. Otherwise, use default image.
     let mut card_image = None;
-    if !baya_character.Images.is_empty() {
-        // Download the first image linked on card.
-        let url = &baya_character.Images[0].imageUrl;
-        print!("Downloading image: ");
-        flush();
-        // Try to download image and check result
-        let mut temp_img = tools::download_image(url);
-        match temp_img {
-            Err(e) => eprintln!("Could not download image because {}", e),
-            Ok(img) => {
-                // Try to convert img and check result
-                temp_img = tools::convert_to_png(&img);
-                match temp_img {
-                    Ok(img) => card_image = Some(img),
-                    Err(e) => eprintln!(
-                        "Could not convert image to PNG because {}",
-                        e
-                    ),
-                };
+    let mut extra_images = Vec::new();
+    let mut side_paths = paths[1..].iter();
+    let image_count = baya_character.Images.len();
+    for (i, image) in baya_character.Images.iter().enumerate() {
+        let side_path = match i == avatar_index {
+            true => None,
+            false => side_paths.next(),
+        };
+        let text = format!("Downloading image {} of {}: ", i + 1, image_count);
+        progress(verbose, &text);
+        let png = tools::download_image(&image.imageUrl, client)
+            .and_then(|x| tools::convert_to_png(&x));
+        match png {
+            Err(e) => eprintln!(
+                "Could not download image of {} because {}",
+                display_char_name, e
+            ),
+            Ok(png) if i == avatar_index => {
+                card_image = Some(png);
+                progress(verbose, "Done! (avatar)\n");
+            }
+            Ok(png) => {
+                extra_images.push((image_label(image, i), png, side_path));
+                progress(verbose, "Done!\n");
             }
         };
-    } else {
-        print!("No image provided, using default image.");
     }
-    println!("Done!");
+    if image_count == 0 {
+        progress(verbose, "No image provided, using default image.\n");
+    }
 
-    print!("Writing tavern card: ");
-    flush();
-    let mut tavern_card = TavernCardV2::from(&baya_character);
+    let mut tavern_card = TavernCardV2::from(baya_character);
     tavern_card.image_data = card_image;
+    let report = options.placeholders.normalize_v2(&mut tavern_card.data);
+    if !report.is_empty() {
+        progress(verbose, &report.to_string());
+    }
+    progress(verbose, "Writing tavern card: ");
 
     info!("\nCONVERTED TAVERN CARD:\n{:#?}", &tavern_card);
 
-    let tavern_image =
-        tavern_card.into_png_image().context("Could not write tavern card")?;
-    let card_name = PathBuf::from(format!("{}.png", display_char_name));
-    write_image_to_file(&tavern_image, &card_name)?;
-    println!("Done!");
-    print!("Fap away!");
-    flush();
-    thread::sleep(Duration::from_millis(150));
-    println!("\rAll done!");
-    flush();
+    let tavern_image = match options.images {
+        ExtraImages::Files => tavern_card.into_png_image(),
+        ExtraImages::Assets => {
+            let (mut card, _) = TavernCardV3::upgrade(tavern_card);
+            let images = extra_images.drain(..).map(|x| (x.0, x.1)).collect();
+            add_image_assets(&mut card, images);
+            card.into_png_image()
+        }
+    };
+    let tavern_image = tavern_image.context("Could not write tavern card")?;
+    write_image_to_file(&tavern_image, &paths[0])?;
+    // Images next to the card go only with a written card
+    for (_, png, path) in &extra_images {
+        if let Some(path) = path {
+            write_image_to_file(png, path)?;
+        }
+    }
+    progress(verbose, "Done!\n");
     Ok(())
 }
 
-/// Extracts character data from the downloaded web page.
-fn parse_page(body: &str) -> Result<BayaCharacter> {
+/// Options of downloading many characters at once
+#[derive(clap::Args, Debug, Clone)]
+pub struct BatchOptions {
+    /// File with more URLs to download, one per line, or - for stdin
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub batch: Option<PathBuf>,
+
+    /// How many characters to download at the same time
+    #[arg(long, default_value_t = 4)]
+    pub jobs: usize,
+
+    /// List of downloaded character ids. Characters in it are skipped
+    #[arg(long, default_value = "backyard_archive.txt")]
+    pub archive: PathBuf,
+}
+
+/// Ids of characters downloaded before, one per line with the name
+struct Archive {
+    path: PathBuf,
+    ids: Mutex<HashSet<String>>,
+}
+
+impl Archive {
+    fn open(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            result => result.with_context(|| {
+                format!("Could not read archive {}", path.display())
+            })?,
+        };
+        let ids = text
+            .lines()
+            .filter_map(|x| x.split_whitespace().next())
+            .map(|x| x.to_string())
+            .collect();
+        Ok(Self { path: path.to_path_buf(), ids: Mutex::new(ids) })
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.lock().unwrap().contains(id)
+    }
+
+    fn add(&self, id: &str, name: &str) -> Result<()> {
+        let mut ids = self.ids.lock().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}\t{}", id, name)?;
+        ids.insert(id.to_string());
+        Ok(())
+    }
+}
+
+/// Id of the character in the URL of its page
+fn character_id_from_url(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let mut segments = url.path_segments()?;
+    segments.find(|x| *x == "character")?;
+    segments.next().filter(|x| !x.is_empty()).map(|x| x.to_string())
+}
+
+/// Sources from the command line and the batch file, without comments
+fn batch_sources(
+    sources: &[String],
+    batch: Option<&Path>,
+) -> Result<Vec<String>> {
+    let mut result = sources.to_vec();
+    if let Some(path) = batch {
+        let text = if path == Path::new("-") {
+            io::read_to_string(io::stdin()).context("Could not read stdin")?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read {}", path.display()))?
+        };
+        let lines = text.lines().map(|x| x.trim());
+        let lines = lines.filter(|x| !x.is_empty() && !x.starts_with('#'));
+        result.extend(lines.map(|x| x.to_string()));
+    }
+    Ok(result)
+}
+
+enum BatchResult {
+    Saved(PathBuf),
+    Skipped(&'static str),
+}
+
+/// Where a batch writes cards and which characters it skips
+#[derive(Default)]
+struct BatchTarget {
+    /// Folder, name template and policy for existing files of the cards,
+    /// unless the output options set them
+    dir: PathBuf,
+    template: &'static str,
+    if_exists: IfExists,
+    /// Characters in the archive are skipped, downloaded ones are added
+    archive: Option<Archive>,
+    /// Update times of the cards already in `dir`, by character id. If
+    /// set, characters that were not updated since are skipped
+    local_updates: Option<HashMap<String, i64>>,
+    /// Update times known before downloading, like from creator listing
+    listed_updates: HashMap<String, i64>,
+}
+
+impl BatchTarget {
+    fn skip_reason(
+        &self,
+        id: &str,
+        updated: Option<i64>,
+    ) -> Option<&'static str> {
+        if self.archive.as_ref().is_some_and(|x| x.contains(id)) {
+            return Some("already downloaded");
+        }
+        let local = self.local_updates.as_ref()?.get(id)?;
+        let updated = updated.or(self.listed_updates.get(id).copied())?;
+        (updated <= *local).then_some("not updated")
+    }
+}
+
+/// Downloads many characters, several at a time
+///
+/// Characters in the archive are skipped, downloaded ones are added to it.
+/// Fails at the end if any of the characters failed.
+pub fn download_batch_from_baya(
+    sources: &[String],
+    options: &BayaOptions,
+    batch: &BatchOptions,
+) -> Result<()> {
+    let sources = batch_sources(sources, batch.batch.as_deref())?;
+    let target = BatchTarget {
+        template: "{name}",
+        if_exists: IfExists::Number,
+        archive: Some(Archive::open(&batch.archive)?),
+        ..Default::default()
+    };
+    let client = options.http_client()?;
+    run_batch(&sources, &client, options, batch.jobs, &target)
+}
+
+fn run_batch(
+    sources: &[String],
+    client: &HttpClient,
+    options: &BayaOptions,
+    jobs: usize,
+    target: &BatchTarget,
+) -> Result<()> {
+    if sources.is_empty() {
+        bail!("No characters to download");
+    }
+    if options.output.output.is_some() && sources.len() > 1 {
+        bail!("--output names one file, use --output-dir or --name-template");
+    }
+    let total = sources.len();
+    println!("Downloading {} characters", total);
+
+    let download = |source: &str| -> Result<BatchResult> {
+        let url_id = character_id_from_url(source);
+        if let Some(reason) =
+            url_id.as_deref().and_then(|x| target.skip_reason(x, None))
+        {
+            return Ok(BatchResult::Skipped(reason));
+        }
+        let character = read_character(source, client, false)?;
+        let id = character.id.clone().or(url_id);
+        let updated = character.updatedAt.timestamp();
+        if let Some(reason) =
+            id.as_deref().and_then(|x| target.skip_reason(x, Some(updated)))
+        {
+            return Ok(BatchResult::Skipped(reason));
+        }
+        let Some(path) =
+            write_character(&character, client, options, target, false)?
+        else {
+            return Ok(BatchResult::Skipped("file exists"));
+        };
+        if let (Some(id), Some(archive)) = (id, &target.archive) {
+            let name = character.aiDisplayName.as_deref().unwrap_or_default();
+            archive.add(&id, name)?;
+        }
+        Ok(BatchResult::Saved(path))
+    };
+
+    let next = Mutex::new(0);
+    let failures = Mutex::new(Vec::new());
+    let (saved, skipped) = (Mutex::new(0), Mutex::new(0));
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, total) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                let Some(source) = sources.get(index) else {
+                    break;
+                };
+                let prefix = format!("[{}/{}] {}", index + 1, total, source);
+                match download(source) {
+                    Ok(BatchResult::Saved(path)) => {
+                        println!("{}: saved {}", prefix, path.display());
+                        *saved.lock().unwrap() += 1;
+                    }
+                    Ok(BatchResult::Skipped(reason)) => {
+                        println!("{}: {}", prefix, reason);
+                        *skipped.lock().unwrap() += 1;
+                    }
+                    Err(e) => {
+                        println!("{}: failed: {}", prefix, e);
+                        failures.lock().unwrap().push((source, e));
+                    }
+                }
+            });
+        }
+    });
+
+    let failures = failures.into_inner().unwrap();
+    println!(
+        "\nSaved {}, skipped {}, failed {}",
+        saved.into_inner().unwrap(),
+        skipped.into_inner().unwrap(),
+        failures.len()
+    );
+    for (source, error) in &failures {
+        println!("    {}: {}", source, error);
+    }
+    if !failures.is_empty() {
+        bail!("{} of {} characters failed", failures.len(), total);
+    }
+    Ok(())
+}
+
+/// Options of downloading all characters of a creator
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct CreatorOptions {
+    /// URL of a creator profile at Backyard AI. All characters of the
+    /// creator are downloaded into a folder named after them
+    #[arg(
+        long,
+        value_name = "PROFILE_URL",
+        conflicts_with_all = ["sources", "batch"]
+    )]
+    pub creator: Option<String>,
+
+    /// Download only characters that are new or were updated after the
+    /// cards in the creator folder
+    #[arg(
+        long,
+        requires = "creator",
+        conflicts_with_all = ["sources", "batch"]
+    )]
+    pub incremental: bool,
+}
+
+/// Stop listing a creator after this many pages, in case the site keeps
+/// repeating the last one
+const MAX_CREATOR_PAGES: u32 = 500;
+
+/// A character in the listing of a creator
+#[derive(Debug, PartialEq)]
+struct ListedCharacter {
+    id: String,
+    /// Unix time of the last update, if the listing has it
+    updated: Option<i64>,
+}
+
+/// Where cards of a creator go, unless the output options say otherwise
+const CREATOR_TEMPLATE: &str = "{creator}/{name}";
+
+/// Downloads all characters of a creator into a folder named after them
+pub fn download_creator_from_baya(
+    profile_url: &str,
+    options: &BayaOptions,
+    creator_options: &CreatorOptions,
+    jobs: usize,
+) -> Result<()> {
+    let client = options.http_client()?;
+    let creator = creator_name_from_url(profile_url)?;
+    println!("Listing characters of {}", creator);
+    let listed = list_creator_characters(profile_url, &creator, &client)?;
+    println!("Found {} characters", listed.len());
+
+    let base = reqwest::Url::parse(profile_url)?;
+    let sources: Vec<String> = listed
+        .iter()
+        .map(|x| base.join(&format!("/hub/character/{}", x.id)))
+        .map(|x| x.map(String::from))
+        .collect::<Result<_, _>>()?;
+    let local_updates = if creator_options.incremental {
+        let fields =
+            NameFields { creator: Some(creator.clone()), ..Default::default() };
+        let dir = options.output.common_dir(
+            Path::new(""),
+            CREATOR_TEMPLATE,
+            &fields,
+        )?;
+        Some(local_updates(&dir))
+    } else {
+        None
+    };
+    let target = BatchTarget {
+        template: CREATOR_TEMPLATE,
+        if_exists: IfExists::Overwrite,
+        local_updates,
+        listed_updates: listed
+            .iter()
+            .filter_map(|x| Some((x.id.clone(), x.updated?)))
+            .collect(),
+        ..Default::default()
+    };
+    run_batch(&sources, &client, options, jobs, &target)
+}
+
+/// Name of the creator in the URL of their profile
+fn creator_name_from_url(url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url).context("Invalid profile URL")?;
+    let segments: Vec<&str> = parsed
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|x| !x.is_empty())
+        .collect();
+    let after_user = segments
+        .iter()
+        .position(|x| ["user", "creator", "u"].contains(x))
+        .and_then(|i| segments.get(i + 1));
+    let name = after_user
+        .or(segments.last())
+        .context("Could not find the creator name in the profile URL")?;
+    Ok(name.to_string())
+}
+
+/// Walks all pages of the creator listing
+///
+/// The next page is the `rel="next"` link of the page, or the URL with the
+/// next `page` number. Listing stops at a page without new characters.
+fn list_creator_characters(
+    profile_url: &str,
+    creator: &str,
+    client: &HttpClient,
+) -> Result<Vec<ListedCharacter>> {
+    let base = reqwest::Url::parse(profile_url)?;
+    let mut found: Vec<ListedCharacter> = Vec::new();
+    let mut page_url = base.clone();
+    for page in 1..=MAX_CREATOR_PAGES {
+        let body = download_character_page(page_url.as_str(), client)?;
+        let (listed, next) = parse_listing(&body, creator, &base);
+        let new: Vec<ListedCharacter> = listed
+            .into_iter()
+            .filter(|x| !found.iter().any(|y| y.id == x.id))
+            .collect();
+        info!(
+            "Page {} of {} lists {} new characters",
+            page,
+            creator,
+            new.len()
+        );
+        if new.is_empty() {
+            break;
+        }
+        found.extend(new);
+        page_url = match next {
+            Some(next) => next,
+            None => {
+                let mut url = base.clone();
+                let query: Vec<(String, String)> = base
+                    .query_pairs()
+                    .filter(|(key, _)| key != "page")
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+                url.query_pairs_mut()
+                    .clear()
+                    .extend_pairs(query)
+                    .append_pair("page", &(page + 1).to_string());
+                url
+            }
+        };
+    }
+    if found.is_empty() {
+        bail!("Found no characters on the profile of {}", creator);
+    }
+    Ok(found)
+}
+
+/// Characters on a page of the creator listing, and the next page link
+///
+/// Characters are taken from `__NEXT_DATA__`, skipping the ones whose
+/// author is someone else. Pages without it are searched for character
+/// links.
+fn parse_listing(
+    body: &str,
+    creator: &str,
+    base: &reqwest::Url,
+) -> (Vec<ListedCharacter>, Option<reqwest::Url>) {
     let soup = soup::Soup::new(body);
-    let scr = soup
+    let mut listed: Vec<ListedCharacter> = Vec::new();
+    let mut add = |id: &str, updated: Option<i64>| {
+        if !listed.iter().any(|x| x.id == id) {
+            listed.push(ListedCharacter { id: id.to_string(), updated });
+        }
+    };
+
+    let next_data = soup
         .tag("script")
         .attr("id", "__NEXT_DATA__")
         .find()
-        .context("Did not find __NEXT_DATA__")?;
+        .and_then(|x| serde_json::from_str::<Value>(&x.text()).ok());
+    let objects = next_data.as_ref().map(all_objects).unwrap_or_default();
+    let mut found_in_data = false;
+    for object in objects {
+        let id = object.get("id").and_then(Value::as_str);
+        let is_character =
+            object.get("aiDisplayName").or(object.get("aiName")).is_some();
+        let (Some(id), true) = (id, is_character) else {
+            continue;
+        };
+        let author = object.pointer("/Author/username").and_then(Value::as_str);
+        if author.is_some_and(|x| !x.eq_ignore_ascii_case(creator)) {
+            continue;
+        }
+        let updated = object
+            .get("updatedAt")
+            .and_then(Value::as_str)
+            .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+            .map(|x| x.timestamp());
+        add(id, updated);
+        found_in_data = true;
+    }
+    if !found_in_data {
+        for link in soup.tag("a").find_all() {
+            let href = link.get("href").unwrap_or_default();
+            let url = base.join(&href).map(String::from).unwrap_or_default();
+            if let Some(id) = character_id_from_url(&url) {
+                add(&id, None);
+            }
+        }
+    }
+
+    let next = soup
+        .tag("a")
+        .attr("rel", "next")
+        .find()
+        .and_then(|x| x.get("href"))
+        .and_then(|x| base.join(&x).ok());
+    (listed, next)
+}
+
+/// Update times of Backyard cards in the folder and its subfolders, by
+/// character id
+fn local_updates(dir: &Path) -> HashMap<String, i64> {
+    let mut result = HashMap::new();
+    let mut folders = vec![dir.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = std::fs::read_dir(&folder) else {
+            continue;
+        };
+        for path in entries.filter_map(|x| Some(x.ok()?.path())) {
+            if path.is_dir() {
+                folders.push(path);
+            } else if let Some((id, updated)) = card_update(&path) {
+                result.insert(id, updated);
+            }
+        }
+    }
+    result
+}
+
+/// Backyard character id and update time of a card file
+fn card_update(path: &Path) -> Option<(String, i64)> {
+    if path.extension().is_none_or(|x| x != "png") {
+        return None;
+    }
+    let data = std::fs::read(path).ok()?;
+    let chunks = png_chunks::read_chunks(&data).ok()?;
+    let id_pointer =
+        format!("/data/extensions/{}/character_id", BACKYARD_EXTENSION_KEY);
+    tools::read_card_chunks(&chunks).ok()?.into_iter().find_map(|(_, json)| {
+        let id = json.pointer(&id_pointer)?.as_str()?;
+        let updated = json.pointer("/data/modification_date")?.as_i64()?;
+        Some((id.to_string(), updated))
+    })
+}
+
+/// Finds the image to use as avatar, by its number or label
+fn select_avatar(images: &[Image], selector: Option<&str>) -> Result<usize> {
+    let Some(selector) = selector else {
+        return Ok(0);
+    };
+    if let Ok(index) = selector.parse::<usize>() {
+        if index >= images.len() {
+            bail!(
+                "There is no image {}, the character has {} images",
+                index,
+                images.len()
+            );
+        }
+        return Ok(index);
+    }
+    let found = images.iter().position(|x| {
+        x.label.as_ref().is_some_and(|x| x.eq_ignore_ascii_case(selector))
+    });
+    match found {
+        Some(index) => Ok(index),
+        None => {
+            let labels: Vec<String> = images
+                .iter()
+                .enumerate()
+                .map(|(i, x)| image_label(x, i))
+                .collect();
+            bail!(
+                "No image labelled '{}'. Images: {}",
+                selector,
+                labels.join(", ")
+            )
+        }
+    }
+}
+
+/// Label of the image, or its number if it has none
+fn image_label(image: &Image, index: usize) -> String {
+    match image.label.as_deref().map(|x| x.trim()) {
+        Some(label) if !label.is_empty() => label.to_string(),
+        _ => format!("image{}", index),
+    }
+}
+
+/// Extensions of the images written next to the card, like `happy.png`
+///
+/// One for each image but the avatar, in order. Images with the same label
+/// get their number added.
+fn side_image_extensions(images: &[Image], avatar_index: usize) -> Vec<String> {
+    let mut extensions: Vec<String> = Vec::new();
+    for (i, image) in images.iter().enumerate() {
+        if i == avatar_index {
+            continue;
+        }
+        let label = file_name_part(&image_label(image, i));
+        let mut extension = format!("{}.png", label);
+        if extensions.contains(&extension) {
+            extension = format!("{}_{}.png", label, i);
+        }
+        extensions.push(extension);
+    }
+    extensions
+}
+
+/// Makes text safe to use in a file name
+fn file_name_part(text: &str) -> String {
+    text.chars()
+        .map(|x| if x.is_alphanumeric() || x == '-' { x } else { '_' })
+        .collect()
+}
+
+/// Embeds images into the card as alternate avatars
+///
+/// The card image stays the main icon. Other images are added as icon
+/// assets, stored in PNG chunks and named by their labels.
+fn add_image_assets(card: &mut TavernCardV3, images: Vec<(String, Bytes)>) {
+    let assets = card.data.assets.get_or_insert_with(Vec::new);
+    if !assets.iter().any(|x| x.uri == DEFAULT_ASSET_URI) {
+        assets.push(Asset {
+            asset_type: "icon".to_string(),
+            uri: DEFAULT_ASSET_URI.to_string(),
+            name: "main".to_string(),
+            ext: "png".to_string(),
+        });
+    }
+    for (i, (label, png)) in images.into_iter().enumerate() {
+        let uri = format!("{}{}", PNG_ASSET_URI_PREFIX, i);
+        assets.push(Asset {
+            asset_type: "icon".to_string(),
+            uri: uri.clone(),
+            name: label,
+            ext: "png".to_string(),
+        });
+        card.asset_data.insert(uri, png);
+    }
+}
+
+/// Downloads the character page, failing if it asks to log in
+fn download_character_page(url: &str, client: &HttpClient) -> Result<String> {
+    let response = client.get(url)?;
+    let status = response.status();
+    let redirected_to_login = is_login_path(response.url().path());
+    if status == reqwest::StatusCode::UNAUTHORIZED
+        || status == reqwest::StatusCode::FORBIDDEN
+        || redirected_to_login
+    {
+        bail!(LOGIN_REQUIRED);
+    }
+    if !status.is_success() {
+        bail!("Failed to download the web page: {:?}", status);
+    }
+    Ok(response.text()?)
+}
+
+/// Whether the path leads to the login page, like `/auth/login`
+///
+/// Whole segments are compared, so that `/author` is not a login page.
+fn is_login_path(path: &str) -> bool {
+    path.to_lowercase().split('/').any(|segment| {
+        ["auth", "login", "signin", "sign-in"].contains(&segment)
+    })
+}
+
+/// Whether the page without a character is the login page
+fn is_login_page(soup: &soup::Soup, next_data: Option<&Value>) -> bool {
+    let page = next_data.and_then(|x| x.get("page")).and_then(Value::as_str);
+    page.is_some_and(is_login_path)
+        || soup.tag("input").attr("type", "password").find().is_some()
+}
+
+/// Extracts character data from a web page or JSON
+///
+/// JSON is either the character itself or the whole `__NEXT_DATA__` of
+/// the page.
+fn parse_input(body: &str) -> Result<BayaCharacter> {
+    if body.trim_start().starts_with('{') {
+        let json = serde_json::from_str(body)
+            .context("JSON was not well-formatted")?;
+        parse_character_json(json)
+    } else {
+        parse_page(body)
+    }
+}
+
+/// Extracts character data from the downloaded web page.
+fn parse_page(body: &str) -> Result<BayaCharacter> {
+    let soup = soup::Soup::new(body);
+    let Some(scr) = soup.tag("script").attr("id", "__NEXT_DATA__").find()
+    else {
+        if is_login_page(&soup, None) {
+            bail!(LOGIN_REQUIRED);
+        }
+        bail!("Did not find __NEXT_DATA__");
+    };
 
     let scr_text = scr.text();
 
     info!("\nSCRIPT DATA:\n{:#?}", &scr_text);
 
-    let mut json: serde_json::Value = serde_json::from_str(&scr.text())
+    let json: Value = serde_json::from_str(&scr_text)
         .context("JSON was not well-formatted")?;
+    if find_character(&json).is_err() && is_login_page(&soup, Some(&json)) {
+        bail!(LOGIN_REQUIRED);
+    }
+    parse_character_json(json)
+}
+
+/// A known place of the character in `__NEXT_DATA__`
+struct Layout {
+    name: &'static str,
+    find: fn(&Value) -> Vec<&Value>,
+}
+
+/// Layouts of Backyard pages, tried in turn
+const LAYOUTS: &[Layout] = &[
+    Layout {
+        name: "tRPC queries (pageProps.trpcState.json.queries[*].state.data)",
+        find: |json| {
+            let queries = json
+                .pointer("/props/pageProps/trpcState/json/queries")
+                .and_then(Value::as_array);
+            let data = queries
+                .into_iter()
+                .flatten()
+                .filter_map(|query| query.pointer("/state/data"));
+            data.flat_map(|x| [x.get("character"), Some(x)]).flatten().collect()
+        },
+    },
+    Layout {
+        name: "page props (pageProps.character)",
+        find: |json| {
+            ["/props/pageProps/character", "/props/pageProps/data/character"]
+                .iter()
+                .filter_map(|x| json.pointer(x))
+                .collect()
+        },
+    },
+    Layout { name: "search of the whole page data", find: all_objects },
+];
 
-    let pointer =
-        "/props/pageProps/trpcState/json/queries/0/state/data/character";
-    let char_json =
-        json.pointer_mut(pointer).context("Could not find character block")?;
+/// All objects in the JSON tree, in document order
+fn all_objects(json: &Value) -> Vec<&Value> {
+    let mut found = Vec::new();
+    let mut stack = vec![json];
+    while let Some(value) = stack.pop() {
+        match value {
+            Value::Object(map) => {
+                found.push(value);
+                stack.extend(map.values().rev());
+            }
+            Value::Array(array) => stack.extend(array.iter().rev()),
+            _ => {}
+        }
+    }
+    found
+}
+
+/// Whether the JSON object is a full character, not a short listing
+fn looks_like_character(json: &Value) -> bool {
+    let has = |key| json.get(key).is_some();
+    (has("aiDisplayName") || has("aiName"))
+        && has("createdAt")
+        && (has("aiPersona") || has("firstMessage") || has("scenario"))
+}
 
-    let json_string = serde_json::to_string_pretty(&char_json)?;
+/// Finds the character in `__NEXT_DATA__` of a page
+///
+/// JSON that is a character itself is returned as it is. When a layout
+/// has several characters, like a page with related characters, the one
+/// with the id of the page is taken, otherwise the most complete one.
+fn find_character(json: &Value) -> Result<&Value> {
+    if looks_like_character(json) {
+        return Ok(json);
+    }
+    let page_id = json.pointer("/query/id").and_then(Value::as_str);
+    for layout in LAYOUTS {
+        let found: Vec<&Value> = (layout.find)(json)
+            .into_iter()
+            .filter(|x| looks_like_character(x))
+            .collect();
+        if found.is_empty() {
+            continue;
+        }
+        info!("Found {} characters in {}", found.len(), layout.name);
+        let with_page_id = found.iter().find(|x| {
+            page_id.is_some() && x.get("id").and_then(Value::as_str) == page_id
+        });
+        let size = |x: &Value| x.as_object().map_or(0, |x| x.len());
+        // Take the first of equally complete characters
+        let most_complete = found.iter().rev().max_by_key(|x| size(x)).copied();
+        return Ok(with_page_id.copied().or(most_complete).unwrap());
+    }
+    let tried: Vec<&str> = LAYOUTS.iter().map(|x| x.name).collect();
+    bail!(
+        "Could not find character block. Tried layouts:\n    {}",
+        tried.join("\n    ")
+    )
+}
+
+fn parse_character_json(json: Value) -> Result<BayaCharacter> {
+    let json = find_character(&json)?;
+
+    let json_string = serde_json::to_string_pretty(&json)?;
     info!("\nCHAR JSON:\n{:#?}", &json_string);
 
     let ds = &mut serde_json::Deserializer::from_str(&json_string);
@@ -197,43 +1173,6 @@ fn parse_page(body: &str) -> Result<BayaCharacter> {
     }
 }
 
-/// Replace all instances of User
-///
-/// The convention on Backyard characters is to adress user as "User" while SillyTavern convention
-/// is to use {{user}} instead. This function replaces all instances of User, trying to
-/// ignore compound words like Userland.
-fn convert_user_tag(text: &str) -> String {
-    const CONVERT_FROM: &str = "User";
-    const CONVERT_INTO: &str = "{{user}}";
-    let mut result = String::new();
-    let mut last_match_end = 0;
-
-    for (start, part) in text
-        .match_indices(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
-    {
-        if start > last_match_end {
-            let word = &text[last_match_end..start];
-            if word == CONVERT_FROM {
-                result.push_str(CONVERT_INTO);
-            } else {
-                result.push_str(word);
-            }
-        }
-        result.push_str(part);
-        last_match_end = start + part.len();
-    }
-
-    if last_match_end < text.len() {
-        let last_word = &text[last_match_end..];
-        if last_word == CONVERT_FROM {
-            result.push_str(CONVERT_INTO);
-        } else {
-            result.push_str(last_word);
-        }
-    }
-    result
-}
-
 impl From<&BayaCharacter> for TavernCardV2 {
     fn from(character: &BayaCharacter) -> Self {
         let mut new_character = TavernCardV2::new();
@@ -242,20 +1181,14 @@ impl From<&BayaCharacter> for TavernCardV2 {
         let transfer_string =
             |s: &Option<String>| s.clone().filter(|x| !x.is_empty());
 
-        let transfer_string_and_conv =
-            |s| transfer_string(s).map(|x| convert_user_tag(&x));
-
         card_data.name = transfer_string(&character.aiDisplayName);
-        card_data.description = transfer_string_and_conv(&character.aiPersona);
-        card_data.scenario = transfer_string_and_conv(&character.scenario);
-        card_data.first_mes = transfer_string_and_conv(&character.firstMessage);
-        card_data.mes_example =
-            transfer_string_and_conv(&character.customDialogue);
+        card_data.description = transfer_string(&character.aiPersona);
+        card_data.scenario = transfer_string(&character.scenario);
+        card_data.first_mes = transfer_string(&character.firstMessage);
+        card_data.mes_example = transfer_string(&character.customDialogue);
         card_data.creator_notes = transfer_string(&character.authorNotes);
-        card_data.system_prompt =
-            transfer_string_and_conv(&character.basePrompt);
-        card_data.personality =
-            transfer_string_and_conv(&character.description);
+        card_data.system_prompt = transfer_string(&character.basePrompt);
+        card_data.personality = transfer_string(&character.description);
 
         for tag in &character.Tags {
             if card_data.tags.is_none() {
@@ -270,6 +1203,27 @@ impl From<&BayaCharacter> for TavernCardV2 {
         };
         card_data.creator = transfer_string(&Some(author_name));
 
+        // Keep the settings and timestamps, which cards have no fields for.
+        // Timestamps use V3 names, so that they become V3 fields on upgrade.
+        let settings = BackyardSettings::from(character);
+        if settings != BackyardSettings::default() {
+            // Serializing a struct of plain values can't fail
+            let settings = serde_json::to_value(settings).unwrap();
+            card_data
+                .extensions
+                .get_or_insert_with(HashMap::new)
+                .insert(BACKYARD_EXTENSION_KEY.to_string(), settings);
+        }
+        let unknown = &mut card_data.unknown_fields;
+        unknown.insert(
+            "creation_date".to_string(),
+            character.createdAt.timestamp().into(),
+        );
+        unknown.insert(
+            "modification_date".to_string(),
+            character.updatedAt.timestamp().into(),
+        );
+
         //Now copy the lorebook
         if let Some(lorebook) = &character.Lorebook {
             if !lorebook.LorebookItems.is_empty() {
@@ -283,77 +1237,770 @@ impl From<&BayaCharacter> for TavernCardV2 {
 
 impl From<&LoreBookItem> for CharacterBookEntry {
     fn from(lorebook_entry: &LoreBookItem) -> Self {
-        let mut new_entry = CharacterBookEntry::default();
-        new_entry.keys = lorebook_entry
-            .key
-            .split(",")
-            .map(|x| x.trim().to_string())
-            .collect();
-        new_entry.content = lorebook_entry.value.clone();
-        new_entry
+        CharacterBookEntry {
+            keys: lorebook_entry
+                .key
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .collect(),
+            content: lorebook_entry.value.clone(),
+            ..Default::default()
+        }
     }
 }
 
 impl From<&Lorebook> for CharacterBook {
     fn from(lorebook: &Lorebook) -> Self {
         let mut new_book = CharacterBook::default();
-        for lorebook_entry in &lorebook.LorebookItems {
-            new_book.entries.push(lorebook_entry.into());
+        for (i, lorebook_entry) in lorebook.LorebookItems.iter().enumerate() {
+            let mut entry: CharacterBookEntry = lorebook_entry.into();
+            entry.id = Some(i as u32);
+            // Backyard orders entries by zero-padded numbers
+            let order = lorebook_entry.order.trim().parse().ok();
+            entry.insertion_order = Some(order.unwrap_or(i as u32));
+            new_book.entries.push(entry);
         }
         new_book
     }
 }
 
+/// Turns tavern placeholders into Backyard ones
+///
+/// `{{user}}` becomes `{user}` and `{{char}}` becomes `{character}`. Old V1
+/// placeholders `<USER>` and `<BOT>` are converted too.
+fn to_backyard_placeholders(text: &str) -> String {
+    static USER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?i)\{\{user\}\}|<user>").unwrap());
+    static CHARACTER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?i)\{\{char\}\}|<bot>").unwrap());
+    let text = USER.replace_all(text, "{user}");
+    CHARACTER.replace_all(&text, "{character}").to_string()
+}
+
+impl BayaCharacter {
+    /// Converts tavern card into Backyard character
+    ///
+    /// Reverses the mapping of `From<&BayaCharacter> for TavernCardV2`.
+    /// Fields that Backyard has no place for are listed in the report.
+    /// Disabled lorebook entries are left out, as Backyard can't disable
+    /// them.
+    pub fn from_tavern_card(
+        card: &TavernCardV2,
+        image_url: Option<String>,
+    ) -> (Self, ConversionReport) {
+        let d = &card.data;
+        let mut report = ConversionReport::default();
+        let mut drop_field = |name: String, present: bool| {
+            if present {
+                report.dropped.push(name);
+            }
+        };
+        let has_text =
+            |x: &Option<String>| x.as_ref().is_some_and(|x| !x.is_empty());
+        drop_field(
+            "data.post_history_instructions".into(),
+            has_text(&d.post_history_instructions),
+        );
+        drop_field(
+            "data.alternate_greetings".into(),
+            d.alternate_greetings.as_ref().is_some_and(|x| !x.is_empty()),
+        );
+        drop_field(
+            "data.character_version".into(),
+            has_text(&d.character_version),
+        );
+        for key in d.extensions.iter().flat_map(|x| x.keys()) {
+            drop_field(
+                format!("data.extensions.{}", key),
+                key != BACKYARD_EXTENSION_KEY,
+            );
+        }
+        for key in d.unknown_fields.keys() {
+            let is_date = key == "creation_date" || key == "modification_date";
+            drop_field(format!("data.{}", key), !is_date);
+        }
+
+        let date = |key: &str| {
+            d.unknown_fields
+                .get(key)
+                .and_then(|x| x.as_i64())
+                .and_then(|x| DateTime::from_timestamp(x, 0))
+                .unwrap_or_else(Utc::now)
+        };
+        let convert = |x: &Option<String>| {
+            x.as_ref()
+                .filter(|x| !x.is_empty())
+                .map(|x| to_backyard_placeholders(x))
+        };
+        let settings: BackyardSettings = d
+            .extensions
+            .as_ref()
+            .and_then(|x| x.get(BACKYARD_EXTENSION_KEY))
+            .and_then(|x| serde_json::from_value(x.clone()).ok())
+            .unwrap_or_default();
+        let lorebook = d
+            .character_book
+            .as_ref()
+            .map(|book| Lorebook::from_book(book, &mut report));
+
+        let character = BayaCharacter {
+            id: None,
+            aiName: d.name.clone(),
+            aiDisplayName: d.name.clone(),
+            description: convert(&d.personality),
+            authorNotes: d.creator_notes.clone().filter(|x| !x.is_empty()),
+            createdAt: date("creation_date"),
+            updatedAt: date("modification_date"),
+            aiPersona: convert(&d.description),
+            basePrompt: convert(&d.system_prompt),
+            customDialogue: convert(&d.mes_example),
+            firstMessage: convert(&d.first_mes),
+            scenario: convert(&d.scenario),
+            temperature: settings.temperature,
+            repeatLastN: settings.repeat_last_n,
+            repeatPenalty: settings.repeat_penalty,
+            isNsfw: settings.is_nsfw,
+            grammar: settings.grammar,
+            topP: settings.top_p,
+            minP: settings.min_p,
+            minPEnabled: settings.min_p_enabled,
+            topK: settings.top_k,
+            promptTemplate: settings.prompt_template,
+            Author: d
+                .creator
+                .clone()
+                .filter(|x| !x.is_empty())
+                .map(|username| Author { username }),
+            ModelFamily: settings.model_family.map(|name| ModelFamily {
+                displayName: name,
+                promptFormat: settings.prompt_format.unwrap_or_default(),
+            }),
+            Tags: d
+                .tags
+                .iter()
+                .flatten()
+                .map(|name| Tag { name: name.clone() })
+                .collect(),
+            Images: image_url
+                .map(|url| Image { imageUrl: url, label: None })
+                .into_iter()
+                .collect(),
+            Lorebook: lorebook,
+        };
+        (character, report)
+    }
+}
+
+impl Lorebook {
+    /// Converts card lorebook, listing what is lost in the report
+    ///
+    /// Entries are written in insertion order. Backyard sorts them by
+    /// `order` as text, so it is zero-padded.
+    fn from_book(book: &CharacterBook, report: &mut ConversionReport) -> Self {
+        let path = "data.character_book";
+        let settings = [
+            ("name", book.name.is_some()),
+            ("description", book.description.is_some()),
+            ("scan_depth", book.scan_depth.is_some()),
+            ("token_budget", book.token_budget.is_some()),
+            ("recursive_scanning", book.recursive_scanning.is_some()),
+        ];
+        for (key, present) in settings {
+            if present {
+                report.dropped.push(format!("{}.{}", path, key));
+            }
+        }
+        let mut entries: Vec<(usize, &CharacterBookEntry)> =
+            book.entries.iter().enumerate().collect();
+        entries.sort_by_key(|(_, x)| x.insertion_order);
+        let mut items = Vec::new();
+        for (i, entry) in entries {
+            let entry_path = format!("{}.entries[{}]", path, i);
+            if !entry.enabled {
+                report.dropped.push(format!("{} (disabled)", entry_path));
+                continue;
+            }
+            let lost = [
+                (
+                    "secondary_keys",
+                    entry
+                        .secondary_keys
+                        .as_ref()
+                        .is_some_and(|x| !x.is_empty()),
+                ),
+                ("constant", entry.constant == Some(true)),
+                ("selective", entry.selective == Some(true)),
+                ("case_sensitive", entry.case_sensitive == Some(true)),
+                ("priority", entry.priority.is_some()),
+                ("position", entry.position.is_some()),
+                ("name", entry.name.as_ref().is_some_and(|x| !x.is_empty())),
+                (
+                    "comment",
+                    entry.comment.as_ref().is_some_and(|x| !x.is_empty()),
+                ),
+                ("extensions", !entry.extensions.is_empty()),
+            ];
+            for (key, present) in lost {
+                if present {
+                    report.dropped.push(format!("{}.{}", entry_path, key));
+                }
+            }
+            // Other entry fields, like use_regex. Off flags lose nothing.
+            let mut unknown: Vec<&String> = entry
+                .unknown_fields
+                .iter()
+                .filter(|(_, x)| !x.is_null() && x.as_bool() != Some(false))
+                .map(|(key, _)| key)
+                .collect();
+            unknown.sort();
+            for key in unknown {
+                report.dropped.push(format!("{}.{}", entry_path, key));
+            }
+            items.push(LoreBookItem {
+                key: entry.keys.join(", "),
+                order: format!("{:04}", items.len()),
+                value: to_backyard_placeholders(&entry.content),
+            });
+        }
+        Lorebook { LorebookItems: items }
+    }
+}
+
+/// Exports card into Backyard AI format
+///
+/// Saves the character as <old_name>.backyard.json, and the image without
+/// the card as <old_name>.backyard.png, next to it.
+pub fn export_backyard_file(
+    path: &Path,
+    options: &ExportOptions,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let mut report = ConversionReport::default();
+    let card = if tools::read_text_chunk(&image, TEXT_KEY_PNG_V3)?.is_some() {
+        let (card, v3_report) =
+            TavernCardV3::from_png_image(&image)?.downgrade();
+        report.dropped = v3_report.dropped;
+        card
+    } else {
+        TavernCardV2::from_png_image(&image)?
+    };
+
+    let avatar = json_card::strip_card_chunks(&image)?;
+    let fields = card.name_fields();
+    let extensions = ["json", "png"];
+    let Some(paths) =
+        options.output_paths(path, "{stem}.backyard", fields, &extensions)?
+    else {
+        return Ok(());
+    };
+    let (json_path, image_path) = (&paths[0], &paths[1]);
+    let image_name =
+        image_path.file_name().map(|x| x.to_string_lossy().to_string());
+    let (character, baya_report) =
+        BayaCharacter::from_tavern_card(&card, image_name);
+    report.dropped.extend(baya_report.dropped);
+
+    println!("Output file name: {}", json_path.display());
+    let json = serde_json::to_string_pretty(&character)?;
+    tools::replace_file(json_path, json.as_bytes())?;
+    write_image_to_file(&avatar, image_path)?;
+    if report.dropped.is_empty() {
+        println!("All fields were exported.");
+    } else {
+        println!("Fields that Backyard has no place for:");
+        print!("{}", report);
+    }
+    println!("Done");
+    Ok(())
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
-    use std::collections::HashMap;
-    use test_context::{test_context, TestContext};
+    use std::sync::Arc;
 
-    const CACHE_PATH: &str = "testing/test_cache.txt"; // Cache for downloaded pages will be stored here.
+    const PAGE_FIXTURE: &str =
+        include_str!("../testing/fixtures/backyard_page.html");
+    const CHARACTER_FIXTURE: &str =
+        include_str!("../testing/fixtures/backyard_character.json");
 
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct TestCache {
-        page_cache: HashMap<String, String>,
+    #[test]
+    fn test_parsing_page() -> Result<()> {
+        let baya_char = parse_input(PAGE_FIXTURE)?;
+        assert_eq!(baya_char.aiDisplayName.as_deref(), Some("Captain Mira"));
+        assert_eq!(baya_char.Images.len(), 2);
+        assert_eq!(baya_char.Lorebook.unwrap().LorebookItems.len(), 2);
+
+        let error = parse_input("<html><body></body></html>").unwrap_err();
+        assert!(error.to_string().contains("__NEXT_DATA__"));
+        Ok(())
     }
 
-    impl TestContext for TestCache {
-        fn setup() -> Self {
-            let file_content = std::fs::read_to_string(CACHE_PATH)
-                .unwrap_or_else(|_| String::new());
-            serde_json::from_str(&file_content)
-                .unwrap_or_else(|_| TestCache { page_cache: HashMap::new() })
-        }
+    #[test]
+    fn test_page_and_json_give_same_card() -> Result<()> {
+        let from_page = TavernCardV2::from(&parse_input(PAGE_FIXTURE)?);
+        let from_json = TavernCardV2::from(&parse_input(CHARACTER_FIXTURE)?);
+        assert_eq!(from_page, from_json);
 
-        fn teardown(self) {
-            let serialized = serde_json::to_string(&self)
-                .expect("Failed to serialize cache");
-            std::fs::write(CACHE_PATH, serialized)
-                .expect("Failed to write cache to file");
-        }
+        // Whole __NEXT_DATA__ works as JSON input too
+        let soup = soup::Soup::new(PAGE_FIXTURE);
+        let script = soup.tag("script").attr("id", "__NEXT_DATA__").find();
+        let next_data =
+            TavernCardV2::from(&parse_input(&script.unwrap().text())?);
+        assert_eq!(next_data, from_json);
+
+        let mut from_json = from_json;
+        let report =
+            PlaceholderOptions::default().normalize_v2(&mut from_json.data);
+        assert_eq!(from_json.data.name.as_deref(), Some("Captain Mira"));
+        assert_eq!(
+            from_json.data.first_mes.as_deref(),
+            Some("*Mira tosses {{user}} a wrench.* Engine's acting up again.")
+        );
+        assert_eq!(
+            from_json.data.mes_example.as_deref(),
+            Some(
+                "#{{user}}: Where are we headed?\n#{{char}}: Somewhere the \
+                patrols won't follow."
+            )
+        );
+        assert_eq!(report.fields.len(), 4);
+        assert_eq!(
+            from_json.data.tags,
+            Some(vec!["Sci-Fi".to_string(), "Adventure".to_string()])
+        );
+        let book = from_json.data.character_book.unwrap();
+        assert_eq!(book.entries[1].keys, vec!["patrol"]);
+        Ok(())
     }
 
-    fn download_testing_webpage<'a>(
-        url: &str,
-        cache: &'a mut TestCache,
-    ) -> Result<&'a str> {
-        if cache.page_cache.contains_key(url) {
-            return Ok(cache.page_cache.get(url).unwrap());
-        }
-        let page_content = tools::download_page(url)?;
-        cache.page_cache.insert(url.to_string(), page_content);
-        return Ok(cache.page_cache.get(url).unwrap());
+    #[test]
+    fn test_finding_character() -> Result<()> {
+        let character = |id: &str, name: &str| {
+            serde_json::json!({
+                "id": id,
+                "aiDisplayName": name,
+                "createdAt": "2024-01-02T03:04:05.000Z",
+                "updatedAt": "2024-01-02T03:04:05.000Z",
+                "firstMessage": "Hi",
+                "Tags": [],
+                "Images": [],
+            })
+        };
+        let name = |json: &Value| -> Result<String> {
+            Ok(parse_character_json(json.clone())?.aiDisplayName.unwrap())
+        };
+
+        // Queries reordered, with a listing of related characters first
+        let listing = serde_json::json!([{ "id": "b", "aiDisplayName": "B" }]);
+        let page = serde_json::json!({
+            "query": { "id": "a" },
+            "props": { "pageProps": { "trpcState": { "json": { "queries": [
+                { "state": { "data": listing } },
+                { "state": { "data": { "character": character("b", "B") } } },
+                { "state": { "data": { "character": character("a", "A") } } },
+            ] } } } },
+        });
+        assert_eq!(name(&page)?, "A");
+
+        let page = serde_json::json!({
+            "props": { "pageProps": { "character": character("c", "C") } },
+        });
+        assert_eq!(name(&page)?, "C");
+
+        let page = serde_json::json!({
+            "props": { "pageProps": { "hub": { "items": [
+                { "id": "d", "aiDisplayName": "D" },
+                { "preview": character("e", "E") },
+            ] } } },
+        });
+        assert_eq!(name(&page)?, "E");
+
+        let page = serde_json::json!({ "props": { "pageProps": {} } });
+        let error = parse_character_json(page).unwrap_err().to_string();
+        assert!(error.contains("tRPC queries"));
+        assert!(error.contains("search of the whole page data"));
+        Ok(())
+    }
+
+    const LOGIN_PAGE: &str = "<html><body><form action=\"/login\">\
+        <input name=\"email\"><input type=\"password\" name=\"password\">\
+        </form></body></html>";
+
+    /// Serves the page fixture and an image to requests with the session
+    /// cookie, and redirects others to the login page
+    fn start_test_server() -> String {
+        use tiny_http::{Header, Response};
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let logged_in = request.headers().iter().any(|x| {
+                    x.field.equiv("Cookie")
+                        && x.value.as_str().contains("session=abc")
+                });
+                let response = match (request.url(), logged_in) {
+                    ("/login", _) => Response::from_string(LOGIN_PAGE).boxed(),
+                    ("/avatar.png", true) => {
+                        let png = tools::get_default_image().to_vec();
+                        Response::from_data(png).boxed()
+                    }
+                    (_, true) => Response::from_string(PAGE_FIXTURE).boxed(),
+                    (_, false) => {
+                        let location =
+                            Header::from_bytes("Location", "/login").unwrap();
+                        Response::empty(302).with_header(location).boxed()
+                    }
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn test_download_with_cookies() -> Result<()> {
+        let address = start_test_server();
+        let page_url = format!("{}/hub/character/clx0fixture", address);
+        let image_url = format!("{}/avatar.png", address);
+
+        let no_cookies = HttpClient::default();
+        let error = download_character_page(&page_url, &no_cookies);
+        assert_eq!(error.unwrap_err().to_string(), LOGIN_REQUIRED);
+        assert!(tools::download_image(&image_url, &no_cookies).is_err());
+
+        let cookies = HttpClient::new(CookieJar::parse_netscape(
+            "127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc\n",
+        )?);
+        let page = download_character_page(&page_url, &cookies)?;
+        let character = parse_input(&page)?;
+        assert_eq!(character.aiDisplayName.as_deref(), Some("Captain Mira"));
+        let image = tools::download_image(&image_url, &cookies)?;
+        assert_eq!(image, tools::get_default_image());
+
+        // A login page saved instead of the character page
+        let error = parse_input(LOGIN_PAGE).unwrap_err();
+        assert_eq!(error.to_string(), LOGIN_REQUIRED);
+
+        let options = BayaOptions {
+            session_token: Some("token".to_string()),
+            ..Default::default()
+        };
+        let url = reqwest::Url::parse("https://backyard.ai/hub/character/x")?;
+        let header = options.cookie_jar()?.header_for(&url);
+        assert_eq!(header.unwrap(), format!("{}=token", SESSION_COOKIE));
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_paths() {
+        assert!(is_login_path("/auth/login"));
+        assert!(is_login_path("/Sign-In"));
+        assert!(is_login_path("/hub/signin"));
+        assert!(!is_login_path("/author/rimwriter"));
+        assert!(!is_login_path("/hub/authors"));
+        assert!(!is_login_path("/hub/character/loginov"));
+    }
+
+    #[test]
+    fn test_batch_skips_archived_characters() -> Result<()> {
+        assert_eq!(
+            character_id_from_url(
+                "https://backyard.ai/hub/character/clx0fixture?tab=chat"
+            )
+            .as_deref(),
+            Some("clx0fixture")
+        );
+        assert_eq!(character_id_from_url("https://backyard.ai/hub"), None);
+        assert_eq!(character_id_from_url("saved.html"), None);
+
+        let archive_path =
+            std::env::temp_dir().join("tavern_card_tools_archive_test.txt");
+        std::fs::write(&archive_path, "clx0fixture\tCaptain Mira\n")?;
+        let archive = Archive::open(&archive_path)?;
+        assert!(archive.contains("clx0fixture"));
+        archive.add("other", "Other")?;
+        assert!(Archive::open(&archive_path)?.contains("other"));
+
+        let options = BayaOptions::default();
+        let batch = BatchOptions {
+            batch: None,
+            jobs: 2,
+            archive: archive_path.clone(),
+        };
+        let sources = vec![
+            "https://backyard.ai/hub/character/clx0fixture".to_string(),
+            "https://backyard.ai/hub/character/other".to_string(),
+        ];
+        download_batch_from_baya(&sources, &options, &batch)?;
+
+        let missing = "testing/fixtures/missing.html".to_string();
+        let sources = [sources, vec![missing]].concat();
+        let error = download_batch_from_baya(&sources, &options, &batch);
+        assert_eq!(error.unwrap_err().to_string(), "1 of 3 characters failed");
+        std::fs::remove_file(&archive_path)?;
+        Ok(())
+    }
+
+    /// Serves a creator listing of two pages, and character pages made
+    /// from the page fixture. Requested paths are recorded.
+    fn start_creator_server() -> (String, Arc<Mutex<Vec<String>>>) {
+        use tiny_http::Response;
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", server.server_addr());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let base = address.clone();
+        thread::spawn(move || {
+            let listing = serde_json::json!({ "props": { "pageProps": {
+                "characters": [
+                    { "id": "a", "aiDisplayName": "A",
+                      "updatedAt": "2024-04-02T09:15:30.000Z",
+                      "Author": { "username": "rimwriter" } },
+                    { "id": "b", "aiDisplayName": "B",
+                      "updatedAt": "2025-01-01T00:00:00.000Z" },
+                ],
+                "related": [
+                    { "id": "x", "aiDisplayName": "X",
+                      "Author": { "username": "someone" } },
+                ],
+            } } });
+            let first_page = format!(
+                "<html><body><script id=\"__NEXT_DATA__\">{}</script>\
+                <a rel=\"next\" href=\"/hub/user/rimwriter?cursor=2\">\
+                Next</a></body></html>",
+                listing
+            );
+            let second_page = "<html><body>\
+                <a href=\"/hub/character/c\">C</a>\
+                <a href=\"/hub/character/a\">A</a></body></html>";
+            for request in server.incoming_requests() {
+                let url = request.url().to_string();
+                recorded.lock().unwrap().push(url.clone());
+                let response = match url.as_str() {
+                    "/hub/user/rimwriter" => Response::from_string(&first_page),
+                    x if x.starts_with("/hub/user/rimwriter?") => {
+                        Response::from_string(second_page)
+                    }
+                    x if x.starts_with("/hub/character/") => {
+                        let id = x.trim_start_matches("/hub/character/");
+                        let page = PAGE_FIXTURE
+                            .replace("Captain Mira", id)
+                            .replace("https://example.com", &base);
+                        Response::from_string(page)
+                    }
+                    _ => Response::from_data(tools::get_default_image()),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        (address, requests)
     }
 
-    #[test_context(TestCache)]
     #[test]
-    fn test_downloading_page(cache: &mut TestCache) -> Result<()> {
-        const TEST_URL: &str =
-            "https://backyard.ai/hub/character/clmg7rj2e03j0mc0v69b1tai1";
-        let page = download_testing_webpage(TEST_URL, cache)?;
-        let baya_char = parse_page(page)?;
-        let baya_char_name = baya_char.aiDisplayName.unwrap();
-        assert_eq!(baya_char_name, "Character crafter Puppy");
+    fn test_download_creator() -> Result<()> {
+        let (address, requests) = start_creator_server();
+        let profile = format!("{}/hub/user/rimwriter", address);
+        assert_eq!(creator_name_from_url(&profile)?, "rimwriter");
+
+        let client = HttpClient::default();
+        let listed = list_creator_characters(&profile, "rimwriter", &client)?;
+        let ids: Vec<&str> = listed.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(listed[0].updated, Some(1712049330));
+        assert_eq!(listed[2].updated, None);
+
+        let dir = std::env::temp_dir().join("tavern_card_tools_creator_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut options = BayaOptions::default();
+        options.output.output_dir = Some(dir.clone());
+        let mut creator = CreatorOptions::default();
+        download_creator_from_baya(&profile, &options, &creator, 2)?;
+        let local = local_updates(&dir);
+        assert_eq!(local.len(), 3);
+        assert_eq!(local["a"], 1712049330);
+        assert!(dir.join("rimwriter/b.Armor.png").exists());
+
+        // Only b is updated in the listing, c has to be checked
+        requests.lock().unwrap().clear();
+        creator.incremental = true;
+        download_creator_from_baya(&profile, &options, &creator, 2)?;
+        let requests = requests.lock().unwrap();
+        let characters: Vec<&String> = requests
+            .iter()
+            .filter(|x| x.starts_with("/hub/character/"))
+            .collect();
+        assert_eq!(characters.len(), 2);
+        assert!(!requests.contains(&"/hub/character/a".to_string()));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_settings_are_kept() -> Result<()> {
+        let character: BayaCharacter =
+            serde_json::from_value(serde_json::json!({
+                "aiDisplayName": "Test",
+                "createdAt": "2024-01-02T03:04:05.000Z",
+                "updatedAt": "2024-02-03T04:05:06.000Z",
+                "temperature": 1.2,
+                "minP": 0.1,
+                "minPEnabled": true,
+                "topK": 30,
+                "promptTemplate": "",
+                "ModelFamily": {
+                    "displayName": "Llama 3",
+                    "promptFormat": "llama3"
+                },
+                "Tags": [],
+                "Images": [],
+            }))?;
+        let card = TavernCardV2::from(&character);
+        let extensions = card.data.extensions.as_ref().unwrap();
+        assert_eq!(
+            extensions[BACKYARD_EXTENSION_KEY],
+            serde_json::json!({
+                "model_family": "Llama 3",
+                "prompt_format": "llama3",
+                "temperature": 1.2,
+                "min_p": 0.1,
+                "min_p_enabled": true,
+                "top_k": 30,
+            })
+        );
+        assert_eq!(card.data.unknown_fields["creation_date"], 1704164645);
+
+        let (card_v3, _) = TavernCardV3::upgrade(card.clone());
+        assert_eq!(card_v3.data.modification_date, Some(1706933106));
+        let printed = card.to_string();
+        assert!(printed.contains("temperature = 1.2"));
+        assert!(printed.contains("Created:: 2024-01-02 03:04 UTC"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_avatar_and_extra_images() -> Result<()> {
+        let images: Vec<Image> = serde_json::from_value(serde_json::json!([
+            { "imageUrl": "https://a", "label": "Casual" },
+            { "imageUrl": "https://b", "label": null },
+            { "imageUrl": "https://c", "label": "Armor" },
+        ]))?;
+        assert_eq!(select_avatar(&images, None)?, 0);
+        assert_eq!(select_avatar(&images, Some("2"))?, 2);
+        assert_eq!(select_avatar(&images, Some("armor"))?, 2);
+        assert!(select_avatar(&images, Some("3")).is_err());
+        let error = select_avatar(&images, Some("Swimsuit")).unwrap_err();
+        assert!(error.to_string().contains("Casual, image1, Armor"));
+        assert_eq!(
+            side_image_extensions(&images, 0),
+            ["image1.png", "Armor.png"]
+        );
+        let twins: Vec<Image> = serde_json::from_value(serde_json::json!([
+            { "imageUrl": "https://a", "label": "Armor" },
+            { "imageUrl": "https://b", "label": "Armor" },
+        ]))?;
+        assert_eq!(
+            side_image_extensions(&twins, 2),
+            ["Armor.png", "Armor_1.png"]
+        );
+
+        let (mut card, _) = TavernCardV3::upgrade(TavernCardV2::new());
+        let png = tools::get_default_image();
+        let extra = vec![
+            (image_label(&images[1], 1), png.clone()),
+            (image_label(&images[2], 2), png.clone()),
+        ];
+        add_image_assets(&mut card, extra);
+        let card = TavernCardV3::from_png_image(&card.into_png_image()?)?;
+        let assets = card.data.assets.as_ref().unwrap();
+        let names: Vec<&str> = assets.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "image1", "Armor"]);
+        assert_eq!(card.asset_data[&assets[2].uri], png);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_to_backyard() -> Result<()> {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test".to_string());
+        card.data.description = Some("{{char}} greets {{User}}.".to_string());
+        card.data.tags = Some(vec!["tag".to_string()]);
+        card.data.post_history_instructions = Some("Be nice".to_string());
+        let settings = serde_json::json!({ "temperature": 0.8, "top_k": 40 });
+        card.data
+            .extensions
+            .as_mut()
+            .unwrap()
+            .insert(BACKYARD_EXTENSION_KEY.to_string(), settings);
+        let disabled = CharacterBookEntry {
+            keys: vec!["b".to_string()],
+            enabled: false,
+            ..Default::default()
+        };
+        let entry = CharacterBookEntry {
+            keys: vec!["a".to_string(), "c".to_string()],
+            content: "About <USER>".to_string(),
+            secondary_keys: Some(vec!["d".to_string()]),
+            insertion_order: Some(3),
+            comment: Some("About a".to_string()),
+            case_sensitive: Some(false),
+            unknown_fields: [
+                ("use_regex".to_string(), true.into()),
+                ("match_whole_words".to_string(), false.into()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let first = CharacterBookEntry {
+            insertion_order: Some(0),
+            ..test_entry(&["e"], "First")
+        };
+        card.data.character_book = Some(CharacterBook {
+            entries: vec![disabled, entry, first],
+            ..Default::default()
+        });
+
+        let (character, report) = BayaCharacter::from_tavern_card(&card, None);
+        assert_eq!(
+            character.aiPersona.as_deref(),
+            Some("{character} greets {user}.")
+        );
+        assert_eq!(character.temperature, Some(0.8));
+        assert_eq!(character.topK, Some(40));
+        let items = &character.Lorebook.as_ref().unwrap().LorebookItems;
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            (items[0].key.as_str(), items[0].order.as_str()),
+            ("e", "0000")
+        );
+        assert_eq!(items[1].key, "a, c");
+        assert_eq!(items[1].value, "About {user}");
+        assert_eq!(
+            report.dropped,
+            vec![
+                "data.post_history_instructions",
+                "data.character_book.entries[0] (disabled)",
+                "data.character_book.entries[1].secondary_keys",
+                "data.character_book.entries[1].comment",
+                "data.character_book.entries[1].use_regex",
+            ]
+        );
+
+        // Our own reader takes the exported JSON back, even with the items
+        // listed out of order
+        let mut character = character;
+        character.Lorebook.as_mut().unwrap().LorebookItems.reverse();
+        let json = serde_json::to_string(&character)?;
+        let character: BayaCharacter = serde_json::from_str(&json)?;
+        let card2 = TavernCardV2::from(&character);
+        assert_eq!(card2.data.name, card.data.name);
+        assert_eq!(card2.data.tags, card.data.tags);
+        let mut entries = card2.data.character_book.unwrap().entries;
+        entries.sort_by_key(|x| x.insertion_order);
+        let keys: Vec<String> =
+            entries.iter().map(|x| x.keys.join(", ")).collect();
+        assert_eq!(keys, vec!["e", "a, c"]);
         Ok(())
     }
 }
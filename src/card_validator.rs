@@ -0,0 +1,671 @@
+//! Checking cards against the V2 and V3 specifications.
+//!
+//! Cards are checked as JSON, so that fields of a wrong type are found too,
+//! not only the missing ones. Problems are reported with their JSON paths,
+//! like `data.character_book.entries[2].keys`. In fix mode, missing fields
+//! get the spec defaults and values of simple wrong types are converted.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+use crate::embedded_card;
+use crate::lorebook_editor::DEFAULT_INSERTION_ORDER;
+use crate::lorebook_lint::Severity;
+use crate::png_chunks;
+use crate::tavern_card_v1::is_v1_json;
+use crate::tavern_card_v2::{SPEC_V2, SPEC_VERSION_V2};
+use crate::tavern_card_v3::{SPEC_V3, SPEC_VERSION_V3, TEXT_KEY_PNG_V3};
+use crate::tools;
+
+/// String fields that both V2 and V3 require in `data`
+const REQUIRED_STRINGS: [&str; 11] = [
+    "name",
+    "description",
+    "personality",
+    "scenario",
+    "first_mes",
+    "mes_example",
+    "creator_notes",
+    "system_prompt",
+    "post_history_instructions",
+    "creator",
+    "character_version",
+];
+
+/// Fields with lists of keys, which some apps write as one comma separated
+/// string
+const KEY_LISTS: [&str; 3] = ["keys", "secondary_keys", "tags"];
+
+const POSITIONS: [&str; 2] = ["before_char", "after_char"];
+
+/// One place where the card does not follow the spec
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    /// JSON path of the field, like `data.tags[1]`
+    pub path: String,
+    pub message: String,
+    /// Whether the problem was fixed
+    pub fixed: bool,
+}
+
+/// Value types used by the spec
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    StringList,
+    Bool,
+    /// Non-negative whole number that fits u32
+    Count,
+    /// Whole number, like a timestamp
+    Integer,
+    Object,
+    Array,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::String => "a string",
+            Kind::StringList => "a list of strings",
+            Kind::Bool => "true or false",
+            Kind::Count => "a non-negative whole number",
+            Kind::Integer => "a whole number",
+            Kind::Object => "an object",
+            Kind::Array => "a list",
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Kind::String => value.is_string(),
+            Kind::StringList => value
+                .as_array()
+                .is_some_and(|x| x.iter().all(|x| x.is_string())),
+            Kind::Bool => value.is_boolean(),
+            Kind::Count => value.as_u64().is_some_and(|x| x <= u32::MAX as u64),
+            Kind::Integer => value.is_i64() || value.is_u64(),
+            Kind::Object => value.is_object(),
+            Kind::Array => value.is_array(),
+        }
+    }
+
+    fn default_value(self) -> Value {
+        match self {
+            Kind::String => json!(""),
+            Kind::StringList | Kind::Array => json!([]),
+            Kind::Bool => json!(false),
+            Kind::Count | Kind::Integer => json!(0),
+            Kind::Object => json!({}),
+        }
+    }
+
+    /// Converts value of a wrong type, when it can be done without guessing
+    fn convert(self, value: &Value, key: &str) -> Option<Value> {
+        let converted = match (self, value) {
+            (Kind::String, Value::Number(x)) => Some(json!(x.to_string())),
+            (Kind::String, Value::Bool(x)) => Some(json!(x.to_string())),
+            (Kind::StringList, Value::String(x))
+                if KEY_LISTS.contains(&key) =>
+            {
+                let keys =
+                    x.split(',').map(|x| x.trim()).filter(|x| !x.is_empty());
+                Some(json!(keys.collect::<Vec<_>>()))
+            }
+            (Kind::StringList, Value::String(x)) => Some(json!([x])),
+            (Kind::StringList, Value::Array(items)) => items
+                .iter()
+                .map(|x| Kind::String.convert(x, key).or(Some(x.clone())))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            (Kind::Bool, Value::String(x)) => {
+                x.trim().parse::<bool>().ok().map(Value::Bool)
+            }
+            (Kind::Count | Kind::Integer, Value::String(x)) => {
+                x.trim().parse::<i64>().ok().map(|x| json!(x))
+            }
+            (Kind::Count | Kind::Integer, Value::Number(x)) => {
+                x.as_f64().filter(|x| x.fract() == 0.0).map(|x| json!(x as i64))
+            }
+            _ => None,
+        };
+        converted.filter(|x| self.matches(x))
+    }
+}
+
+/// Whether a field must be present, and what it is set to if missing
+enum Need {
+    Optional,
+    /// Required, missing field gets the empty value of its kind
+    Required,
+    /// Required, missing field gets the given value
+    Default(Value),
+}
+
+struct Validator {
+    fix: bool,
+    issues: Vec<Issue>,
+}
+
+impl Validator {
+    /// Records a problem. Returns whether it should be fixed
+    fn report(
+        &mut self,
+        severity: Severity,
+        path: &str,
+        message: impl Into<String>,
+        fixable: bool,
+    ) -> bool {
+        let fixed = self.fix && fixable;
+        let path = if path.is_empty() { "card" } else { path };
+        self.issues.push(Issue {
+            severity,
+            path: path.to_string(),
+            message: message.into(),
+            fixed,
+        });
+        fixed
+    }
+
+    /// Checks one field of an object
+    ///
+    /// Returns the field if it is present and has the right kind, after the
+    /// fixes.
+    fn field<'a>(
+        &mut self,
+        object: &'a mut Map<String, Value>,
+        path: &str,
+        key: &str,
+        kind: Kind,
+        need: Need,
+    ) -> Option<&'a mut Value> {
+        let field_path = join_path(path, key);
+        let default = match need {
+            Need::Optional => None,
+            Need::Required => Some(kind.default_value()),
+            Need::Default(value) => Some(value),
+        };
+        match (object.get(key).cloned(), default) {
+            (None, Some(default)) => {
+                let message = "Required field is missing";
+                if self.report(Severity::Error, &field_path, message, true) {
+                    object.insert(key.to_string(), default);
+                }
+            }
+            (Some(Value::Null), Some(default)) => {
+                let message = format!("Must be {}, not null", kind.name());
+                if self.report(Severity::Error, &field_path, message, true) {
+                    object.insert(key.to_string(), default);
+                }
+            }
+            (Some(Value::Null), None) => {
+                let message = "Optional field is null, it should be left out";
+                if self.report(Severity::Warning, &field_path, message, true) {
+                    object.remove(key);
+                }
+            }
+            (Some(value), _) if !kind.matches(&value) => {
+                let converted = kind.convert(&value, key);
+                let message = format!("Must be {}", kind.name());
+                let fixable = converted.is_some();
+                if self.report(Severity::Error, &field_path, message, fixable) {
+                    object.insert(key.to_string(), converted.unwrap());
+                }
+            }
+            _ => (),
+        }
+        object.get_mut(key).filter(|x| kind.matches(x))
+    }
+
+    fn check_card(&mut self, json: &mut Value, v3: bool) {
+        if is_v1_json(json) {
+            let message = "V1 card, the spec needs V2 or V3. Run `upgrade`";
+            self.report(Severity::Error, "", message, false);
+            return;
+        }
+        let Some(card) = json.as_object_mut() else {
+            self.report(Severity::Error, "", "Must be an object", false);
+            return;
+        };
+        let (spec, version) = match v3 {
+            true => (SPEC_V3, SPEC_VERSION_V3),
+            false => (SPEC_V2, SPEC_VERSION_V2),
+        };
+        let spec_value = Need::Default(json!(spec));
+        if let Some(value) =
+            self.field(card, "", "spec", Kind::String, spec_value)
+        {
+            if value != spec {
+                let message = format!("Must be \"{}\"", spec);
+                if self.report(Severity::Error, "spec", message, true) {
+                    *value = json!(spec);
+                }
+            }
+        }
+        let version_value = Need::Default(json!(version));
+        if let Some(value) =
+            self.field(card, "", "spec_version", Kind::String, version_value)
+        {
+            let supported = match v3 {
+                // Any 3.x version is readable by V3 apps
+                true => value
+                    .as_str()
+                    .and_then(|x| x.parse::<f64>().ok())
+                    .is_some_and(|x| (3.0..4.0).contains(&x)),
+                false => value == version,
+            };
+            if !supported {
+                let message = format!("Must be \"{}\"", version);
+                if self.report(Severity::Error, "spec_version", message, true) {
+                    *value = json!(version);
+                }
+            }
+        }
+        if let Some(data) =
+            self.field(card, "", "data", Kind::Object, Need::Required)
+        {
+            self.check_data(data.as_object_mut().unwrap(), v3);
+        }
+    }
+
+    fn check_data(&mut self, data: &mut Map<String, Value>, v3: bool) {
+        let path = "data";
+        for key in REQUIRED_STRINGS {
+            self.field(data, path, key, Kind::String, Need::Required);
+        }
+        let mut lists = vec!["alternate_greetings", "tags"];
+        if v3 {
+            lists.push("group_only_greetings");
+        }
+        for key in lists {
+            self.field(data, path, key, Kind::StringList, Need::Required);
+        }
+        self.field(data, path, "extensions", Kind::Object, Need::Required);
+        if data.get("name").is_some_and(|x| x == "") {
+            let message = "Name is empty";
+            self.report(Severity::Warning, "data.name", message, false);
+        }
+        if let Some(book) = self.field(
+            data,
+            path,
+            "character_book",
+            Kind::Object,
+            Need::Optional,
+        ) {
+            let book = book.as_object_mut().unwrap();
+            self.check_book(book, "data.character_book", v3);
+        }
+        if !v3 {
+            return;
+        }
+
+        self.field(data, path, "nickname", Kind::String, Need::Optional);
+        self.field(data, path, "source", Kind::StringList, Need::Optional);
+        for key in ["creation_date", "modification_date"] {
+            self.field(data, path, key, Kind::Integer, Need::Optional);
+        }
+        let key = "creator_notes_multilingual";
+        if let Some(notes) =
+            self.field(data, path, key, Kind::Object, Need::Optional)
+        {
+            let notes_path = join_path(path, key);
+            let notes = notes.as_object_mut().unwrap();
+            let languages: Vec<String> = notes.keys().cloned().collect();
+            for language in languages {
+                self.field(
+                    notes,
+                    &notes_path,
+                    &language,
+                    Kind::String,
+                    Need::Required,
+                );
+            }
+        }
+        if let Some(assets) =
+            self.field(data, path, "assets", Kind::Array, Need::Optional)
+        {
+            for (i, asset) in
+                assets.as_array_mut().unwrap().iter_mut().enumerate()
+            {
+                let asset_path = format!("data.assets[{}]", i);
+                let Some(asset) = asset.as_object_mut() else {
+                    let message = "Must be an object";
+                    self.report(Severity::Error, &asset_path, message, false);
+                    continue;
+                };
+                for key in ["type", "uri", "name", "ext"] {
+                    self.field(
+                        asset,
+                        &asset_path,
+                        key,
+                        Kind::String,
+                        Need::Required,
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_book(
+        &mut self,
+        book: &mut Map<String, Value>,
+        path: &str,
+        v3: bool,
+    ) {
+        for key in ["name", "description"] {
+            self.field(book, path, key, Kind::String, Need::Optional);
+        }
+        for key in ["scan_depth", "token_budget"] {
+            self.field(book, path, key, Kind::Count, Need::Optional);
+        }
+        let key = "recursive_scanning";
+        self.field(book, path, key, Kind::Bool, Need::Optional);
+        self.field(book, path, "extensions", Kind::Object, Need::Required);
+        let Some(entries) =
+            self.field(book, path, "entries", Kind::Array, Need::Required)
+        else {
+            return;
+        };
+
+        let mut ids: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, entry) in entries.as_array_mut().unwrap().iter_mut().enumerate()
+        {
+            let entry_path = format!("{}.entries[{}]", path, i);
+            let Some(entry) = entry.as_object_mut() else {
+                let message = "Must be an object";
+                self.report(Severity::Error, &entry_path, message, false);
+                continue;
+            };
+            self.check_entry(entry, &entry_path, v3);
+            if let Some(id) = entry.get("id").and_then(|x| x.as_u64()) {
+                ids.entry(id).or_default().push(i);
+            }
+        }
+        for (id, users) in ids {
+            for i in users.iter().skip(1) {
+                let entry_path = format!("{}.entries[{}].id", path, i);
+                let message = format!(
+                    "Id {} is also used by entries[{}]. Run `lorebook renumber`",
+                    id, users[0]
+                );
+                self.report(Severity::Error, &entry_path, message, false);
+            }
+        }
+    }
+
+    fn check_entry(
+        &mut self,
+        entry: &mut Map<String, Value>,
+        path: &str,
+        v3: bool,
+    ) {
+        self.field(entry, path, "keys", Kind::StringList, Need::Required);
+        self.field(entry, path, "content", Kind::String, Need::Required);
+        self.field(entry, path, "extensions", Kind::Object, Need::Required);
+        let enabled = Need::Default(json!(true));
+        self.field(entry, path, "enabled", Kind::Bool, enabled);
+        let order = Need::Default(json!(DEFAULT_INSERTION_ORDER));
+        self.field(entry, path, "insertion_order", Kind::Count, order);
+        if v3 {
+            self.field(entry, path, "use_regex", Kind::Bool, Need::Required);
+        }
+        for key in ["case_sensitive", "selective", "constant"] {
+            self.field(entry, path, key, Kind::Bool, Need::Optional);
+        }
+        for key in ["name", "comment"] {
+            self.field(entry, path, key, Kind::String, Need::Optional);
+        }
+        for key in ["priority", "id"] {
+            self.field(entry, path, key, Kind::Count, Need::Optional);
+        }
+        let key = "secondary_keys";
+        self.field(entry, path, key, Kind::StringList, Need::Optional);
+        if let Some(position) =
+            self.field(entry, path, "position", Kind::String, Need::Optional)
+        {
+            if !POSITIONS.iter().any(|x| position == x) {
+                let message =
+                    "Must be \"before_char\" or \"after_char\", or left out";
+                let position_path = join_path(path, "position");
+                if self.report(Severity::Error, &position_path, message, true) {
+                    entry.remove("position");
+                }
+            }
+        }
+
+        let flag = |key: &str| entry.get(key).and_then(|x| x.as_bool());
+        let count = |key: &str| {
+            entry.get(key).and_then(|x| x.as_array()).map_or(0, |x| x.len())
+        };
+        if flag("enabled") != Some(false)
+            && flag("constant") != Some(true)
+            && count("keys") == 0
+        {
+            let message = "Entry has no keys and is not constant, so it never \
+                activates";
+            self.report(Severity::Warning, path, message, false);
+        }
+        if flag("selective") == Some(true) && count("secondary_keys") == 0 {
+            let message = "Entry is selective, but has no secondary keys";
+            self.report(Severity::Warning, path, message, false);
+        }
+    }
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+/// Checks card JSON against the V2 or V3 spec
+///
+/// If `fix` is set, fixable problems are fixed in `json` and marked as
+/// fixed in the result.
+pub fn check_card(json: &mut Value, v3: bool, fix: bool) -> Vec<Issue> {
+    let mut validator = Validator { fix, issues: Vec::new() };
+    validator.check_card(json, v3);
+    validator.issues
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = self.severity.to_string();
+        write!(f, "{:<8}{}: {}", severity, self.path, self.message)?;
+        if self.fixed {
+            write!(f, " (fixed)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks card file against the spec, and fixes it in place if asked
+///
+/// PNG cards are checked in every card chunk. Returns error if there are
+/// problems left that break the spec.
+pub fn validate_card_file(path: &Path, fix: bool) -> Result<()> {
+    let is_json = path.extension().is_some_and(|x| x == "json");
+    let mut issues = Vec::new();
+    if is_json {
+        let text = std::fs::read_to_string(path)?;
+        let mut json: Value =
+            serde_json::from_str(&text).with_context(|| {
+                format!("{} is not a valid JSON", path.display())
+            })?;
+        let v3 = json.get("spec").is_some_and(|x| x == SPEC_V3);
+        let card_issues = check_card(&mut json, v3, fix);
+        print_issues(
+            &format!("{} card", if v3 { "V3" } else { "V2" }),
+            &card_issues,
+        );
+        if card_issues.iter().any(|x| x.fixed) {
+            std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+        }
+        issues.extend(card_issues);
+    } else {
+        let image = tools::read_image_from_file(path)?;
+        let is_png = image.starts_with(png_chunks::PNG_SIGNATURE);
+        if fix && !is_png {
+            bail!("Only PNG and JSON cards can be fixed");
+        }
+        let image = match is_png {
+            true => image,
+            false => embedded_card::to_png_card(&image)?,
+        };
+        let mut chunks = png_chunks::read_chunks(&image)?;
+        let card_chunks = tools::read_card_chunks(&chunks)?;
+        if card_chunks.is_empty() {
+            bail!("No card in the image");
+        }
+        let mut changed = false;
+        for (index, mut json) in card_chunks {
+            let keyword = chunks[index].keyword().unwrap_or_default();
+            let v3 = keyword.eq_ignore_ascii_case(TEXT_KEY_PNG_V3);
+            let card_issues = check_card(&mut json, v3, fix);
+            let label = format!(
+                "{} chunk ({} card)",
+                keyword,
+                if v3 { "V3" } else { "V2" }
+            );
+            print_issues(&label, &card_issues);
+            if card_issues.iter().any(|x| x.fixed) {
+                chunks[index] =
+                    tools::card_chunk_with_json(&chunks[index], &json)?;
+                changed = true;
+            }
+            issues.extend(card_issues);
+        }
+        if changed {
+            let new_image = png_chunks::write_chunks(&chunks);
+            tools::write_image_to_file(&new_image, path)?;
+        }
+    }
+
+    let fixed = issues.iter().filter(|x| x.fixed).count();
+    let left = |severity| {
+        issues.iter().filter(|x| x.severity == severity && !x.fixed).count()
+    };
+    let errors = left(Severity::Error);
+    println!(
+        "{} errors, {} warnings, {} fixed",
+        errors,
+        left(Severity::Warning),
+        fixed
+    );
+    if errors > 0 {
+        match fix {
+            true => bail!("Card still does not follow the spec"),
+            false => bail!("Card does not follow the spec. Try --fix"),
+        }
+    }
+    Ok(())
+}
+
+fn print_issues(label: &str, issues: &[Issue]) {
+    if issues.is_empty() {
+        println!("{}: OK", label);
+        return;
+    }
+    println!("{}:", label);
+    for issue in issues {
+        println!("    {}", issue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tavern_card_v2::{CharacterBook, TavernCardV2};
+    use crate::tavern_card_v3::TavernCardV3;
+
+    #[test]
+    fn test_check_card() -> Result<()> {
+        let mut json = json!({
+            "spec": "chara_card_v2",
+            "data": {
+                "name": "Test",
+                "description": null,
+                "tags": "one, two",
+                "character_version": 2,
+                "character_book": {
+                    "scan_depth": "4",
+                    "entries": [
+                        { "keys": ["a"], "content": "A", "position": "top" },
+                        { "keys": [], "content": "B", "id": 3,
+                          "extensions": {}, "enabled": true,
+                          "insertion_order": 1.0 },
+                        { "keys": ["c"], "content": "C", "id": 3,
+                          "extensions": {}, "enabled": false,
+                          "insertion_order": "5" },
+                    ]
+                }
+            }
+        });
+        let issues = check_card(&mut json, false, false);
+        let paths: Vec<&str> = issues.iter().map(|x| x.path.as_str()).collect();
+        for path in [
+            "spec_version",
+            "data.description",
+            "data.tags",
+            "data.character_version",
+            "data.extensions",
+            "data.character_book.scan_depth",
+            "data.character_book.extensions",
+            "data.character_book.entries[0].enabled",
+            "data.character_book.entries[0].position",
+            "data.character_book.entries[1]",
+            "data.character_book.entries[1].insertion_order",
+            "data.character_book.entries[2].id",
+        ] {
+            assert!(paths.contains(&path), "{} is not reported", path);
+        }
+        assert!(!paths.contains(&"data.name"));
+        assert!(!paths.contains(&"data.character_book.entries[2]"));
+        assert!(issues.iter().all(|x| !x.fixed));
+
+        let issues = check_card(&mut json, false, true);
+        let left: Vec<&Issue> = issues.iter().filter(|x| !x.fixed).collect();
+        assert_eq!(left.len(), 2);
+        assert_eq!(json["data"]["tags"], json!(["one", "two"]));
+        assert_eq!(json["data"]["character_version"], "2");
+        let card: TavernCardV2 = serde_json::from_value(json.clone())?;
+        let book = card.data.character_book.unwrap();
+        assert_eq!(book.scan_depth, Some(4));
+        assert_eq!(book.entries[0].position, None);
+        assert!(book.entries[0].enabled);
+        assert_eq!(
+            book.entries[0].insertion_order,
+            Some(DEFAULT_INSERTION_ORDER)
+        );
+        assert_eq!(book.entries[1].insertion_order, Some(1));
+
+        // Fixing again finds nothing new
+        let issues = check_card(&mut json, false, true);
+        assert!(issues.iter().all(|x| !x.fixed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_cards() -> Result<()> {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test".to_string());
+        card.data.character_book = Some(CharacterBook::default());
+        let mut json = serde_json::to_value(&card)?;
+        assert_eq!(check_card(&mut json, false, false), vec![]);
+
+        let (card, _) = TavernCardV3::upgrade(card);
+        let mut json = serde_json::to_value(&card)?;
+        assert_eq!(check_card(&mut json, true, false), vec![]);
+
+        let mut json = json!({ "name": "Test", "first_mes": "Hi" });
+        let issues = check_card(&mut json, false, true);
+        assert_eq!(issues.len(), 1);
+        assert!(!issues[0].fixed);
+        Ok(())
+    }
+}
@@ -0,0 +1,368 @@
+//! Reading and writing CharX files.
+//!
+//! CharX is a zip archive with the V3 card in `card.json` and the assets
+//! stored as files next to it, referred from the card by `embeded://` URIs.
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::info;
+use zip::write::SimpleFileOptions;
+
+use crate::json_card;
+use crate::placeholders::PlaceholderOptions;
+use crate::tavern_card_v3::*;
+use crate::tools::{self, ExportOptions, WriteOptions};
+
+pub const CARD_FILE_NAME: &str = "card.json";
+/// Asset URI prefix for files in the archive. The misspelling is from spec.
+pub const CHARX_URI_PREFIX: &str = "embeded://";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Checks if the data looks like a CharX (zip) file.
+pub fn is_charx(data: &[u8]) -> bool {
+    data.starts_with(ZIP_MAGIC)
+}
+
+/// Reads card and its assets from CharX file content
+///
+/// The main icon becomes the card image, converted to PNG. If there is none,
+/// the default image is used.
+pub fn read_charx(charx_data: &Bytes) -> Result<TavernCardV3> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(charx_data.as_ref()))
+        .context("Not a valid CharX archive")?;
+
+    let mut card_json = Vec::new();
+    archive
+        .by_name(CARD_FILE_NAME)
+        .with_context(|| format!("No {} in CharX archive", CARD_FILE_NAME))?
+        .read_to_end(&mut card_json)?;
+    let mut card: TavernCardV3 = serde_json::from_slice(&card_json)
+        .with_context(|| format!("Failed to parse {}", CARD_FILE_NAME))?;
+
+    for asset in card.data.assets.iter().flatten() {
+        let Some(path) = charx_asset_path(&asset.uri) else {
+            continue;
+        };
+        let mut data = Vec::new();
+        archive
+            .by_name(path)
+            .with_context(|| format!("Asset {} not in archive", asset.uri))?
+            .read_to_end(&mut data)?;
+        card.asset_data.insert(asset.uri.clone(), Bytes::from(data));
+    }
+
+    let main_icon = find_main_icon(&card).and_then(|x| card.asset_data.get(x));
+    card.image_data = match main_icon {
+        Some(icon) => Some(tools::convert_to_png(icon)?),
+        None => Some(tools::get_default_image()),
+    };
+    Ok(card)
+}
+
+/// Writes card and its assets into CharX file content
+///
+/// The card image is stored as the main icon, unless the card already has
+/// an embedded one. Remote icons don't count, the avatar would be lost.
+pub fn write_charx(card: &TavernCardV3) -> Result<Bytes> {
+    let mut card = card.clone();
+    let mut files: BTreeMap<String, Bytes> = BTreeMap::new();
+
+    // Assets without any data are remote (or broken) and are kept as is.
+    let mut assets = card.data.assets.take().unwrap_or_default();
+    let has_icon = assets.iter().any(|x| {
+        let embedded = x.name == "main" && card.asset_data.contains_key(&x.uri);
+        x.asset_type == "icon" && (x.uri == DEFAULT_ASSET_URI || embedded)
+    });
+    if !has_icon {
+        // First, so that readers pick it before any remote main icon
+        assets.insert(
+            0,
+            Asset {
+                asset_type: "icon".to_string(),
+                uri: DEFAULT_ASSET_URI.to_string(),
+                name: "main".to_string(),
+                ext: "png".to_string(),
+            },
+        );
+    }
+    for asset in assets.iter_mut() {
+        let data = if asset.uri == DEFAULT_ASSET_URI {
+            // The card and the assets are in the archive already, so the
+            // icon is stored without their chunks.
+            let image = card.image_data.clone();
+            let image = image.unwrap_or_else(tools::get_default_image);
+            remove_asset_chunks(&json_card::strip_card_chunks(&image)?)?
+        } else {
+            match card.asset_data.get(&asset.uri) {
+                Some(data) => data.clone(),
+                None => continue,
+            }
+        };
+        let path = unique_asset_path(asset, &files);
+        asset.uri = format!("{}{}", CHARX_URI_PREFIX, path);
+        files.insert(path, data);
+    }
+    card.data.assets = Some(assets);
+
+    let mut buffer = Vec::new();
+    let mut zip = zip::ZipWriter::new(Cursor::new(&mut buffer));
+    let options = SimpleFileOptions::default();
+    zip.start_file(CARD_FILE_NAME, options)?;
+    zip.write_all(serde_json::to_string(&card)?.as_bytes())?;
+    for (path, data) in &files {
+        zip.start_file(path.as_str(), options)?;
+        zip.write_all(data)?;
+    }
+    zip.finish()?;
+    Ok(Bytes::from(buffer))
+}
+
+/// Converts assets of a card read from CharX for storing in PNG.
+///
+/// The main icon becomes the PNG image itself, the other embedded assets
+/// get PNG chunk URIs.
+pub fn charx_assets_to_png(card: &mut TavernCardV3) {
+    let main_icon = find_main_icon(card).map(|x| x.to_string());
+    let old_data = std::mem::take(&mut card.asset_data);
+    let mut number = 0;
+    for asset in card.data.assets.iter_mut().flatten() {
+        if Some(&asset.uri) == main_icon.as_ref() {
+            asset.uri = DEFAULT_ASSET_URI.to_string();
+            continue;
+        }
+        if let Some(data) = old_data.get(&asset.uri) {
+            asset.uri = format!("{}{}", PNG_ASSET_URI_PREFIX, number);
+            card.asset_data.insert(asset.uri.clone(), data.clone());
+            number += 1;
+        }
+    }
+}
+
+/// Converts PNG card into CharX file. Saves the result to <old_name>.charx
+pub fn export_charx_file(path: &Path, options: &ExportOptions) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let card = TavernCardV3::from_png_image(&image)?;
+    info!("\nCHARACTER INFO:\n{:#?}", &card.data);
+
+    let charx = write_charx(&card)?;
+    let fields = card.name_fields();
+    let Some(new_path) =
+        options.output_path(path, "{stem}", fields, "charx")?
+    else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&charx, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Converts CharX file into PNG card. Saves the result to <old_name>.png
+///
+/// With `webp` option, the result is WebP card <old_name>.webp instead.
+pub fn import_charx_file(
+    path: &Path,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
+) -> Result<()> {
+    let charx_data = tools::read_image_from_file(path)?;
+    let mut card = read_charx(&charx_data)?;
+    info!("\nCHARACTER INFO:\n{:#?}", &card.data);
+    let report = placeholders.for_native_card().normalize_v3(&mut card.data);
+    if !report.is_empty() {
+        print!("{}", report);
+    }
+    charx_assets_to_png(&mut card);
+    card.sync_v1_fields(options.v1_fields);
+
+    let fields = card.name_fields();
+    let new_image = card.into_png_image_with_compression(options.compress)?;
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(path, "{stem}", fields)? else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&new_image, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Prints the content of CharX file, with assets resolved
+pub fn print_charx(charx_data: &Bytes) -> Result<()> {
+    let card = read_charx(charx_data)?;
+    let assets = card.data.assets.clone().unwrap_or_default();
+    let asset_data = card.asset_data.clone();
+    let (card_v2, _) = card.downgrade();
+    println!("{}", card_v2);
+
+    println!("Assets:");
+    for asset in assets {
+        let location = match asset_data.get(&asset.uri) {
+            Some(data) => format!("{} bytes", data.len()),
+            None if asset.uri == DEFAULT_ASSET_URI => "card image".to_string(),
+            None => "not embedded".to_string(),
+        };
+        println!(
+            "    {} {}.{}: {} ({})",
+            asset.asset_type, asset.name, asset.ext, asset.uri, location
+        );
+    }
+    Ok(())
+}
+
+/// Returns the archive path of an `embeded://` asset URI.
+fn charx_asset_path(uri: &str) -> Option<&str> {
+    uri.strip_prefix(CHARX_URI_PREFIX)
+}
+
+/// Returns URI of the main icon asset, if it is embedded.
+fn find_main_icon(card: &TavernCardV3) -> Option<&str> {
+    card.data
+        .assets
+        .iter()
+        .flatten()
+        .filter(|x| x.asset_type == "icon")
+        .find(|x| x.name == "main" || x.uri == DEFAULT_ASSET_URI)
+        .filter(|x| card.asset_data.contains_key(&x.uri))
+        .map(|x| x.uri.as_str())
+}
+
+/// Builds archive path for asset, like `assets/icon/images/main.png`.
+fn unique_asset_path(asset: &Asset, taken: &BTreeMap<String, Bytes>) -> String {
+    let category = match asset.ext.to_lowercase().as_str() {
+        "png" | "jpg" | "jpeg" | "webp" | "gif" | "avif" | "bmp" => "images",
+        "mp3" | "ogg" | "wav" | "flac" | "m4a" => "audio",
+        "mp4" | "webm" | "mkv" => "video",
+        _ => "other",
+    };
+    let clean = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+            .collect()
+    };
+    let asset_type = clean(&asset.asset_type);
+    let name = clean(&asset.name);
+    let ext = clean(&asset.ext);
+    let mut path =
+        format!("assets/{}/{}/{}.{}", asset_type, category, name, ext);
+    let mut counter = 1;
+    while taken.contains_key(&path) {
+        path = format!(
+            "assets/{}/{}/{}_{}.{}",
+            asset_type, category, name, counter, ext
+        );
+        counter += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn create_test_card() -> TavernCardV3 {
+        let mut card = TavernCardV3::new();
+        card.data.name = Some(String::from("Test name"));
+        card.data.assets = Some(vec![
+            Asset {
+                asset_type: String::from("icon"),
+                uri: String::from(DEFAULT_ASSET_URI),
+                name: String::from("main"),
+                ext: String::from("png"),
+            },
+            Asset {
+                asset_type: String::from("emotion"),
+                uri: format!("{}0", PNG_ASSET_URI_PREFIX),
+                name: String::from("happy"),
+                ext: String::from("png"),
+            },
+            Asset {
+                asset_type: String::from("background"),
+                uri: String::from("https://example.com/bg.png"),
+                name: String::from("bg"),
+                ext: String::from("png"),
+            },
+        ]);
+        card.asset_data.insert(
+            format!("{}0", PNG_ASSET_URI_PREFIX),
+            Bytes::from_static(b"happy sprite"),
+        );
+        card.image_data = Some(tools::get_default_image());
+        card
+    }
+
+    #[test]
+    fn test_write_and_read() -> Result<()> {
+        let card = create_test_card();
+        let charx = write_charx(&card)?;
+        assert!(is_charx(&charx));
+        let card2 = read_charx(&charx)?;
+
+        let uris: Vec<&str> = card2
+            .data
+            .assets
+            .iter()
+            .flatten()
+            .map(|x| x.uri.as_str())
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "embeded://assets/icon/images/main.png",
+                "embeded://assets/emotion/images/happy.png",
+                "https://example.com/bg.png",
+            ]
+        );
+        assert_eq!(card2.image_data, card.image_data);
+        assert_eq!(
+            card2.asset_data["embeded://assets/emotion/images/happy.png"],
+            Bytes::from_static(b"happy sprite")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_png_round_trip() -> Result<()> {
+        let card = create_test_card();
+        // Card read from PNG keeps the whole image, with the card chunks
+        let card = TavernCardV3::from_png_image(&card.into_png_image()?)?;
+        let mut card2 = read_charx(&write_charx(&card)?)?;
+        let icon_keys =
+            tools::list_text_keys(card2.image_data.as_ref().unwrap())?;
+        assert!(icon_keys.is_empty(), "Icon has chunks {:?}", icon_keys);
+        charx_assets_to_png(&mut card2);
+        let image = card2.into_png_image()?;
+
+        let card3 = TavernCardV3::from_png_image(&image)?;
+        assert_eq!(card3.data.assets, card.data.assets);
+        assert_eq!(card3.asset_data, card.asset_data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_assets_keep_avatar() -> Result<()> {
+        let mut card = create_test_card();
+        card.data.assets.as_mut().unwrap().drain(..2);
+        let card2 = read_charx(&write_charx(&card)?)?;
+        let uris: Vec<&str> = card2
+            .data
+            .assets
+            .iter()
+            .flatten()
+            .map(|x| x.uri.as_str())
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "embeded://assets/icon/images/main.png",
+                "https://example.com/bg.png",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Inspecting and editing PNG chunks of card images.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::actions;
+use crate::png_chunks::{self, Chunk};
+use crate::tavern_card_v2::TEXT_KEY_PNG;
+use crate::tavern_card_v3::TEXT_KEY_PNG_V3;
+use crate::tools;
+
+/// Prints all chunks of PNG, and which one the card is read from.
+pub fn list_chunks(path: &Path) -> Result<()> {
+    let image = tools::read_image_from_file(path)?;
+    let chunks = png_chunks::read_chunks(&image)?;
+    println!(
+        "{:>3}  {:<4}  {:>10}  {:>10}  KEYWORD",
+        "#", "TYPE", "SIZE", "OFFSET"
+    );
+    for (i, chunk) in chunks.iter().enumerate() {
+        println!(
+            "{:>3}  {:<4}  {:>10}  {:>10}  {}",
+            i,
+            chunk.type_str(),
+            chunk.data.len(),
+            chunk.offset,
+            chunk.keyword().unwrap_or_default()
+        );
+    }
+    println!();
+    match find_card_chunk(&chunks) {
+        Some((index, reason)) => {
+            println!("Card is read from chunk #{}: {}", index, reason)
+        }
+        None => println!("No card chunks found"),
+    }
+    Ok(())
+}
+
+/// Finds the chunk that the card is read from
+///
+/// Returns its index and the explanation why it was chosen. Mirrors the
+/// lookup done when reading cards: V3 chunk is preferred, keywords are
+/// compared ignoring case, and the first chunk in the file wins.
+pub fn find_card_chunk(chunks: &[Chunk]) -> Option<(usize, String)> {
+    for (key, reason) in [
+        (TEXT_KEY_PNG_V3, "V3 card is preferred over V2"),
+        (TEXT_KEY_PNG, "there is no V3 card"),
+    ] {
+        let matching: Vec<usize> = chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| {
+                x.keyword().is_some_and(|k| k.eq_ignore_ascii_case(key))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        let Some(first) = matching.first() else {
+            continue;
+        };
+        let mut reason = format!("{} chunk, {}", key, reason);
+        if matching.len() > 1 {
+            let others: Vec<String> =
+                matching[1..].iter().map(|x| format!("#{}", x)).collect();
+            reason += &format!(
+                "; it is the first of {} such chunks (keyword case is ignored), so {} are ignored",
+                matching.len(),
+                others.join(", ")
+            );
+        }
+        return Some((*first, reason));
+    }
+    None
+}
+
+/// Prints the decoded content of a chunk.
+///
+/// Text chunks are decompressed, card data is decoded and printed as JSON.
+pub fn dump_chunk(path: &Path, selector: &str) -> Result<()> {
+    let image = tools::read_image_from_file(path)?;
+    let chunks = png_chunks::read_chunks(&image)?;
+    for index in select_chunks(&chunks, selector)? {
+        let chunk = &chunks[index];
+        println!("Chunk #{} ({}):", index, chunk.type_str());
+        match chunk.text()? {
+            Some(text) => {
+                let decoded = tools::decode_card_payload(&text)
+                    .ok()
+                    .and_then(|x| String::from_utf8(x).ok())
+                    .and_then(|x| actions::pretty_json(&x).ok());
+                println!("{}", decoded.unwrap_or(text));
+            }
+            None => println!("{}", hex_dump(&chunk.data, 256)),
+        }
+    }
+    Ok(())
+}
+
+/// Removes chunks from PNG file, in place.
+pub fn remove_chunks(path: &Path, selector: &str) -> Result<()> {
+    let image = tools::read_image_from_file(path)?;
+    let mut chunks = png_chunks::read_chunks(&image)?;
+    let selected = select_chunks(&chunks, selector)?;
+    for index in selected.iter().rev() {
+        if is_critical(&chunks[*index]) {
+            bail!(
+                "Chunk #{} ({}) is required to display the image",
+                index,
+                chunks[*index].type_str()
+            );
+        }
+    }
+    for index in selected.iter().rev() {
+        let chunk = chunks.remove(*index);
+        println!("Removed chunk #{} ({})", index, chunk.type_str());
+    }
+    tools::write_image_to_file(&png_chunks::write_chunks(&chunks), path)?;
+    Ok(())
+}
+
+/// Changes keyword of text chunks in PNG file, in place.
+pub fn rename_chunks(
+    path: &Path,
+    selector: &str,
+    new_keyword: &str,
+) -> Result<()> {
+    let valid = |c: char| (' '..='~').contains(&c);
+    if new_keyword.is_empty()
+        || new_keyword.len() > 79
+        || !new_keyword.chars().all(valid)
+    {
+        bail!("Keyword must be 1 to 79 printable ASCII characters");
+    }
+    let image = tools::read_image_from_file(path)?;
+    let mut chunks = png_chunks::read_chunks(&image)?;
+    for index in select_chunks(&chunks, selector)? {
+        let chunk = &mut chunks[index];
+        let Some(old_keyword) = chunk.keyword() else {
+            bail!(
+                "Chunk #{} ({}) is not a text chunk",
+                index,
+                chunk.type_str()
+            );
+        };
+        let separator = chunk.data.iter().position(|x| *x == 0).unwrap();
+        let mut data = new_keyword.as_bytes().to_vec();
+        data.extend_from_slice(&chunk.data[separator..]);
+        chunk.data = Bytes::from(data);
+        println!(
+            "Renamed chunk #{}: {} -> {}",
+            index, old_keyword, new_keyword
+        );
+    }
+    tools::write_image_to_file(&png_chunks::write_chunks(&chunks), path)?;
+    Ok(())
+}
+
+/// Finds chunks by index (as shown by `list_chunks`) or by exact keyword.
+fn select_chunks(chunks: &[Chunk], selector: &str) -> Result<Vec<usize>> {
+    if let Ok(index) = selector.parse::<usize>() {
+        if index >= chunks.len() {
+            bail!("There is no chunk #{}", index);
+        }
+        return Ok(vec![index]);
+    }
+    let selected: Vec<usize> = chunks
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.keyword().is_some_and(|k| k == selector))
+        .map(|(i, _)| i)
+        .collect();
+    if selected.is_empty() {
+        bail!("No chunk with keyword {}", selector);
+    }
+    Ok(selected)
+}
+
+/// Checks if chunk is critical: the image can't be displayed without it.
+fn is_critical(chunk: &Chunk) -> bool {
+    chunk.chunk_type[0].is_ascii_uppercase()
+}
+
+fn hex_dump(data: &[u8], limit: usize) -> String {
+    let mut lines: Vec<String> = data[..data.len().min(limit)]
+        .chunks(16)
+        .map(|x| {
+            x.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+        })
+        .collect();
+    if data.len() > limit {
+        lines.push(format!("... ({} bytes total)", data.len()));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_list(keywords: &[&str]) -> Vec<Chunk> {
+        let mut chunks = vec![Chunk::new(b"IHDR", Bytes::new())];
+        chunks.extend(keywords.iter().map(|x| Chunk::new_text(x, "e30=")));
+        chunks.push(Chunk::new(b"IEND", Bytes::new()));
+        chunks
+    }
+
+    #[test]
+    fn test_find_card_chunk() {
+        let chunks = chunk_list(&["Comment", "chara", "Chara"]);
+        let (index, reason) = find_card_chunk(&chunks).unwrap();
+        assert_eq!(index, 2);
+        assert!(reason.contains("#3"));
+
+        let chunks = chunk_list(&["Chara", "ccv3"]);
+        let (index, reason) = find_card_chunk(&chunks).unwrap();
+        assert_eq!(index, 2);
+        assert!(reason.contains("V3 card is preferred"));
+
+        assert!(find_card_chunk(&chunk_list(&["Comment"])).is_none());
+    }
+
+    #[test]
+    fn test_select_chunks() -> Result<()> {
+        let chunks = chunk_list(&["chara", "Chara", "Chara"]);
+        assert_eq!(select_chunks(&chunks, "Chara")?, vec![2, 3]);
+        assert_eq!(select_chunks(&chunks, "1")?, vec![1]);
+        assert!(select_chunks(&chunks, "10").is_err());
+        assert!(select_chunks(&chunks, "ccv3").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Cookies sent with downloads, read from the Netscape `cookies.txt` files
+//! that browser extensions export.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cookie {
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    /// Unix time, 0 for session cookies
+    expires: i64,
+    name: String,
+    value: String,
+}
+
+impl Cookie {
+    fn matches(&self, url: &reqwest::Url, now: i64) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let domain_matches = host.eq_ignore_ascii_case(&self.domain)
+            || (self.include_subdomains
+                && host.to_ascii_lowercase().ends_with(&format!(
+                    ".{}",
+                    self.domain.to_ascii_lowercase()
+                )));
+        domain_matches
+            && url.path().starts_with(&self.path)
+            && (!self.secure || url.scheme() == "https")
+            && (self.expires == 0 || self.expires > now)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Reads cookies in the Netscape format
+    ///
+    /// Each line is domain, subdomains flag, path, secure flag, expiry
+    /// time, name and value, separated by tabs. Lines starting with `#`
+    /// are comments, except for the `#HttpOnly_` prefix of the domain.
+    pub fn parse_netscape(text: &str) -> Result<Self> {
+        let mut cookies = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] =
+                fields[..]
+            else {
+                bail!(
+                    "Line {} of cookies file has {} fields instead of 7",
+                    number + 1,
+                    fields.len()
+                );
+            };
+            let domain = domain.trim_start_matches('.');
+            cookies.push(Cookie {
+                domain: domain.to_string(),
+                include_subdomains: subdomains.eq_ignore_ascii_case("TRUE"),
+                path: path.to_string(),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                expires: expires.parse().unwrap_or(0),
+                name: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+        Ok(Self { cookies })
+    }
+
+    pub fn read_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| {
+            format!("Could not read cookies from {}", path.display())
+        })?;
+        Self::parse_netscape(&text)
+    }
+
+    /// Adds a session cookie for the domain and all its subdomains
+    pub fn add(&mut self, domain: &str, name: &str, value: &str) {
+        self.cookies.retain(|x| !(x.domain == domain && x.name == name));
+        self.cookies.push(Cookie {
+            domain: domain.to_string(),
+            include_subdomains: true,
+            path: "/".to_string(),
+            secure: false,
+            expires: 0,
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    /// Value of the `Cookie` header for the URL, if any cookies match it
+    pub fn header_for(&self, url: &reqwest::Url) -> Option<String> {
+        let now = chrono::Utc::now().timestamp();
+        let pairs: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|x| x.matches(url, now))
+            .map(|x| format!("{}={}", x.name, x.value))
+            .collect();
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookies_for_url() -> Result<()> {
+        let text = "# Netscape HTTP Cookie File\n\
+            .example.com\tTRUE\t/\tFALSE\t0\ta\t1\n\
+            #HttpOnly_example.com\tFALSE\t/\tTRUE\t0\tb\t2\n\
+            example.com\tFALSE\t/hub\tFALSE\t0\tc\t3\n\
+            example.com\tFALSE\t/\tFALSE\t1000\told\t4\n\
+            other.com\tFALSE\t/\tFALSE\t0\td\t5\n";
+        let mut jar = CookieJar::parse_netscape(text)?;
+        let header =
+            |url: &str| jar.header_for(&reqwest::Url::parse(url).unwrap());
+        assert_eq!(
+            header("https://example.com/hub/x").unwrap(),
+            "a=1; b=2; c=3"
+        );
+        assert_eq!(header("http://example.com/").unwrap(), "a=1");
+        assert_eq!(header("https://cdn.example.com/hub").unwrap(), "a=1");
+        assert_eq!(header("https://notexample.com/"), None);
+
+        jar.add("example.com", "a", "new");
+        let url = reqwest::Url::parse("https://img.example.com/").unwrap();
+        assert_eq!(jar.header_for(&url).unwrap(), "a=new");
+
+        let error = CookieJar::parse_netscape("a\tb\n").unwrap_err();
+        assert!(error.to_string().contains("Line 1"));
+        Ok(())
+    }
+}
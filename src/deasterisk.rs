@@ -2,12 +2,13 @@
 
 use std::path::Path;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use log::info;
 
 use crate::{
+    placeholders::PlaceholderOptions,
     tavern_card_v2::TavernCardV2,
-    tools::{self, read_image_from_file},
+    tools::{self, WriteOptions},
 };
 
 /// Remove asterisks from text
@@ -67,7 +68,7 @@ fn remove_paired_asterisks(input_str: &str) -> String {
 pub fn deasterisk_tavern_card(tavern_card: &mut TavernCardV2) {
     let d = &mut tavern_card.data;
     let de8 = |x: &mut Option<String>| {
-        let t = x.as_ref().map(|y| remove_paired_asterisks(&y));
+        let t = x.as_ref().map(|y| remove_paired_asterisks(y));
         *x = t;
     };
     de8(&mut d.description);
@@ -90,51 +91,42 @@ pub fn deasterisk_tavern_card(tavern_card: &mut TavernCardV2) {
 // Opens file, applies deasterisk to it, saves in new location.
 pub fn deasterisk_tavern_file(
     png_path: &Path,
-    auto_overwrite: bool,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
 ) -> Result<()> {
     println!("Deasterisk file: {}", &png_path.display());
-    let image_data = read_image_from_file(png_path)?;
-    let mut card = TavernCardV2::from_png_image(&image_data)?;
+    let image_data = tools::read_card_image_from_file(png_path)?;
+    let (mut card, legacy_report) =
+        TavernCardV2::from_png_image_with_report(&image_data)?;
+    if let Some(legacy_report) = &legacy_report {
+        print!("{}", legacy_report);
+    }
+    // V1 cards come from apps with their own placeholders
+    if legacy_report.is_some_and(|x| x.is_v1()) {
+        let report = placeholders.normalize_v2(&mut card.data);
+        if !report.is_empty() {
+            print!("{}", report);
+        }
+    }
     println!(
         "Character name is {}",
         card.data.name.to_owned().unwrap_or_else(|| "".to_string())
     );
     deasterisk_tavern_card(&mut card);
+    card.sync_v1_fields(options.v1_fields);
 
     info!("\nCHARACTER INFO:\n{:#?}", &card.data);
 
-    // Build new file name.
-    let file_name = png_path
-        .file_name()
-        .map_or("image.png".to_string(), |x| x.to_string_lossy().to_string());
-    let new_file_name = format!("de8.{}", file_name);
-    println!("Output file name: {}", new_file_name);
-    let new_path = png_path.with_file_name(new_file_name);
-
-    // Check if output file already exists
-    let path_exists = new_path.try_exists();
-    if let Err(e) = path_exists {
-        bail!("Output path is not available: {}", e);
-    }
-    // If it exists - ask if it should be overwritten, unless `auto_overwrite`
-    // is set to true (then always overwrite).
-    if path_exists.unwrap() {
-        let mut overwrite = auto_overwrite;
-        if !auto_overwrite {
-            println!("File {} already exists. Overwrite?", new_path.display());
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-            overwrite = input.trim().to_lowercase() == "y";
-        }
-        if overwrite {
-            std::fs::remove_file(&new_path)?;
-        } else {
-            bail!("File {} already exists ", new_path.display());
-        }
+    let fields = card.name_fields();
+    let new_image = card.into_png_image_with_compression(options.compress)?;
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(png_path, "de8.{stem}", fields)?
+    else {
+        return Ok(());
     };
+    println!("Output file name: {}", new_path.display());
 
     // Save image to new name
-    let new_image = card.into_png_image()?;
     tools::write_image_to_file(&new_image, &new_path)?;
     println!("Done");
     Ok(())
@@ -143,6 +135,7 @@ pub fn deasterisk_tavern_file(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
 
     #[test]
     fn test_remove_paired_asterisks() {
@@ -204,11 +197,15 @@ mod tests {
             Some(String::from("Only **unpaired** asterisks."));
         card.data.character_book = Some(CharacterBook::default());
         //card.data.character_book.unwrap().entries
-        let mut entry1 = CharacterBookEntry::default();
-        entry1.content = String::from("*Example text of no importance*");
+        let entry1 = CharacterBookEntry {
+            content: String::from("*Example text of no importance*"),
+            ..Default::default()
+        };
 
-        let mut entry2 = CharacterBookEntry::default();
-        entry2.content = String::from("**Example text of no importance**");
+        let entry2 = CharacterBookEntry {
+            content: String::from("**Example text of no importance**"),
+            ..Default::default()
+        };
 
         card.data.character_book.as_mut().unwrap().entries.push(entry1);
         card.data.character_book.as_mut().unwrap().entries.push(entry2);
@@ -244,4 +241,39 @@ mod tests {
             String::from("**Example text of no importance**")
         );
     }
+
+    #[test]
+    fn test_deasterisk_pygmalion_card() -> Result<()> {
+        use base64::prelude::*;
+        use serde_json::json;
+
+        let dir = std::env::temp_dir().join("tavern_card_tools_de8_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let json = json!({
+            "char_name": "Mira",
+            "char_persona": "<BOT> greets <USER> *warmly*.",
+        });
+        let image = tools::write_text_to_png(
+            crate::tavern_card_v2::TEXT_KEY_PNG,
+            &BASE64_STANDARD.encode(json.to_string()),
+            &tools::get_default_image(),
+        )?;
+        let path = dir.join("mira.png");
+        tools::write_image_to_file(&image, &path)?;
+
+        let output = dir.join("out.png");
+        let mut options = WriteOptions::default();
+        options.output.output = Some(output.clone());
+        deasterisk_tavern_file(&path, &options, &Default::default())?;
+        let card = TavernCardV2::from_png_image(&tools::read_image_from_file(
+            &output,
+        )?)?;
+        assert_eq!(
+            card.data.description.as_deref(),
+            Some("{{char}} greets {{user}} warmly.")
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,478 @@
+//! Cards embedded in WebP and JPEG images.
+//!
+//! Some sites store the card JSON (plain or base64) in EXIF UserComment or
+//! in XMP packet of the image. Such cards are converted to PNG cards when
+//! read, so the rest of the program only has to deal with PNG. Cards can be
+//! written back into WebP, with the card in EXIF UserComment.
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+use image::{ImageEncoder, ImageFormat};
+
+use crate::png_chunks::{self, PNG_SIGNATURE};
+use crate::tavern_card_v2::TEXT_KEY_PNG;
+use crate::tavern_card_v3::{PNG_ASSET_KEY_PREFIX, SPEC_V3, TEXT_KEY_PNG_V3};
+use crate::tools;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_USER_COMMENT: u16 = 0x9286;
+
+/// Metadata of WebP or JPEG image that may contain a card
+#[derive(Debug, Default)]
+struct Metadata {
+    /// TIFF structure of EXIF data, without the `Exif` header
+    exif: Option<Vec<u8>>,
+    xmp: Option<String>,
+}
+
+/// Converts image with embedded card into PNG card
+///
+/// PNG images are returned as they are. For WebP and JPEG the card is
+/// searched in XMP (`ccv3` property), then in EXIF UserComment, then in XMP
+/// again (`chara` property). The first one that holds a JSON object wins.
+pub fn to_png_card(image_data: &Bytes) -> Result<Bytes> {
+    if image_data.starts_with(PNG_SIGNATURE) {
+        return Ok(image_data.clone());
+    }
+    let metadata = match image::guess_format(image_data) {
+        Ok(ImageFormat::WebP) => read_webp_metadata(image_data)?,
+        Ok(ImageFormat::Jpeg) => read_jpeg_metadata(image_data)?,
+        _ => bail!("Not a PNG, WebP or JPEG image"),
+    };
+    let xmp = metadata.xmp.as_deref();
+    let candidates = [
+        xmp.and_then(|x| find_xmp_value(x, TEXT_KEY_PNG_V3)),
+        metadata.exif.as_deref().and_then(read_user_comment),
+        xmp.and_then(|x| find_xmp_value(x, TEXT_KEY_PNG)),
+    ];
+    let Some((json, card)) = candidates
+        .into_iter()
+        .flatten()
+        .filter_map(|x| tools::decode_card_payload(&x).ok())
+        .find_map(|x| {
+            let json: serde_json::Value = serde_json::from_slice(&x).ok()?;
+            json.is_object().then_some((json, x))
+        })
+    else {
+        bail!("No card found in EXIF or XMP metadata of the image");
+    };
+
+    let key = match json.get("spec").and_then(|x| x.as_str()) {
+        Some(SPEC_V3) => TEXT_KEY_PNG_V3,
+        _ => TEXT_KEY_PNG,
+    };
+    let png = tools::convert_to_png(image_data)?;
+    tools::write_text_to_png(key, &BASE64_STANDARD.encode(card), &png)
+}
+
+/// Converts PNG card into WebP image, with the card in EXIF UserComment
+///
+/// The image is encoded losslessly. Only the card itself is kept: V3 card
+/// is preferred, and assets stored in PNG chunks are dropped.
+pub fn png_card_to_webp(png_data: &Bytes) -> Result<Bytes> {
+    let chunks = png_chunks::read_chunks(png_data)?;
+    let mut card = None;
+    for key in [TEXT_KEY_PNG_V3, TEXT_KEY_PNG] {
+        card = tools::read_text_chunk(png_data, key)?;
+        if card.is_some() {
+            break;
+        }
+    }
+    let card = card.context("No card in PNG image")?;
+    let has_assets = chunks.iter().any(|x| {
+        x.keyword().is_some_and(|k| k.starts_with(PNG_ASSET_KEY_PREFIX))
+    });
+    if has_assets {
+        println!("Warning: embedded assets can't be stored in WebP, dropped");
+    }
+    let card = BASE64_STANDARD.encode(tools::decode_card_payload(&card)?);
+
+    let image = image::load_from_memory(png_data)?.to_rgba8();
+    let mut encoded = Vec::new();
+    image::codecs::webp::WebPEncoder::new_lossless(&mut encoded).write_image(
+        &image,
+        image.width(),
+        image.height(),
+        image::ExtendedColorType::Rgba8,
+    )?;
+    let Some((_, bitstream)) =
+        riff_chunks(&encoded)?.into_iter().find(|(name, _)| name == b"VP8L")
+    else {
+        bail!("WebP encoder did not produce a lossless bitstream");
+    };
+
+    // Extended format: the VP8X header is required to attach EXIF.
+    let mut vp8x = vec![0x10 | 0x08, 0, 0, 0]; // Alpha and EXIF flags
+    vp8x.extend_from_slice(&(image.width() - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(image.height() - 1).to_le_bytes()[..3]);
+    let mut body = b"WEBP".to_vec();
+    for (name, data) in [
+        (b"VP8X", vp8x.as_slice()),
+        (b"VP8L", bitstream),
+        (b"EXIF", &build_exif(&card)),
+    ] {
+        body.extend_from_slice(name);
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend(body);
+    Ok(Bytes::from(output))
+}
+
+/// Splits WebP file into RIFF chunks.
+fn riff_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        bail!("Not a WebP image");
+    }
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let name: [u8; 4] = data[pos..pos + 4].try_into()?;
+        let size =
+            u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        let end = (pos + 8 + size).min(data.len());
+        chunks.push((name, &data[pos + 8..end]));
+        pos = end + size % 2;
+    }
+    Ok(chunks)
+}
+
+fn read_webp_metadata(data: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    for (name, chunk) in riff_chunks(data)? {
+        match &name {
+            b"EXIF" => {
+                // The header is not in spec, but some apps write it anyway.
+                let tiff = chunk.strip_prefix(EXIF_HEADER).unwrap_or(chunk);
+                metadata.exif = Some(tiff.to_vec());
+            }
+            b"XMP " => {
+                metadata.xmp = Some(String::from_utf8_lossy(chunk).to_string())
+            }
+            _ => (),
+        }
+    }
+    Ok(metadata)
+}
+
+fn read_jpeg_metadata(data: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut pos = 2; // Skip SOI marker
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            bail!("Broken JPEG segment at offset {}", pos);
+        }
+        let marker = data[pos + 1];
+        // Start of scan: the image data follows, no more metadata.
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let size = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = (pos + 2 + size).min(data.len());
+        let segment = &data[(pos + 4).min(end)..end];
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(EXIF_HEADER) {
+                metadata.exif = Some(tiff.to_vec());
+            } else if let Some(xmp) = segment.strip_prefix(XMP_HEADER) {
+                metadata.xmp = Some(String::from_utf8_lossy(xmp).to_string());
+            }
+        }
+        pos = end;
+    }
+    Ok(metadata)
+}
+
+/// Reads UserComment tag from TIFF structure of EXIF data.
+fn read_user_comment(tiff: &[u8]) -> Option<String> {
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    // Returns (count, value position) of a tag in IFD.
+    let find_tag = |ifd: usize, tag: u16| -> Option<(usize, usize)> {
+        let entries = u16_at(ifd)? as usize;
+        (0..entries).map(|i| ifd + 2 + i * 12).find_map(|entry| {
+            if u16_at(entry)? != tag {
+                return None;
+            }
+            let count = u32_at(entry + 4)? as usize;
+            let value = match count <= 4 {
+                true => entry + 8,
+                false => u32_at(entry + 8)? as usize,
+            };
+            Some((count, value))
+        })
+    };
+
+    let ifd0 = u32_at(4)? as usize;
+    let (count, pos) = find_tag(ifd0, TAG_USER_COMMENT).or_else(|| {
+        let (_, pointer) = find_tag(ifd0, TAG_EXIF_IFD)?;
+        find_tag(u32_at(pointer)? as usize, TAG_USER_COMMENT)
+    })?;
+    let comment = tiff.get(pos..pos + count)?;
+    let (charset, text) = comment.split_at_checked(8)?;
+    let text = match charset {
+        b"UNICODE\0" => {
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|x| match big_endian {
+                    true => u16::from_be_bytes([x[0], x[1]]),
+                    false => u16::from_le_bytes([x[0], x[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).to_string(),
+    };
+    Some(text.trim_end_matches(['\0', ' ']).to_string())
+}
+
+/// Builds EXIF data (TIFF structure) with only a UserComment tag.
+fn build_exif(comment: &str) -> Vec<u8> {
+    let mut value = b"ASCII\0\0\0".to_vec();
+    value.extend_from_slice(comment.as_bytes());
+
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes()); // IFD0 offset
+
+    // IFD0: one entry, pointer to Exif IFD (which starts at 26)
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    tiff.extend_from_slice(&TAG_EXIF_IFD.to_le_bytes());
+    tiff.extend_from_slice(&4u16.to_le_bytes()); // LONG
+    tiff.extend_from_slice(&1u32.to_le_bytes());
+    tiff.extend_from_slice(&26u32.to_le_bytes());
+    tiff.extend_from_slice(&0u32.to_le_bytes()); // No next IFD
+
+    // Exif IFD: one entry, UserComment with value at 44
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    tiff.extend_from_slice(&TAG_USER_COMMENT.to_le_bytes());
+    tiff.extend_from_slice(&7u16.to_le_bytes()); // UNDEFINED
+    tiff.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    tiff.extend_from_slice(&44u32.to_le_bytes());
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend(value);
+    tiff
+}
+
+/// Finds value of XMP property by name, ignoring namespace and case.
+///
+/// The value may be written both as an attribute (`cc:chara="..."`) and
+/// as an element (`<cc:chara>...</cc:chara>`), possibly wrapped in
+/// `rdf:Alt` or similar containers.
+fn find_xmp_value(xmp: &str, name: &str) -> Option<String> {
+    // ASCII only, so that positions in both strings are the same.
+    let lower = xmp.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(&name) {
+        let start = from + found;
+        let end = start + name.len();
+        from = end;
+        let before = lower[..start].chars().next_back();
+        if !matches!(before, Some(':' | '<') | Some(' ' | '\t' | '\r' | '\n')) {
+            continue;
+        }
+        let rest = lower[end..].trim_start();
+        let offset = end + (lower.len() - end - rest.len());
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let quote = after_eq.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                continue;
+            }
+            let value_start = lower.len() - after_eq.len() + 1;
+            let value_end = value_start + xmp[value_start..].find(quote)?;
+            return Some(unescape_xml(&xmp[value_start..value_end]));
+        }
+        if matches!(before, Some(':' | '<')) && rest.starts_with('>') {
+            let content_start = offset + 1;
+            // The closing tag has the same qualified name.
+            let qualified_start = lower[..start].rfind('<')? + 1;
+            let closing = format!("</{}>", &lower[qualified_start..end]);
+            let content_end =
+                content_start + lower[content_start..].find(&closing)?;
+            let content = strip_xml_tags(&xmp[content_start..content_end]);
+            return Some(unescape_xml(content.trim()));
+        }
+    }
+    None
+}
+
+fn strip_xml_tags(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => output.push(c),
+            _ => (),
+        }
+    }
+    output
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        output.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semicolon) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semicolon];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|x| x.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                output.push(c);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tavern_card_v2::TavernCardV2;
+    use crate::tavern_card_v3::TavernCardV3;
+    use anyhow::Result;
+
+    fn create_test_card() -> TavernCardV2 {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test name".to_string());
+        card.data.description = Some("Ünïcode <and> \"quotes\"".to_string());
+        card
+    }
+
+    /// Encodes default image as JPEG with a given APP1 segment.
+    fn create_jpeg(app1: &[u8]) -> Result<Bytes> {
+        let image = image::load_from_memory(&tools::get_default_image())?;
+        let mut jpeg = Vec::new();
+        image.to_rgb8().write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            ImageFormat::Jpeg,
+        )?;
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(app1);
+        jpeg.splice(2..2, segment);
+        Ok(Bytes::from(jpeg))
+    }
+
+    #[test]
+    fn test_webp_round_trip() -> Result<()> {
+        let card = create_test_card();
+        let webp = png_card_to_webp(&card.into_png_image()?)?;
+        assert_eq!(image::guess_format(&webp)?, ImageFormat::WebP);
+        // The image must stay readable by image decoders.
+        image::load_from_memory(&webp)?;
+
+        let card2 = TavernCardV2::from_png_image(&to_png_card(&webp)?)?;
+        assert_eq!(card2.data, card.data);
+
+        let (card_v3, _) = TavernCardV3::upgrade(card);
+        let webp = png_card_to_webp(&card_v3.into_png_image()?)?;
+        let png = to_png_card(&webp)?;
+        assert!(tools::read_text_chunk(&png, TEXT_KEY_PNG_V3)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_jpeg_exif() -> Result<()> {
+        let card = create_test_card();
+        let json = serde_json::to_string(&card)?;
+        let mut app1 = EXIF_HEADER.to_vec();
+        app1.extend(build_exif(&json));
+        let png = to_png_card(&create_jpeg(&app1)?)?;
+        let card2 = TavernCardV2::from_png_image(&png)?;
+        assert_eq!(card2.data, card.data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jpeg_xmp() -> Result<()> {
+        let card = create_test_card();
+        let encoded = BASE64_STANDARD.encode(serde_json::to_string(&card)?);
+        let xmp = format!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description \
+             ai:Chara=\"{}\"/></rdf:RDF></x:xmpmeta>",
+            encoded
+        );
+        let mut app1 = XMP_HEADER.to_vec();
+        app1.extend_from_slice(xmp.as_bytes());
+        let png = to_png_card(&create_jpeg(&app1)?)?;
+        let card2 = TavernCardV2::from_png_image(&png)?;
+        assert_eq!(card2.data, card.data);
+
+        assert!(to_png_card(&create_jpeg(b"nothing")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_xmp_value() {
+        let xmp =
+            "<rdf:Description a:other=\"1\" a:chara = '{&quot;a&quot;:1}'/>";
+        assert_eq!(find_xmp_value(xmp, "chara").as_deref(), Some("{\"a\":1}"));
+        let xmp = "<a:ccv3><rdf:Alt><rdf:li xml:lang=\"x-default\">e30=\
+                   </rdf:li></rdf:Alt></a:ccv3>";
+        assert_eq!(find_xmp_value(xmp, "ccv3").as_deref(), Some("e30="));
+        assert_eq!(find_xmp_value("<a:characters/>", "chara"), None);
+    }
+
+    #[test]
+    fn test_unicode_user_comment() {
+        let mut tiff = build_exif("");
+        tiff.truncate(44);
+        let text: Vec<u8> =
+            "{\"é\":1}".encode_utf16().flat_map(|x| x.to_le_bytes()).collect();
+        tiff.extend_from_slice(b"UNICODE\0");
+        tiff.extend(&text);
+        let count = (8 + text.len()) as u32;
+        tiff[32..36].copy_from_slice(&count.to_le_bytes());
+        assert_eq!(read_user_comment(&tiff).as_deref(), Some("{\"é\":1}"));
+    }
+}
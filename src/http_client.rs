@@ -0,0 +1,180 @@
+//! HTTP client for downloads: sends cookies, keeps a polite rate limit and
+//! retries transient errors.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::info;
+use reqwest::{blocking::Response, StatusCode};
+
+use crate::cookie_jar::CookieJar;
+
+/// Longest wait between retries, even if the server asks for more
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct HttpClient {
+    client: reqwest::blocking::Client,
+    cookies: CookieJar,
+    /// Shortest time between the starts of two requests
+    interval: Duration,
+    /// How many times to repeat a request that failed with a transient error
+    retries: u32,
+    /// Wait before the first retry, doubled for each next one
+    backoff: Duration,
+    next_request: Mutex<Instant>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(CookieJar::default())
+    }
+}
+
+impl HttpClient {
+    pub fn new(cookies: CookieJar) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            cookies,
+            interval: Duration::ZERO,
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sends GET request with the cookies that match the URL
+    ///
+    /// Connection errors, timeouts and the 429 and 5xx statuses are
+    /// retried. After the last retry the response is returned as it is.
+    pub fn get(&self, url: &str) -> Result<Response> {
+        let url = reqwest::Url::parse(url).context("Invalid URL")?;
+        let mut attempt = 0;
+        loop {
+            self.wait_for_turn();
+            let mut request = self.client.get(url.clone());
+            if let Some(header) = self.cookies.header_for(&url) {
+                request = request.header(reqwest::header::COOKIE, header);
+            }
+            let result = request.send();
+            let retry_after = match &result {
+                Ok(response) if is_transient_status(response.status()) => {
+                    Some(retry_after(response))
+                }
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_timeout() => Some(None),
+                Err(_) => None,
+            };
+            let Some(retry_after) = retry_after else {
+                return Ok(result?);
+            };
+            if attempt >= self.retries {
+                return Ok(result?);
+            }
+            let wait = retry_after
+                .unwrap_or(self.backoff * 2u32.pow(attempt))
+                .min(MAX_BACKOFF);
+            info!("Retrying {} in {:?}", url, wait);
+            thread::sleep(wait);
+            attempt += 1;
+        }
+    }
+
+    /// Sleeps until the rate limit allows the next request
+    fn wait_for_turn(&self) {
+        let wait = {
+            let mut next = self.next_request.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + self.interval;
+            start - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::INTERNAL_SERVER_ERROR
+        || status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::SERVICE_UNAVAILABLE
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+/// Wait asked by the `Retry-After` header, in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    let header = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let seconds = header.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    /// Answers `/flaky` with 503 twice, then 200; anything else with 404.
+    /// Returns the address and the request counter.
+    fn start_test_server() -> (String, Arc<AtomicU32>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", server.server_addr());
+        let counter = Arc::new(AtomicU32::new(0));
+        let requests = counter.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let count = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                let status = match request.url() {
+                    "/flaky" if count <= 2 => 503,
+                    "/flaky" => 200,
+                    _ => 404,
+                };
+                let response = tiny_http::Response::empty(status);
+                request.respond(response).unwrap();
+            }
+        });
+        (address, counter)
+    }
+
+    #[test]
+    fn test_retries_and_rate_limit() -> Result<()> {
+        let (address, counter) = start_test_server();
+        let client = HttpClient::default()
+            .with_retries(3, Duration::from_millis(1))
+            .with_interval(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let response = client.get(&format!("{}/flaky", address))?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        // Not found is not transient
+        let response = client.get(&format!("{}/missing", address))?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+
+        // Without retries the transient error is returned
+        counter.store(0, Ordering::SeqCst);
+        let client = HttpClient::default();
+        let response = client.get(&format!("{}/flaky", address))?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+}
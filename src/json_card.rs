@@ -0,0 +1,271 @@
+//! Exporting cards to JSON files and importing them back.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+
+use crate::output_naming::NameFields;
+use crate::placeholders::PlaceholderOptions;
+use crate::tavern_card_v1::has_legacy_keys;
+use crate::tavern_card_v2::*;
+use crate::tavern_card_v3::{TavernCardV3, SPEC_V3, TEXT_KEY_PNG_V3};
+use crate::tools::{self, ExportOptions, WriteOptions};
+
+/// Top level key for the avatar image, stored as data URL
+pub const AVATAR_KEY: &str = "avatar";
+const DATA_URL_PREFIX: &str = "data:image/png;base64,";
+
+/// Extracts card JSON from card image
+///
+/// The JSON is kept as it was stored in the image (V3 card is preferred),
+/// only pretty-printed. If `with_avatar` is set, the image without the card
+/// is added at the top level as a data URL.
+pub fn card_json_from_image(
+    image_data: &Bytes,
+    with_avatar: bool,
+) -> Result<String> {
+    let mut text = tools::read_text_chunk(image_data, TEXT_KEY_PNG_V3)?;
+    if text.is_none() {
+        text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?;
+    }
+    let text = text.context("No card in the image")?;
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&tools::decode_card_payload(&text)?)
+            .context("Card in the image is not valid JSON")?;
+    if with_avatar {
+        let Some(top_level) = json.as_object_mut() else {
+            bail!("Card in the image is not a JSON object");
+        };
+        let avatar = strip_card_chunks(image_data)?;
+        let url =
+            format!("{}{}", DATA_URL_PREFIX, BASE64_STANDARD.encode(avatar));
+        top_level.insert(AVATAR_KEY.to_string(), url.into());
+    }
+    Ok(serde_json::to_string_pretty(&json)?)
+}
+
+/// Builds PNG card from card JSON
+///
+/// Accepts the full card (V1, V2 or V3) and the bare V2 `data` object. The
+/// image is taken from `image_data`, or from the avatar data URL in the
+/// JSON, or the default image is used. Placeholders in the text are
+/// normalized.
+pub fn card_image_from_json(
+    json: &str,
+    image_data: Option<&Bytes>,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
+) -> Result<Bytes> {
+    let mut json: serde_json::Value =
+        serde_json::from_str(json).context("Not a valid JSON")?;
+    let Some(top_level) = json.as_object_mut() else {
+        bail!("Card JSON is not an object");
+    };
+
+    let avatar = match top_level.get(AVATAR_KEY).and_then(|x| x.as_str()) {
+        Some(url) if url.starts_with("data:") => {
+            let avatar = decode_data_url(url)?;
+            top_level.remove(AVATAR_KEY);
+            Some(avatar)
+        }
+        _ => None,
+    };
+    let image = match (image_data, avatar) {
+        (Some(image), _) => tools::convert_to_png(image)?,
+        (None, Some(avatar)) => tools::convert_to_png(&avatar)?,
+        (None, None) => tools::get_default_image(),
+    };
+    let image = strip_card_chunks(&image)?;
+
+    // Bare data object gets the V2 envelope. V1 cards are upgraded when
+    // read from the image, just like V1 PNG cards.
+    let has_envelope = top_level.contains_key("data");
+    if !has_envelope && !has_legacy_keys(&json) {
+        json = serde_json::json!({
+            "spec": SPEC_V2,
+            "spec_version": SPEC_VERSION_V2,
+            "data": json,
+        });
+    }
+    let is_v3 = json.get("spec").and_then(|x| x.as_str()) == Some(SPEC_V3);
+    let key = if is_v3 { TEXT_KEY_PNG_V3 } else { TEXT_KEY_PNG };
+    let encoded = BASE64_STANDARD.encode(serde_json::to_string(&json)?);
+    let image = tools::write_text_to_png(key, &encoded, &image)?;
+
+    if is_v3 {
+        let mut card = TavernCardV3::from_png_image(&image)?;
+        let report =
+            placeholders.for_native_card().normalize_v3(&mut card.data);
+        if !report.is_empty() {
+            print!("{}", report);
+        }
+        card.sync_v1_fields(options.v1_fields);
+        card.into_png_image_with_compression(options.compress)
+    } else {
+        let (mut card, legacy_report) =
+            TavernCardV2::from_png_image_with_report(&image)?;
+        if let Some(legacy_report) = &legacy_report {
+            print!("{}", legacy_report);
+        }
+        // Cards exported from Tavern apps keep their "User" words and name
+        let placeholders = match has_envelope && legacy_report.is_none() {
+            true => placeholders.for_native_card(),
+            false => placeholders.clone(),
+        };
+        let report = placeholders.normalize_v2(&mut card.data);
+        if !report.is_empty() {
+            print!("{}", report);
+        }
+        card.sync_v1_fields(options.v1_fields);
+        card.into_png_image_with_compression(options.compress)
+    }
+}
+
+/// Saves card JSON from card image to <old_name>.json
+pub fn export_json_file(
+    path: &Path,
+    with_avatar: bool,
+    options: &ExportOptions,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let json = card_json_from_image(&image, with_avatar)?;
+
+    let fields = NameFields::from_card_json(&serde_json::from_str(&json)?);
+    let Some(new_path) = options.output_path(path, "{stem}", fields, "json")?
+    else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::replace_file(&new_path, json.as_bytes())?;
+    println!("Done");
+    Ok(())
+}
+
+/// Builds card image from JSON file. Saves the result to <old_name>.png
+pub fn import_json_file(
+    path: &Path,
+    image_path: Option<&Path>,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
+) -> Result<()> {
+    let json = std::fs::read_to_string(path)?;
+    let image = image_path.map(tools::read_image_from_file).transpose()?;
+    let new_image =
+        card_image_from_json(&json, image.as_ref(), options, placeholders)?;
+
+    let fields = serde_json::from_str(&json)
+        .map(|x| NameFields::from_card_json(&x))
+        .unwrap_or_default();
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(path, "{stem}", fields)? else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&new_image, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Removes card chunks from PNG, keeping asset chunks and the rest.
+pub fn strip_card_chunks(image_data: &Bytes) -> Result<Bytes> {
+    let image = tools::remove_text_from_png(TEXT_KEY_PNG, image_data)?;
+    tools::remove_text_from_png(TEXT_KEY_PNG_V3, &image)
+}
+
+fn decode_data_url(url: &str) -> Result<Bytes> {
+    let Some((header, data)) = url.split_once(',') else {
+        bail!("Avatar is not a valid data URL");
+    };
+    if !header.ends_with(";base64") {
+        bail!("Avatar data URL is not base64-encoded");
+    }
+    Ok(Bytes::from(BASE64_STANDARD.decode(data.trim())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_export_and_import() -> Result<()> {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test name".to_string());
+        card.data.unknown_fields.insert("custom".into(), 42.into());
+        let image = card.into_png_image()?;
+
+        let json = card_json_from_image(&image, false)?;
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(value["data"]["custom"], 42);
+        assert!(value.get(AVATAR_KEY).is_none());
+
+        let options = WriteOptions::default();
+        let placeholders = PlaceholderOptions::default();
+        let image2 =
+            card_image_from_json(&json, None, &options, &placeholders)?;
+        let card2 = TavernCardV2::from_png_image(&image2)?;
+        assert_eq!(card2.data, card.data);
+
+        // Bare data object
+        let data = serde_json::to_string(&value["data"])?;
+        let image3 =
+            card_image_from_json(&data, None, &options, &placeholders)?;
+        assert_eq!(TavernCardV2::from_png_image(&image3)?.data, card.data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_and_import_keeps_name() -> Result<()> {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Mira".to_string());
+        card.data.description =
+            Some("Mira greets User and {{user}}.".to_string());
+        let image = card.into_png_image()?;
+
+        let json = card_json_from_image(&image, false)?;
+        let options = WriteOptions::default();
+        let placeholders = PlaceholderOptions::default();
+        let image2 =
+            card_image_from_json(&json, None, &options, &placeholders)?;
+        let card2 = TavernCardV2::from_png_image(&image2)?;
+        assert_eq!(card2.data, card.data);
+
+        // Bare data object is normalized in full
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        let data = serde_json::to_string(&value["data"])?;
+        let image3 =
+            card_image_from_json(&data, None, &options, &placeholders)?;
+        assert_eq!(
+            TavernCardV2::from_png_image(&image3)?.data.description.as_deref(),
+            Some("{{char}} greets {{user}} and {{user}}.")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_avatar_data_url() -> Result<()> {
+        let (mut card, _) = TavernCardV3::upgrade(TavernCardV2::new());
+        card.data.name = Some("Test name".to_string());
+        let image = card.into_png_image()?;
+
+        let json = card_json_from_image(&image, true)?;
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        let url = value[AVATAR_KEY].as_str().unwrap();
+        assert!(url.starts_with(DATA_URL_PREFIX));
+        assert_eq!(decode_data_url(url)?, strip_card_chunks(&image)?);
+
+        let image2 = card_image_from_json(
+            &json,
+            None,
+            &Default::default(),
+            &Default::default(),
+        )?;
+        assert_eq!(strip_card_chunks(&image2)?, strip_card_chunks(&image)?);
+        let card2 = TavernCardV3::from_png_image(&image2)?;
+        assert_eq!(card2.data, card.data);
+        assert!(!card2.unknown_fields.contains_key(AVATAR_KEY));
+        Ok(())
+    }
+}
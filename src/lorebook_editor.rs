@@ -0,0 +1,579 @@
+//! Editing lorebook entries of a card in place.
+//!
+//! Only `data.character_book` of the card chunks is rewritten. The rest of
+//! the card JSON, other chunks and the image data stay as they were.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use clap::ValueHint;
+use serde_json::Value;
+
+use crate::png_chunks;
+use crate::tavern_card_v2::*;
+use crate::tools;
+
+/// Insertion order of the first entry in an empty lorebook
+pub const DEFAULT_INSERTION_ORDER: u32 = 100;
+/// Length of content preview in the list of entries
+const PREVIEW_LENGTH: usize = 60;
+
+/// Entry fields that can be set from the command line
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct EntryFields {
+    /// Keys that activate the entry, comma-separated
+    #[arg(long, value_delimiter = ',')]
+    pub keys: Option<Vec<String>>,
+
+    /// Secondary keys, comma-separated. Makes the entry selective
+    #[arg(long, value_delimiter = ',')]
+    pub secondary_keys: Option<Vec<String>>,
+
+    /// Text of the entry
+    #[arg(long, conflicts_with = "content_file")]
+    pub content: Option<String>,
+
+    /// Read text of the entry from file, or from stdin if "-"
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub content_file: Option<PathBuf>,
+
+    /// Comment (title) of the entry
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Name of the entry
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Insertion order
+    #[arg(long)]
+    pub order: Option<u32>,
+
+    /// Priority, used when the token budget is exceeded
+    #[arg(long)]
+    pub priority: Option<u32>,
+
+    /// Always insert the entry, without matching keys
+    #[arg(long)]
+    pub constant: Option<bool>,
+
+    /// Require one of the secondary keys to match too
+    #[arg(long)]
+    pub selective: Option<bool>,
+
+    /// Match keys case-sensitively
+    #[arg(long)]
+    pub case_sensitive: Option<bool>,
+
+    /// Where to insert the entry
+    #[arg(long, value_parser = ["before_char", "after_char"])]
+    pub position: Option<String>,
+}
+
+impl EntryFields {
+    /// Sets the given fields of the entry, leaving the rest as they are
+    pub fn apply(&self, entry: &mut CharacterBookEntry) -> Result<()> {
+        if let Some(content) = self.read_content()? {
+            entry.content = content;
+        }
+        if let Some(keys) = &self.keys {
+            entry.keys = clean_keys(keys);
+        }
+        if let Some(keys) = &self.secondary_keys {
+            let keys = clean_keys(keys);
+            if self.selective.is_none() {
+                entry.selective = Some(!keys.is_empty());
+            }
+            entry.secondary_keys = Some(keys);
+        }
+        let text_fields = [
+            (&self.comment, &mut entry.comment),
+            (&self.name, &mut entry.name),
+            (&self.position, &mut entry.position),
+        ];
+        for (value, field) in text_fields {
+            if let Some(value) = value {
+                *field = (!value.is_empty()).then(|| value.clone());
+            }
+        }
+        let number_fields = [
+            (self.order, &mut entry.insertion_order),
+            (self.priority, &mut entry.priority),
+        ];
+        for (value, field) in number_fields {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+        let flag_fields = [
+            (self.constant, &mut entry.constant),
+            (self.selective, &mut entry.selective),
+            (self.case_sensitive, &mut entry.case_sensitive),
+        ];
+        for (value, field) in flag_fields {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_content(&self) -> Result<Option<String>> {
+        if let Some(content) = &self.content {
+            return Ok(Some(content.clone()));
+        }
+        let Some(path) = &self.content_file else {
+            return Ok(None);
+        };
+        let content = if path.as_os_str() == "-" {
+            let mut content = String::new();
+            std::io::stdin().read_to_string(&mut content)?;
+            content
+        } else {
+            std::fs::read_to_string(path).with_context(|| {
+                format!("Can't read content from {}", path.display())
+            })?
+        };
+        Ok(Some(content.trim_end().to_string()))
+    }
+}
+
+fn clean_keys(keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Finds entries matching the selector
+///
+/// `#N` is the position of the entry in the list, a number is the entry id,
+/// anything else is one of the entry keys, compared ignoring case.
+pub fn find_entries(book: &CharacterBook, selector: &str) -> Vec<usize> {
+    let selector = selector.trim();
+    let matches = |entry: &CharacterBookEntry, index: usize| {
+        if let Some(index_text) = selector.strip_prefix('#') {
+            return index_text.parse() == Ok(index);
+        }
+        if let Ok(id) = selector.parse::<u32>() {
+            return entry.id == Some(id);
+        }
+        entry.keys.iter().any(|x| x.trim().eq_ignore_ascii_case(selector))
+    };
+    book.entries
+        .iter()
+        .enumerate()
+        .filter(|(index, entry)| matches(entry, *index))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Finds the single entry matching the selector, see `find_entries`
+pub fn find_entry(book: &CharacterBook, selector: &str) -> Result<usize> {
+    match find_entries(book, selector).as_slice() {
+        [] => bail!("No lorebook entry matches '{}'", selector),
+        [index] => Ok(*index),
+        found => {
+            let found: Vec<String> =
+                found.iter().map(|x| format!("#{}", x)).collect();
+            bail!(
+                "'{}' matches several entries: {}. Use #N to pick one",
+                selector,
+                found.join(", ")
+            )
+        }
+    }
+}
+
+/// Adds a new entry at the end of the lorebook. Returns its index
+///
+/// The entry gets the next free id and is inserted after all others.
+pub fn add_entry(
+    book: &mut CharacterBook,
+    fields: &EntryFields,
+) -> Result<usize> {
+    let mut entry = CharacterBookEntry {
+        id: Some(
+            book.entries.iter().filter_map(|x| x.id).max().map_or(0, |x| x + 1),
+        ),
+        insertion_order: Some(
+            book.entries
+                .iter()
+                .filter_map(|x| x.insertion_order)
+                .max()
+                .map_or(DEFAULT_INSERTION_ORDER, |x| x + 1),
+        ),
+        ..Default::default()
+    };
+    fields.apply(&mut entry)?;
+    if fields.content.is_none() && fields.content_file.is_none() {
+        bail!("New entry needs --content or --content-file");
+    }
+    if entry.keys.is_empty() && entry.constant != Some(true) {
+        bail!("New entry needs --keys, unless it is --constant true");
+    }
+    book.entries.push(entry);
+    Ok(book.entries.len() - 1)
+}
+
+/// Moves entry to a new position in the list
+pub fn move_entry(
+    book: &mut CharacterBook,
+    index: usize,
+    new_index: usize,
+) -> Result<()> {
+    if new_index >= book.entries.len() {
+        bail!(
+            "Position #{} is out of range, lorebook has {} entries",
+            new_index,
+            book.entries.len()
+        );
+    }
+    let entry = book.entries.remove(index);
+    book.entries.insert(new_index, entry);
+    Ok(())
+}
+
+/// Gives entries sequential ids in list order, starting from `start`
+///
+/// If `order_step` is given, insertion order is renumbered the same way:
+/// the first entry gets `order_step`, the second twice that, and so on.
+pub fn renumber_entries(
+    book: &mut CharacterBook,
+    start: u32,
+    order_step: Option<u32>,
+) {
+    for (i, entry) in book.entries.iter_mut().enumerate() {
+        entry.id = Some(start + i as u32);
+        if let Some(step) = order_step {
+            entry.insertion_order = Some(step * (i as u32 + 1));
+        }
+    }
+}
+
+/// Reads lorebook of a PNG card
+///
+/// The book is taken from the chunk the card is read from, so V3 card is
+/// preferred. Returns `None` if the card has no lorebook.
+pub fn read_book(image_data: &Bytes) -> Result<Option<CharacterBook>> {
+    let chunks = png_chunks::read_chunks(image_data)?;
+    let Some((_, json)) = tools::read_card_chunks(&chunks)?.into_iter().next()
+    else {
+        bail!("No card in the image");
+    };
+    match json.pointer("/data/character_book") {
+        None | Some(Value::Null) => Ok(None),
+        Some(book) => Ok(Some(
+            serde_json::from_value(book.clone())
+                .context("Lorebook of the card is not valid")?,
+        )),
+    }
+}
+
+/// Writes lorebook into all card chunks of a PNG card
+///
+/// Chunks keep their place and type (tEXt or zTXt); everything else in the
+/// image is copied as it is.
+pub fn write_book(image_data: &Bytes, book: &CharacterBook) -> Result<Bytes> {
+    let mut chunks = png_chunks::read_chunks(image_data)?;
+    let card_chunks = tools::read_card_chunks(&chunks)?;
+    if card_chunks.is_empty() {
+        bail!("No card in the image");
+    }
+    let book = serde_json::to_value(book)?;
+    for (index, mut json) in card_chunks {
+        let Some(data) = json.get_mut("data").and_then(|x| x.as_object_mut())
+        else {
+            bail!("V1 cards have no lorebook, upgrade the card first");
+        };
+        data.insert("character_book".to_string(), book.clone());
+        chunks[index] = tools::card_chunk_with_json(&chunks[index], &json)?;
+    }
+    Ok(png_chunks::write_chunks(&chunks))
+}
+
+/// Reads the card file, lets `edit` change the lorebook and saves it back
+///
+/// If the card has no lorebook, an empty one is created when
+/// `create_missing` is set, otherwise it is an error.
+pub fn edit_book_file(
+    path: &Path,
+    create_missing: bool,
+    edit: impl FnOnce(&mut CharacterBook) -> Result<()>,
+) -> Result<()> {
+    let image = tools::read_image_from_file(path)?;
+    if !image.starts_with(png_chunks::PNG_SIGNATURE) {
+        bail!(
+            "{} is not PNG, only PNG cards are edited in place",
+            path.display()
+        );
+    }
+    let mut book = match read_book(&image)? {
+        Some(book) => book,
+        None if create_missing => CharacterBook::default(),
+        None => bail!("{} has no lorebook", path.display()),
+    };
+    edit(&mut book)?;
+    let new_image = write_book(&image, &book)?;
+    tools::replace_file(path, &new_image)?;
+    println!("Done");
+    Ok(())
+}
+
+/// Prints entries of the card lorebook
+pub fn list_entries_file(path: &Path) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let Some(book) = read_book(&image)? else {
+        println!("{} has no lorebook", path.display());
+        return Ok(());
+    };
+    println!(
+        "{:>3}  {:>5}  {:>5}  {:<3}  KEYS / CONTENT",
+        "#", "ID", "ORDER", "ON"
+    );
+    for (index, entry) in book.entries.iter().enumerate() {
+        let keys = match entry.constant {
+            Some(true) => "(constant)".to_string(),
+            _ => entry.keys.join(", "),
+        };
+        let title = entry.comment.as_ref().or(entry.name.as_ref());
+        let title = title.map(|x| format!(" - {}", x)).unwrap_or_default();
+        println!(
+            "{:>3}  {:>5}  {:>5}  {:<3}  {}{}",
+            format!("#{}", index),
+            entry.id.map(|x| x.to_string()).unwrap_or_default(),
+            entry.insertion_order.map(|x| x.to_string()).unwrap_or_default(),
+            if entry.enabled { "yes" } else { "no" },
+            keys,
+            title
+        );
+        println!("{:>26}{}", "", preview(&entry.content));
+    }
+    println!("{} entries", book.entries.len());
+    Ok(())
+}
+
+/// Shortens content to one line for the list of entries
+fn preview(content: &str) -> String {
+    let line = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= PREVIEW_LENGTH {
+        return line;
+    }
+    let short: String = line.chars().take(PREVIEW_LENGTH).collect();
+    format!("{}...", short)
+}
+
+/// Adds entry to the card lorebook. Edits the image in place
+pub fn add_entry_file(path: &Path, fields: &EntryFields) -> Result<()> {
+    edit_book_file(path, true, |book| {
+        let index = add_entry(book, fields)?;
+        println!(
+            "Added entry #{} (id {})",
+            index,
+            book.entries[index].id.unwrap_or_default()
+        );
+        Ok(())
+    })
+}
+
+/// Removes entries from the card lorebook. Edits the image in place
+pub fn remove_entries_file(path: &Path, selectors: &[String]) -> Result<()> {
+    edit_book_file(path, false, |book| {
+        let mut indices = selectors
+            .iter()
+            .map(|x| find_entry(book, x))
+            .collect::<Result<Vec<_>>>()?;
+        indices.sort_unstable();
+        indices.dedup();
+        for index in indices.into_iter().rev() {
+            let entry = book.entries.remove(index);
+            println!("Removed entry #{}: {}", index, entry.keys.join(", "));
+        }
+        Ok(())
+    })
+}
+
+/// Changes fields of an entry in the card lorebook. Edits the image in place
+pub fn edit_entry_file(
+    path: &Path,
+    selector: &str,
+    fields: &EntryFields,
+) -> Result<()> {
+    edit_book_file(path, false, |book| {
+        let index = find_entry(book, selector)?;
+        fields.apply(&mut book.entries[index])?;
+        println!("Changed entry #{}", index);
+        Ok(())
+    })
+}
+
+/// Enables or disables entries in the card lorebook. Edits the image in place
+pub fn set_entries_enabled_file(
+    path: &Path,
+    selectors: &[String],
+    enabled: bool,
+) -> Result<()> {
+    edit_book_file(path, false, |book| {
+        for selector in selectors {
+            let index = find_entry(book, selector)?;
+            book.entries[index].enabled = enabled;
+            let state = if enabled { "Enabled" } else { "Disabled" };
+            println!("{} entry #{}", state, index);
+        }
+        Ok(())
+    })
+}
+
+/// Moves entry to a new position in the card lorebook. Edits the image in
+/// place
+pub fn reorder_entry_file(
+    path: &Path,
+    selector: &str,
+    new_index: usize,
+) -> Result<()> {
+    edit_book_file(path, false, |book| {
+        let index = find_entry(book, selector)?;
+        move_entry(book, index, new_index)?;
+        println!("Moved entry #{} to #{}", index, new_index);
+        Ok(())
+    })
+}
+
+/// Renumbers entry ids of the card lorebook. Edits the image in place
+pub fn renumber_entries_file(
+    path: &Path,
+    start: u32,
+    order_step: Option<u32>,
+) -> Result<()> {
+    edit_book_file(path, false, |book| {
+        renumber_entries(book, start, order_step);
+        println!("Renumbered {} entries", book.entries.len());
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tavern_card_v3::TavernCardV3;
+    use anyhow::Result;
+
+    fn entry(id: u32, keys: &[&str]) -> CharacterBookEntry {
+        CharacterBookEntry {
+            id: Some(id),
+            insertion_order: Some(id * 10),
+            ..test_entry(keys, &format!("Entry {}", id))
+        }
+    }
+
+    #[test]
+    fn test_editing_entries() -> Result<()> {
+        let mut book = CharacterBook {
+            entries: vec![entry(5, &["Dragon"]), entry(7, &["dragon", "cave"])],
+            ..Default::default()
+        };
+        assert_eq!(find_entries(&book, "#1"), vec![1]);
+        assert_eq!(find_entries(&book, "7"), vec![1]);
+        assert_eq!(find_entries(&book, "DRAGON"), vec![0, 1]);
+        assert!(find_entry(&book, "dragon").is_err());
+        assert!(find_entry(&book, "3").is_err());
+        assert_eq!(find_entry(&book, "cave")?, 1);
+
+        let fields = EntryFields {
+            keys: Some(vec!["castle".into(), " ".into()]),
+            content: Some("A castle".into()),
+            secondary_keys: Some(vec!["gate".into()]),
+            ..Default::default()
+        };
+        let index = add_entry(&mut book, &fields)?;
+        let added = &book.entries[index];
+        assert_eq!(added.keys, vec!["castle"]);
+        assert_eq!(added.id, Some(8));
+        assert_eq!(added.insertion_order, Some(71));
+        assert_eq!(added.selective, Some(true));
+        assert!(add_entry(&mut book, &EntryFields::default()).is_err());
+
+        let fields =
+            EntryFields { comment: Some("Big".into()), ..Default::default() };
+        fields.apply(&mut book.entries[0])?;
+        assert_eq!(book.entries[0].comment.as_deref(), Some("Big"));
+        assert_eq!(book.entries[0].content, "Entry 5");
+
+        move_entry(&mut book, 2, 0)?;
+        assert!(move_entry(&mut book, 0, 3).is_err());
+        renumber_entries(&mut book, 0, Some(10));
+        let ids: Vec<_> = book.entries.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(book.entries[0].keys, vec!["castle"]);
+        assert_eq!(book.entries[2].insertion_order, Some(30));
+        Ok(())
+    }
+
+    #[test]
+    fn test_card_without_lorebook() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("tavern_card_tools_no_book_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("card.png");
+        let image = TavernCardV2::new().into_png_image()?;
+        tools::write_image_to_file(&image, &path)?;
+
+        let error = renumber_entries_file(&path, 0, None).unwrap_err();
+        assert!(error.to_string().contains("has no lorebook"));
+        assert_eq!(tools::read_image_from_file(&path)?, image);
+
+        let fields = EntryFields {
+            keys: Some(vec!["dragon".into()]),
+            content: Some("A dragon".into()),
+            ..Default::default()
+        };
+        add_entry_file(&path, &fields)?;
+        let book = read_book(&tools::read_image_from_file(&path)?)?;
+        assert_eq!(book.unwrap().entries[0].keys, vec!["dragon"]);
+        let files: Vec<_> = std::fs::read_dir(&dir)?.collect();
+        assert_eq!(files.len(), 1, "Temporary file left in {:?}", files);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_book_keeps_card() -> Result<()> {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test name".to_string());
+        card.data.unknown_fields.insert("custom".into(), 42.into());
+        let (card_v3, _) = TavernCardV3::upgrade(card.clone());
+        card.image_data = Some(card_v3.into_png_image()?);
+        let image = card.into_png_image_with_compression(true)?;
+        assert_eq!(read_book(&image)?, None);
+
+        let book = CharacterBook {
+            entries: vec![entry(1, &["dragon"])],
+            ..Default::default()
+        };
+        let new_image = write_book(&image, &book)?;
+        assert_eq!(read_book(&new_image)?, Some(book.clone()));
+
+        let new_v2 = tools::read_text_chunk(&new_image, TEXT_KEY_PNG)?;
+        let new_v2: TavernCardV2 = serde_json::from_slice(
+            &tools::decode_card_payload(&new_v2.unwrap())?,
+        )?;
+        assert_eq!(new_v2.data.character_book, Some(book));
+        assert_eq!(new_v2.data.unknown_fields["custom"], 42);
+        let new_v3 = TavernCardV3::from_png_image(&new_image)?;
+        assert_eq!(new_v3.data.name.as_deref(), Some("Test name"));
+
+        // Other chunks are unchanged, card chunks keep their type.
+        let old_chunks = png_chunks::read_chunks(&image)?;
+        let new_chunks = png_chunks::read_chunks(&new_image)?;
+        assert_eq!(old_chunks.len(), new_chunks.len());
+        for (old, new) in old_chunks.iter().zip(&new_chunks) {
+            assert_eq!(old.chunk_type, new.chunk_type);
+            if !old.is_text() {
+                assert_eq!(old.data, new.data);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! Checks of lorebooks for common problems.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::lorebook_simulate::parse_regex_key;
+use crate::tavern_card_v2::*;
+use crate::world_info;
+
+/// Common English words, to find keys that trigger inside them.
+#[rustfmt::skip]
+const COMMON_WORDS: [&str; 120] = [
+    "about", "after", "again", "against", "almost", "always", "another",
+    "answer", "around", "because", "before", "behind", "believe", "better",
+    "between", "body", "bring", "brother", "business", "called", "cannot",
+    "change", "children", "close", "come", "could", "course", "different",
+    "doing", "during", "early", "enough", "even", "every", "everything", "eyes",
+    "face", "family", "father", "feel", "find", "first", "friend", "from",
+    "going", "good", "great", "hand", "happen", "have", "head", "heart", "help",
+    "here", "herself", "himself", "home", "hours", "house", "however",
+    "important", "inside", "into", "just", "know", "large", "later", "leave",
+    "life", "light", "little", "long", "look", "make", "many", "maybe", "means",
+    "minute", "moment", "money", "morning", "mother", "much", "must", "myself",
+    "never", "night", "nothing", "other", "over", "part", "party", "people",
+    "person", "place", "please", "point", "power", "pretty", "really", "right",
+    "room", "said", "school", "should", "since", "something", "start", "still",
+    "story", "that", "their", "there", "these", "thing", "think", "those",
+    "through", "time", "together",
+];
+
+#[derive(
+    serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// One problem found in the lorebook
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct LintWarning {
+    pub severity: Severity,
+    /// Short name of the check, like `empty-keys`
+    pub code: &'static str,
+    /// Index of the entry in the lorebook, if the problem is in one entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub message: String,
+}
+
+impl LintWarning {
+    fn new(
+        severity: Severity,
+        code: &'static str,
+        entry: Option<(usize, &CharacterBookEntry)>,
+        message: String,
+    ) -> Self {
+        LintWarning {
+            severity,
+            code,
+            entry: entry.map(|x| x.0),
+            id: entry.and_then(|x| x.1.id),
+            message,
+        }
+    }
+}
+
+/// Checks lorebook for problems. Warnings are sorted by entry.
+pub fn lint_book(book: &CharacterBook) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let mut key_users: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+
+    for (index, entry) in book.entries.iter().enumerate() {
+        let at = Some((index, entry));
+        let mut warn = |severity, code, message: String| {
+            warnings.push(LintWarning::new(severity, code, at, message));
+        };
+        if !entry.enabled {
+            warn(Severity::Info, "disabled", "Entry is disabled".to_string());
+        }
+        if entry.content.trim().is_empty() {
+            warn(
+                Severity::Warning,
+                "empty-content",
+                "Entry has no content".into(),
+            );
+        }
+        if entry.insertion_order.is_none() {
+            warn(
+                Severity::Warning,
+                "no-insertion-order",
+                "Entry has no insertion_order".to_string(),
+            );
+        }
+        let keys: Vec<&String> =
+            entry.keys.iter().filter(|x| !x.trim().is_empty()).collect();
+        if keys.is_empty() && entry.constant != Some(true) {
+            warn(
+                Severity::Error,
+                "empty-keys",
+                "Entry has no keys and is not constant, it never activates"
+                    .to_string(),
+            );
+        }
+        if keys.len() < entry.keys.len() {
+            warn(Severity::Warning, "blank-key", "Entry has blank keys".into());
+        }
+
+        let case_sensitive = entry.case_sensitive == Some(true);
+        let mut seen: Vec<String> = Vec::new();
+        let secondary = entry.secondary_keys.iter().flatten();
+        for key in keys.iter().copied().chain(secondary) {
+            match parse_regex_key(key) {
+                Some(Err(e)) => {
+                    warn(Severity::Error, "bad-regex", e.to_string());
+                    continue;
+                }
+                Some(Ok(_)) => continue,
+                None => (),
+            }
+            let normalized = match case_sensitive {
+                true => key.trim().to_string(),
+                false => key.trim().to_lowercase(),
+            };
+            if seen.contains(&normalized) {
+                warn(
+                    Severity::Warning,
+                    "duplicate-key",
+                    format!("Key '{}' is listed twice", key),
+                );
+            }
+            seen.push(normalized);
+        }
+        for key in keys {
+            if parse_regex_key(key).is_some() {
+                continue;
+            }
+            let users = key_users.entry(key.trim().to_lowercase()).or_default();
+            if !users.contains(&index) {
+                users.push(index);
+            }
+            let whole_words = entry
+                .extensions
+                .get("match_whole_words")
+                .and_then(|x| x.as_bool())
+                .unwrap_or(false);
+            if !whole_words {
+                let lower = key.trim().to_lowercase();
+                let inside = COMMON_WORDS
+                    .iter()
+                    .find(|x| x.contains(lower.as_str()) && **x != lower);
+                if let Some(word) = inside {
+                    warn(
+                        Severity::Warning,
+                        "common-substring",
+                        format!(
+                            "Key '{}' also matches inside common words, \
+                             like '{}'",
+                            key, word
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    // Keys shared by entries, and keys that contain keys of other entries
+    for (key, users) in &key_users {
+        if users.len() > 1 {
+            let (first, others) = users.split_first().unwrap();
+            let entry = Some((*first, &book.entries[*first]));
+            warnings.push(LintWarning::new(
+                Severity::Warning,
+                "duplicate-key",
+                entry,
+                format!("Key '{}' is also used by {}", key, entry_list(others)),
+            ));
+        }
+        for (other_key, other_users) in &key_users {
+            if other_key == key || !other_key.contains(key.as_str()) {
+                continue;
+            }
+            let others: Vec<usize> = other_users
+                .iter()
+                .copied()
+                .filter(|x| !users.contains(x))
+                .collect();
+            if others.is_empty() {
+                continue;
+            }
+            let entry = Some((users[0], &book.entries[users[0]]));
+            warnings.push(LintWarning::new(
+                Severity::Info,
+                "overlapping-key",
+                entry,
+                format!(
+                    "Key '{}' also activates whenever '{}' of {} does",
+                    key,
+                    other_key,
+                    entry_list(&others)
+                ),
+            ));
+        }
+    }
+
+    let mut ids: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    for (index, entry) in book.entries.iter().enumerate() {
+        if let Some(id) = entry.id {
+            ids.entry(id).or_default().push(index);
+        }
+    }
+    for (id, users) in ids.iter().filter(|x| x.1.len() > 1) {
+        let entry = Some((users[1], &book.entries[users[1]]));
+        warnings.push(LintWarning::new(
+            Severity::Error,
+            "duplicate-id",
+            entry,
+            format!("Id {} is also used by {}", id, entry_list(&users[..1])),
+        ));
+    }
+    warnings.extend(order_gaps(book));
+
+    warnings.sort_by_key(|x| (x.entry.is_some(), x.entry));
+    warnings
+}
+
+/// Finds gaps in insertion order, bigger than its usual step.
+fn order_gaps(book: &CharacterBook) -> Vec<LintWarning> {
+    let mut orders: Vec<u32> =
+        book.entries.iter().filter_map(|x| x.insertion_order).collect();
+    orders.sort();
+    orders.dedup();
+    let steps: Vec<u32> = orders.windows(2).map(|x| x[1] - x[0]).collect();
+    let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+    for step in &steps {
+        *counts.entry(*step).or_default() += 1;
+    }
+    // The most common step, the smallest one if there is a tie.
+    let Some(usual) = counts
+        .iter()
+        .max_by_key(|x| (x.1, std::cmp::Reverse(x.0)))
+        .map(|x| *x.0)
+    else {
+        return Vec::new();
+    };
+    orders
+        .windows(2)
+        .filter(|x| x[1] - x[0] > usual)
+        .map(|x| {
+            LintWarning::new(
+                Severity::Info,
+                "order-gap",
+                None,
+                format!(
+                    "insertion_order jumps from {} to {} (usual step is {})",
+                    x[0], x[1], usual
+                ),
+            )
+        })
+        .collect()
+}
+
+fn entry_list(indexes: &[usize]) -> String {
+    let list: Vec<String> = indexes.iter().map(|x| format!("#{}", x)).collect();
+    format!(
+        "entr{} {}",
+        if list.len() > 1 { "ies" } else { "y" },
+        list.join(", ")
+    )
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}]", self.severity, self.code)?;
+        match (self.entry, self.id) {
+            (Some(entry), Some(id)) => {
+                write!(f, " entry #{} (id {})", entry, id)?
+            }
+            (Some(entry), None) => write!(f, " entry #{}", entry)?,
+            _ => write!(f, " lorebook")?,
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Prints problems of the lorebook of a card or a World Info file
+///
+/// With `json` set, prints them as JSON array instead.
+pub fn lint_lorebook_file(path: &Path, json: bool) -> Result<()> {
+    let book = world_info::read_lorebook_file(path)?;
+
+    let warnings = lint_book(&book);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&warnings)?);
+        return Ok(());
+    }
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+    let count =
+        |severity| warnings.iter().filter(|x| x.severity == severity).count();
+    println!(
+        "{} entries checked: {} errors, {} warnings, {} notes",
+        book.entries.len(),
+        count(Severity::Error),
+        count(Severity::Warning),
+        count(Severity::Info)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(keys: &[&str], order: u32) -> CharacterBookEntry {
+        CharacterBookEntry {
+            insertion_order: Some(order),
+            ..test_entry(keys, "Content")
+        }
+    }
+
+    fn codes(warnings: &[LintWarning]) -> Vec<(&str, Option<usize>)> {
+        warnings.iter().map(|x| (x.code, x.entry)).collect()
+    }
+
+    #[test]
+    fn test_lint_book() {
+        let mut book = CharacterBook {
+            entries: vec![
+                entry(&["Dragon", "dragon"], 1),
+                entry(&[], 2),
+                entry(&["/drag(on/i", "dragon"], 3),
+                entry(&["art", "dragonfly"], 10),
+            ],
+            ..Default::default()
+        };
+        book.entries[1].enabled = false;
+        book.entries[2].id = Some(7);
+        book.entries[3].id = Some(7);
+
+        let warnings = lint_book(&book);
+        let codes = codes(&warnings);
+        assert_eq!(
+            codes,
+            vec![
+                ("order-gap", None),
+                ("duplicate-key", Some(0)),
+                ("duplicate-key", Some(0)),
+                ("overlapping-key", Some(0)),
+                ("disabled", Some(1)),
+                ("empty-keys", Some(1)),
+                ("bad-regex", Some(2)),
+                ("common-substring", Some(3)),
+                ("duplicate-id", Some(3)),
+            ]
+        );
+        assert_eq!(
+            warnings[2].message,
+            "Key 'dragon' is also used by entry #2"
+        );
+    }
+
+    #[test]
+    fn test_clean_book() {
+        let book = CharacterBook {
+            entries: vec![entry(&["castle"], 10), entry(&["forest"], 20)],
+            ..Default::default()
+        };
+        assert!(lint_book(&book).is_empty());
+    }
+}
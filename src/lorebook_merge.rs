@@ -0,0 +1,247 @@
+//! Merging lorebook entries from another card or World Info file.
+
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::lorebook_editor;
+use crate::tavern_card_v2::*;
+use crate::world_info;
+
+/// What to do with a source entry that has the same keys as an entry
+/// already in the destination lorebook
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ConflictStrategy {
+    /// Skip the source entry
+    #[default]
+    Keep,
+    /// Put the source entry in place of the destination one
+    Replace,
+    /// Add the source entry as well
+    Append,
+    /// Add the source entry, with the source name in its comment
+    Rename,
+}
+
+/// Lorebook settings that can be set while merging
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct BookSettings {
+    /// Name of the merged lorebook
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// How many recent messages are scanned for keys
+    #[arg(long)]
+    pub scan_depth: Option<u32>,
+
+    /// Maximum tokens of lorebook entries in the prompt
+    #[arg(long)]
+    pub token_budget: Option<u32>,
+
+    /// Let activated entries trigger other entries
+    #[arg(long)]
+    pub recursive_scanning: Option<bool>,
+}
+
+impl BookSettings {
+    fn apply(&self, book: &mut CharacterBook) {
+        if self.name.is_some() {
+            book.name.clone_from(&self.name);
+        }
+        if self.scan_depth.is_some() {
+            book.scan_depth = self.scan_depth;
+        }
+        if self.token_budget.is_some() {
+            book.token_budget = self.token_budget;
+        }
+        if self.recursive_scanning.is_some() {
+            book.recursive_scanning = self.recursive_scanning;
+        }
+    }
+}
+
+/// What happened to each source entry during the merge
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeReport {
+    pub added: Vec<String>,
+    pub replaced: Vec<String>,
+    pub kept: Vec<String>,
+    pub renamed: Vec<String>,
+}
+
+/// Copies entries of `source` into `dest`
+///
+/// Entries conflict when they have the same keys, ignoring case and order;
+/// entries without keys conflict when they have the same comment. Copied
+/// entries get new ids after the ones in `dest`, replaced entries keep the
+/// id of the entry they replace. Lorebook settings of `dest` are not
+/// changed. `label` is added to comments of renamed entries.
+pub fn merge_books(
+    dest: &mut CharacterBook,
+    source: &CharacterBook,
+    strategy: ConflictStrategy,
+    label: &str,
+) -> MergeReport {
+    let mut report = MergeReport::default();
+    let mut next_id =
+        dest.entries.iter().filter_map(|x| x.id).max().map_or(0, |x| x + 1);
+    for entry in &source.entries {
+        let mut entry = entry.clone();
+        let title = entry_title(&entry);
+        let conflict = dest.entries.iter().position(|x| same_keys(x, &entry));
+        match (conflict, strategy) {
+            (Some(_), ConflictStrategy::Keep) => {
+                report.kept.push(title);
+                continue;
+            }
+            (Some(index), ConflictStrategy::Replace) => {
+                entry.id = dest.entries[index].id;
+                dest.entries[index] = entry;
+                report.replaced.push(title);
+                continue;
+            }
+            (Some(_), ConflictStrategy::Rename) => {
+                let comment = format!("{} ({})", title, label);
+                entry.comment = Some(comment.clone());
+                report.renamed.push(comment);
+            }
+            _ => report.added.push(title),
+        }
+        entry.id = Some(next_id);
+        next_id += 1;
+        dest.entries.push(entry);
+    }
+    report
+}
+
+fn same_keys(a: &CharacterBookEntry, b: &CharacterBookEntry) -> bool {
+    let normalized = |entry: &CharacterBookEntry| {
+        let mut keys: Vec<String> =
+            entry.keys.iter().map(|x| x.trim().to_lowercase()).collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    };
+    let (keys_a, keys_b) = (normalized(a), normalized(b));
+    if keys_a.is_empty() && keys_b.is_empty() {
+        return a.comment.is_some() && a.comment == b.comment;
+    }
+    keys_a == keys_b
+}
+
+/// Comment of the entry, or its name, or its keys
+fn entry_title(entry: &CharacterBookEntry) -> String {
+    entry
+        .comment
+        .as_ref()
+        .or(entry.name.as_ref())
+        .filter(|x| !x.is_empty())
+        .cloned()
+        .unwrap_or_else(|| entry.keys.join(", "))
+}
+
+impl Display for MergeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let groups = [
+            ("Added", &self.added),
+            ("Replaced", &self.replaced),
+            ("Kept existing", &self.kept),
+            ("Added renamed", &self.renamed),
+        ];
+        for (action, titles) in groups {
+            if titles.is_empty() {
+                continue;
+            }
+            writeln!(f, "{} {} entries:", action, titles.len())?;
+            for title in titles {
+                writeln!(f, "    {}", title)?;
+            }
+        }
+        if groups.iter().all(|(_, x)| x.is_empty()) {
+            writeln!(f, "Source lorebook has no entries")?;
+        }
+        Ok(())
+    }
+}
+
+/// Merges lorebook from a card or World Info file into the card lorebook
+///
+/// Edits the image in place. If the card had no lorebook, settings of the
+/// source lorebook are used.
+pub fn merge_lorebook_file(
+    path: &Path,
+    source_path: &Path,
+    strategy: ConflictStrategy,
+    settings: &BookSettings,
+) -> Result<()> {
+    let source = world_info::read_lorebook_file(source_path)?;
+    let label = source.name.clone().unwrap_or_else(|| {
+        let stem = source_path.file_stem().unwrap_or_default();
+        stem.to_string_lossy().to_string()
+    });
+    lorebook_editor::edit_book_file(path, true, |book| {
+        if *book == CharacterBook::default() {
+            *book = CharacterBook { entries: Vec::new(), ..source.clone() };
+        }
+        let report = merge_books(book, &source, strategy, &label);
+        settings.apply(book);
+        print!("{}", report);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(entries: &[(u32, &[&str])]) -> CharacterBook {
+        let entries = entries
+            .iter()
+            .map(|(id, keys)| CharacterBookEntry {
+                id: Some(*id),
+                ..test_entry(keys, &format!("Entry {}", id))
+            })
+            .collect();
+        CharacterBook { entries, scan_depth: Some(3), ..Default::default() }
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let dest = book(&[(4, &["Dragon", "wyrm"]), (9, &["cave"])]);
+        let mut source = book(&[(0, &["wyrm", "dragon"]), (1, &["castle"])]);
+        source.scan_depth = Some(10);
+
+        let mut merged = dest.clone();
+        let report =
+            merge_books(&mut merged, &source, ConflictStrategy::Keep, "S");
+        assert_eq!(report.kept, vec!["wyrm, dragon"]);
+        assert_eq!(report.added, vec!["castle"]);
+        assert_eq!(merged.entries.len(), 3);
+        assert_eq!(merged.entries[0].content, "Entry 4");
+        assert_eq!(merged.entries[2].id, Some(10));
+        assert_eq!(merged.scan_depth, Some(3));
+
+        let mut merged = dest.clone();
+        merge_books(&mut merged, &source, ConflictStrategy::Replace, "S");
+        assert_eq!(merged.entries.len(), 3);
+        assert_eq!(merged.entries[0].content, "Entry 0");
+        assert_eq!(merged.entries[0].id, Some(4));
+
+        let mut merged = dest.clone();
+        merge_books(&mut merged, &source, ConflictStrategy::Append, "S");
+        assert_eq!(merged.entries.len(), 4);
+        assert_eq!(merged.entries[2].comment, None);
+
+        let mut merged = dest.clone();
+        let report =
+            merge_books(&mut merged, &source, ConflictStrategy::Rename, "S");
+        assert_eq!(report.renamed, vec!["wyrm, dragon (S)"]);
+        let ids: Vec<_> = merged.entries.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![Some(4), Some(9), Some(10), Some(11)]);
+        assert_eq!(
+            merged.entries[2].comment.as_deref(),
+            Some("wyrm, dragon (S)")
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Simulation of lorebook activation on a chat transcript.
+//!
+//! Follows the rules of V2 spec, with the SillyTavern extensions that
+//! World Info import keeps in entry `extensions` (`selectiveLogic`,
+//! `exclude_recursion`, `prevent_recursion`, `scan_depth`,
+//! `match_whole_words`, `position`, `depth`).
+
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use regex::{Regex, RegexBuilder};
+
+use crate::tavern_card_v2::*;
+use crate::tools;
+
+/// Number of messages scanned if the lorebook does not say. Same as in
+/// SillyTavern.
+pub const DEFAULT_SCAN_DEPTH: usize = 2;
+
+/// Why an entry was activated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    Constant,
+    Key {
+        key: String,
+        secondary: Option<String>,
+    },
+    /// Key was found in the content of another activated entry
+    Recursion {
+        key: String,
+        source: usize,
+    },
+}
+
+/// Activated lorebook entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activation {
+    /// Index of the entry in the lorebook
+    pub index: usize,
+    pub trigger: Trigger,
+    /// Estimated size of the entry content
+    pub tokens: usize,
+    /// Entry did not fit into the token budget
+    pub dropped: bool,
+}
+
+/// Finds entries of the lorebook that activate on the chat messages
+///
+/// Messages go from the oldest to the newest. Returned activations are
+/// sorted by insertion order.
+pub fn simulate(book: &CharacterBook, messages: &[String]) -> Vec<Activation> {
+    let book_depth = book.scan_depth.map_or(DEFAULT_SCAN_DEPTH, |x| x as usize);
+    let scan_text = |depth: usize| -> String {
+        let start = messages.len().saturating_sub(depth);
+        messages[start..].join("\n")
+    };
+
+    let mut activations: Vec<Activation> = Vec::new();
+    let is_active = |activations: &[Activation], index: usize| {
+        activations.iter().any(|x| x.index == index)
+    };
+    for (index, entry) in book.entries.iter().enumerate() {
+        if !entry.enabled {
+            continue;
+        }
+        let trigger = if entry.constant == Some(true) {
+            Some(Trigger::Constant)
+        } else {
+            let depth = extension_number(entry, "scan_depth")
+                .map_or(book_depth, |x| x as usize);
+            match_entry(entry, &scan_text(depth))
+                .map(|(key, secondary)| Trigger::Key { key, secondary })
+        };
+        if let Some(trigger) = trigger {
+            activations.push(activation(book, index, trigger));
+        }
+    }
+
+    // Contents of activated entries may activate more entries.
+    let mut scanned = 0;
+    while book.recursive_scanning == Some(true) && scanned < activations.len() {
+        let source = activations[scanned].index;
+        scanned += 1;
+        if extension_flag(&book.entries[source], "prevent_recursion") {
+            continue;
+        }
+        let text = &book.entries[source].content;
+        for (index, entry) in book.entries.iter().enumerate() {
+            if !entry.enabled
+                || is_active(&activations, index)
+                || extension_flag(entry, "exclude_recursion")
+            {
+                continue;
+            }
+            if let Some((key, _)) = match_entry(entry, text) {
+                let trigger = Trigger::Recursion { key, source };
+                activations.push(activation(book, index, trigger));
+            }
+        }
+    }
+
+    // When over budget, entries with lower priority are dropped first.
+    if let Some(budget) = book.token_budget {
+        let mut by_priority: Vec<&mut Activation> =
+            activations.iter_mut().collect();
+        by_priority.sort_by_key(|x| {
+            let entry = &book.entries[x.index];
+            std::cmp::Reverse((entry.priority, entry.insertion_order))
+        });
+        let mut used = 0;
+        for activation in by_priority {
+            if used + activation.tokens > budget as usize {
+                activation.dropped = true;
+            } else {
+                used += activation.tokens;
+            }
+        }
+    }
+    activations
+        .sort_by_key(|x| (book.entries[x.index].insertion_order, x.index));
+    activations
+}
+
+/// Describes where the entry content is inserted into the prompt.
+pub fn insertion_place(entry: &CharacterBookEntry) -> String {
+    let depth = extension_number(entry, "depth").unwrap_or(4);
+    match extension_number(entry, "position") {
+        Some(0) => "before character definitions".to_string(),
+        Some(1) => "after character definitions".to_string(),
+        Some(2) => "before author's note".to_string(),
+        Some(3) => "after author's note".to_string(),
+        Some(4) => format!("in chat at depth {}", depth),
+        Some(5) => "before example messages".to_string(),
+        Some(6) => "after example messages".to_string(),
+        // Like in World Info export, entries without position go after
+        _ => match entry.position.as_deref() {
+            Some("before_char") => "before character definitions".to_string(),
+            _ => "after character definitions".to_string(),
+        },
+    }
+}
+
+/// Checks entry keys against the text
+///
+/// Returns the primary key that matched and, for selective entries, the
+/// secondary key.
+pub fn match_entry(
+    entry: &CharacterBookEntry,
+    text: &str,
+) -> Option<(String, Option<String>)> {
+    let key = entry.keys.iter().find(|x| key_matches(entry, x, text))?;
+    let secondary_keys = entry.secondary_keys.as_deref().unwrap_or_default();
+    if entry.selective != Some(true) || secondary_keys.is_empty() {
+        return Some((key.clone(), None));
+    }
+    let matched: Vec<&String> =
+        secondary_keys.iter().filter(|x| key_matches(entry, x, text)).collect();
+    // Logic numbers are from SillyTavern: AND ANY, NOT ALL, NOT ANY, AND ALL
+    let passes = match extension_number(entry, "selectiveLogic").unwrap_or(0) {
+        1 => matched.len() < secondary_keys.len(),
+        2 => matched.is_empty(),
+        3 => matched.len() == secondary_keys.len(),
+        _ => !matched.is_empty(),
+    };
+    passes.then(|| (key.clone(), matched.first().map(|x| x.to_string())))
+}
+
+/// Parses key written as `/regex/flags`
+///
+/// Returns None if the key is not a regex, and error if it is a broken one.
+pub fn parse_regex_key(key: &str) -> Option<Result<Regex>> {
+    let body = key.strip_prefix('/')?;
+    let end = body.rfind('/')?;
+    let (pattern, flags) = (&body[..end], &body[end + 1..]);
+    if pattern.is_empty() || !flags.chars().all(|c| "gimsuy".contains(c)) {
+        return None;
+    }
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .dot_matches_new_line(flags.contains('s'))
+        .build();
+    Some(regex.map_err(|e| anyhow::anyhow!("Bad regex key {}: {}", key, e)))
+}
+
+fn key_matches(entry: &CharacterBookEntry, key: &str, text: &str) -> bool {
+    if key.trim().is_empty() {
+        return false;
+    }
+    if let Some(regex) = parse_regex_key(key) {
+        return regex.is_ok_and(|x| x.is_match(text));
+    }
+    let case_sensitive = entry.case_sensitive.unwrap_or(false);
+    if extension_flag(entry, "match_whole_words") {
+        let pattern = format!(r"\b{}\b", regex::escape(key));
+        return RegexBuilder::new(&pattern)
+            .case_insensitive(!case_sensitive)
+            .build()
+            .is_ok_and(|x| x.is_match(text));
+    }
+    match case_sensitive {
+        true => text.contains(key),
+        false => text.to_lowercase().contains(&key.to_lowercase()),
+    }
+}
+
+fn activation(
+    book: &CharacterBook,
+    index: usize,
+    trigger: Trigger,
+) -> Activation {
+    let tokens = estimate_tokens(&book.entries[index].content);
+    Activation { index, trigger, tokens, dropped: false }
+}
+
+/// Rough token count: about 4 characters per token.
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn extension_number(entry: &CharacterBookEntry, key: &str) -> Option<u64> {
+    entry.extensions.get(key).and_then(|x| x.as_u64())
+}
+
+fn extension_flag(entry: &CharacterBookEntry, key: &str) -> bool {
+    entry.extensions.get(key).and_then(|x| x.as_bool()).unwrap_or(false)
+}
+
+/// Reads chat messages from transcript text
+///
+/// SillyTavern chats (JSONL) are recognized by their first line. In plain
+/// text, messages are separated by empty lines; if there are none, every
+/// line is a message.
+pub fn parse_transcript(text: &str) -> Vec<String> {
+    let first_line = text.lines().find(|x| !x.trim().is_empty());
+    let is_jsonl = first_line.is_some_and(|x| {
+        serde_json::from_str::<serde_json::Value>(x)
+            .is_ok_and(|x| x.is_object())
+    });
+    if is_jsonl {
+        return text
+            .lines()
+            .filter_map(|x| serde_json::from_str::<serde_json::Value>(x).ok())
+            .filter_map(|x| x.get("mes")?.as_str().map(|x| x.to_string()))
+            .collect();
+    }
+    let text = text.replace("\r\n", "\n");
+    let has_paragraphs = text.trim().contains("\n\n");
+    let separator = if has_paragraphs { "\n\n" } else { "\n" };
+    text.split(separator)
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Prints which lorebook entries of a card activate on a chat transcript
+pub fn simulate_lorebook_file(
+    path: &Path,
+    transcript_path: &Path,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let card = TavernCardV2::from_png_image(&image)?;
+    let Some(book) = &card.data.character_book else {
+        bail!("{} has no lorebook", path.display());
+    };
+    let messages = parse_transcript(&std::fs::read_to_string(transcript_path)?);
+    let depth = book.scan_depth.map_or(DEFAULT_SCAN_DEPTH, |x| x as usize);
+    println!(
+        "Scanning {} of {} messages (scan depth {}), recursion {}",
+        depth.min(messages.len()),
+        messages.len(),
+        depth,
+        if book.recursive_scanning == Some(true) { "on" } else { "off" }
+    );
+
+    let activations = simulate(book, &messages);
+    if activations.is_empty() {
+        println!("No entries activated");
+        return Ok(());
+    }
+    println!("Activated entries, in insertion order:");
+    for activation in activations.iter().filter(|x| !x.dropped) {
+        println!("{}", ActivationDisplay { book, activation });
+    }
+    if activations.iter().any(|x| x.dropped) {
+        println!(
+            "Dropped by token budget ({} tokens):",
+            book.token_budget.unwrap_or_default()
+        );
+        for activation in activations.iter().filter(|x| x.dropped) {
+            println!("{}", ActivationDisplay { book, activation });
+        }
+    }
+    let used: usize =
+        activations.iter().filter(|x| !x.dropped).map(|x| x.tokens).sum();
+    println!("Total: about {} tokens", used);
+    Ok(())
+}
+
+struct ActivationDisplay<'a> {
+    book: &'a CharacterBook,
+    activation: &'a Activation,
+}
+
+impl Display for ActivationDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entry = &self.book.entries[self.activation.index];
+        let title = entry
+            .comment
+            .as_ref()
+            .or(entry.name.as_ref())
+            .cloned()
+            .unwrap_or_else(|| entry.keys.join(","));
+        write!(f, "    #{} {}: ", self.activation.index, title)?;
+        match &self.activation.trigger {
+            Trigger::Constant => write!(f, "constant")?,
+            Trigger::Key { key, secondary: None } => {
+                write!(f, "key '{}'", key)?
+            }
+            Trigger::Key { key, secondary: Some(secondary) } => {
+                write!(f, "key '{}' with '{}'", key, secondary)?
+            }
+            Trigger::Recursion { key, source } => {
+                write!(f, "key '{}' in entry #{}", key, source)?
+            }
+        }
+        write!(
+            f,
+            "; {}, order {}, ~{} tokens",
+            insertion_place(entry),
+            entry.insertion_order.unwrap_or_default(),
+            self.activation.tokens
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_keys_and_scan_depth() {
+        let mut book = CharacterBook {
+            entries: vec![
+                test_entry(&["Forest"], "Forest lore"),
+                test_entry(&["castle"], "Castle lore"),
+                test_entry(&["/dra(g|k)on/i"], "Dragon lore"),
+                test_entry(&["river"], "River lore"),
+                test_entry(&[], "Always"),
+            ],
+            ..Default::default()
+        };
+        book.entries[1].case_sensitive = Some(true);
+        book.entries[3].enabled = false;
+        book.entries[4].constant = Some(true);
+        let chat = messages(&[
+            "We went to the forest.",
+            "The Castle and the river are far. A DRAKON!",
+        ]);
+
+        let keys = |activations: Vec<Activation>| -> Vec<usize> {
+            activations.iter().map(|x| x.index).collect()
+        };
+        assert_eq!(keys(simulate(&book, &chat)), vec![0, 2, 4]);
+        book.scan_depth = Some(1);
+        assert_eq!(keys(simulate(&book, &chat)), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_selective_and_recursion() {
+        let mut book = CharacterBook {
+            entries: vec![
+                test_entry(&["queen"], "The queen lives in the tower."),
+                test_entry(&["tower"], "The tower is tall."),
+                test_entry(&["sword"], "A sword."),
+            ],
+            recursive_scanning: Some(true),
+            ..Default::default()
+        };
+        book.entries[2].selective = Some(true);
+        book.entries[2].secondary_keys = Some(vec!["magic".to_string()]);
+        let chat = messages(&["The queen has a sword."]);
+
+        let activations = simulate(&book, &chat);
+        assert_eq!(activations.len(), 2);
+        assert_eq!(
+            activations[1].trigger,
+            Trigger::Recursion { key: "tower".to_string(), source: 0 }
+        );
+
+        let chat = messages(&["The queen has a magic sword."]);
+        book.recursive_scanning = Some(false);
+        let activations = simulate(&book, &chat);
+        let trigger = Trigger::Key {
+            key: "sword".to_string(),
+            secondary: Some("magic".to_string()),
+        };
+        assert_eq!(activations[1].trigger, trigger);
+    }
+
+    #[test]
+    fn test_token_budget() {
+        let mut book = CharacterBook {
+            entries: vec![
+                test_entry(&["a"], &"x".repeat(40)),
+                test_entry(&["a"], &"y".repeat(40)),
+            ],
+            token_budget: Some(15),
+            ..Default::default()
+        };
+        book.entries[0].priority = Some(1);
+        book.entries[1].priority = Some(5);
+        let activations = simulate(&book, &messages(&["a"]));
+        assert!(activations[0].dropped);
+        assert!(!activations[1].dropped);
+    }
+
+    #[test]
+    fn test_insertion_place() {
+        let mut entry = test_entry(&["a"], "text");
+        assert_eq!(insertion_place(&entry), "after character definitions");
+        entry.position = Some("before_char".to_string());
+        assert_eq!(insertion_place(&entry), "before character definitions");
+    }
+
+    #[test]
+    fn test_parse_transcript() {
+        let jsonl = "{\"user_name\":\"User\",\"character_name\":\"Char\"}\n\
+                     {\"name\":\"User\",\"is_user\":true,\"mes\":\"Hi\"}\n\
+                     {\"name\":\"Char\",\"is_user\":false,\"mes\":\"Hello\\nthere\"}";
+        assert_eq!(parse_transcript(jsonl), vec!["Hi", "Hello\nthere"]);
+        assert_eq!(parse_transcript("One\nTwo\n"), vec!["One", "Two"]);
+        assert_eq!(
+            parse_transcript("One\nline\n\nTwo"),
+            vec!["One\nline", "Two"]
+        );
+    }
+}
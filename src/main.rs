@@ -1,14 +1,37 @@
 #![allow(dead_code)]
 
 use anyhow::Result;
+use baya_download::{BatchOptions, BayaOptions, CreatorOptions};
 use clap::{Parser, ValueHint};
+use lorebook_editor::EntryFields;
+use lorebook_merge::{BookSettings, ConflictStrategy};
+use placeholders::PlaceholderOptions;
 use std::path::{Path, PathBuf};
+use tools::{ExportOptions, WriteOptions};
 
 mod actions;
+mod backyard_extension;
 mod baya_download;
+mod card_validator;
+mod charx;
+mod chunk_editor;
+mod cookie_jar;
 mod deasterisk;
+mod embedded_card;
+mod http_client;
+mod json_card;
+mod lorebook_editor;
+mod lorebook_lint;
+mod lorebook_merge;
+mod lorebook_simulate;
+mod output_naming;
+mod placeholders;
+mod png_chunks;
+mod tavern_card_v1;
 mod tavern_card_v2;
+mod tavern_card_v3;
 mod tools;
+mod world_info;
 //mod example;
 
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -33,9 +56,20 @@ enum Commands {
     #[command(name = "baya_get")]
     #[command(arg_required_else_help = true)]
     BayaGet {
-        /// URL at Backyard AI website to download from
-        #[arg()]
-        url: String,
+        /// URL at Backyard AI website to download from, a saved copy of the
+        /// page (.html), the character JSON, or - to read them from stdin.
+        /// Several of them are downloaded as a batch
+        #[arg(required_unless_present_any = ["batch", "creator"])]
+        sources: Vec<String>,
+
+        #[command(flatten)]
+        options: BayaOptions,
+
+        #[command(flatten)]
+        batch: BatchOptions,
+
+        #[command(flatten)]
+        creator: CreatorOptions,
     },
     /// Remove paired asterisks from text in tavern card. Makes a copy of the image and renames it to de8.<old_name.png>
     #[command(arg_required_else_help = true)]
@@ -44,14 +78,134 @@ enum Commands {
         #[arg(value_hint = ValueHint::FilePath)]
         path: PathBuf,
 
-        /// Overwrite output file if it exists already
+        #[command(flatten)]
+        options: WriteOptions,
+
+        #[command(flatten)]
+        placeholders: PlaceholderOptions,
+    },
+    /// Convert V2 card into V3 card. Makes a copy of the image and renames it to v3.<old_name.png>
+    #[command(arg_required_else_help = true)]
+    Upgrade {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: WriteOptions,
+
+        #[command(flatten)]
+        placeholders: PlaceholderOptions,
+    },
+    /// Convert V3 card into V2 card. Makes a copy of the image and renames it to v2.<old_name.png>
+    #[command(arg_required_else_help = true)]
+    Downgrade {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: WriteOptions,
+    },
+    /// Convert PNG card into CharX file. Saves it as <old_name>.charx
+    #[command(name = "export-charx")]
+    #[command(arg_required_else_help = true)]
+    ExportCharx {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: ExportOptions,
+    },
+    /// Convert CharX file into PNG card. Saves it as <old_name>.png
+    #[command(name = "import-charx")]
+    #[command(arg_required_else_help = true)]
+    ImportCharx {
+        /// Path to card.charx
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: WriteOptions,
+
+        #[command(flatten)]
+        placeholders: PlaceholderOptions,
+    },
+    /// Save the card JSON into <old_name>.json
+    #[command(name = "export-json")]
+    #[command(arg_required_else_help = true)]
+    ExportJson {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Also store the image in JSON, as a data URL
+        #[arg(long)]
+        avatar: bool,
+
+        #[command(flatten)]
+        options: ExportOptions,
+    },
+    /// Build PNG card from JSON file. Saves it as <old_name>.png
+    #[command(name = "import-json")]
+    #[command(arg_required_else_help = true)]
+    ImportJson {
+        /// Path to card.json
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Image for the card. If not given, the avatar from JSON or the
+        /// default image is used
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        image: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: WriteOptions,
+
+        #[command(flatten)]
+        placeholders: PlaceholderOptions,
+    },
+    /// Save the card in Backyard AI format, as <old_name>.backyard.json and
+    /// <old_name>.backyard.png
+    #[command(name = "export-backyard")]
+    #[command(arg_required_else_help = true)]
+    ExportBackyard {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: ExportOptions,
+    },
+    /// Work with the lorebook of the card
+    #[command(arg_required_else_help = true)]
+    Lorebook {
+        #[command(subcommand)]
+        command: LorebookCommands,
+    },
+    /// Inspect and edit PNG chunks of the card image
+    #[command(arg_required_else_help = true)]
+    Chunks {
+        #[command(subcommand)]
+        command: ChunksCommands,
+    },
+    /// Check the card against the V2/V3 spec
+    #[command(arg_required_else_help = true)]
+    Validate {
+        /// Path to image.png or card.json
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Set missing fields to the spec defaults and fix wrong types.
+        /// Edits the file in place
         #[arg(long)]
-        force: bool,
+        fix: bool,
     },
     /// Print the content of the card
     #[command(arg_required_else_help = true)]
     Print {
-        /// Path to image.png
+        /// Path to image.png or card.charx
         #[arg(value_hint = ValueHint::FilePath)]
         path: PathBuf,
     },
@@ -65,6 +219,216 @@ enum Commands {
     },
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum LorebookCommands {
+    /// Save the lorebook as SillyTavern World Info file <old_name>.lorebook.json
+    #[command(arg_required_else_help = true)]
+    Export {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        options: ExportOptions,
+    },
+    /// Replace the lorebook with SillyTavern World Info file. Makes a copy of the image and renames it to lorebook.<old_name.png>
+    #[command(arg_required_else_help = true)]
+    Import {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Path to World Info file
+        #[arg(value_hint = ValueHint::FilePath)]
+        world_info: PathBuf,
+
+        #[command(flatten)]
+        options: WriteOptions,
+
+        #[command(flatten)]
+        placeholders: PlaceholderOptions,
+    },
+    /// Show which lorebook entries activate on a chat transcript
+    #[command(arg_required_else_help = true)]
+    Simulate {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Path to chat: SillyTavern .jsonl file, or text with messages
+        /// separated by empty lines
+        #[arg(value_hint = ValueHint::FilePath)]
+        transcript: PathBuf,
+    },
+    /// Check the lorebook for common problems
+    #[command(arg_required_else_help = true)]
+    Lint {
+        /// Path to image.png or World Info file
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Print the problems as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List lorebook entries
+    #[command(arg_required_else_help = true)]
+    List {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Add lorebook entry. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        fields: EntryFields,
+    },
+    /// Remove lorebook entries. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Entries: #index, id or key
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+    /// Change fields of lorebook entry. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Edit {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Entry: #index, id or key
+        entry: String,
+
+        #[command(flatten)]
+        fields: EntryFields,
+    },
+    /// Enable lorebook entries. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Enable {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Entries: #index, id or key
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+    /// Disable lorebook entries. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Disable {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Entries: #index, id or key
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+    /// Move lorebook entry to another position in the list. Edits the image
+    /// in place
+    #[command(arg_required_else_help = true)]
+    Reorder {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Entry: #index, id or key
+        entry: String,
+
+        /// New position in the list, starting from 0
+        position: usize,
+    },
+    /// Give lorebook entries sequential ids in list order. Edits the image
+    /// in place
+    #[command(arg_required_else_help = true)]
+    Renumber {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Id of the first entry
+        #[arg(long, default_value_t = 0)]
+        start: u32,
+
+        /// Also renumber insertion order with this step
+        #[arg(long)]
+        order_step: Option<u32>,
+    },
+    /// Copy entries from another card or World Info file. Edits the image in
+    /// place
+    #[command(arg_required_else_help = true)]
+    Merge {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Path to card or World Info file to copy entries from
+        #[arg(value_hint = ValueHint::FilePath)]
+        source: PathBuf,
+
+        /// What to do with entries that have the same keys
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: ConflictStrategy,
+
+        #[command(flatten)]
+        settings: BookSettings,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ChunksCommands {
+    /// List all chunks and show which one the card is read from
+    #[command(arg_required_else_help = true)]
+    List {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Print the decoded content of chunks
+    #[command(arg_required_else_help = true)]
+    Dump {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Chunk number or keyword
+        chunk: String,
+    },
+    /// Remove chunks. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Chunk number or keyword
+        chunk: String,
+    },
+    /// Change keyword of text chunks. Edits the image in place
+    #[command(arg_required_else_help = true)]
+    Rename {
+        /// Path to image.png
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+
+        /// Chunk number or keyword
+        chunk: String,
+
+        /// New keyword
+        new_keyword: String,
+    },
+}
+
 fn main() {
     // Prepare debug logging.
     #[cfg(debug_assertions)]
@@ -81,11 +445,11 @@ fn main() {
             .init();
     }
 
-    // Print intro
-    println!("tavern card tools v{}", APP_VERSION);
+    // Print intro. Goes to stderr, to keep machine-readable output clean.
+    eprintln!("tavern card tools v{}", APP_VERSION);
 
     if let Err(err) = parse_args() {
-        println!("Error: {}", err);
+        eprintln!("Error: {}", err);
         std::process::exit(1);
     }
 }
@@ -105,11 +469,122 @@ fn parse_args() -> Result<()> {
     }
 
     match args.command.unwrap() {
-        Commands::BayaGet { url } => {
-            baya_download::download_card_from_baya_url(&url)?
+        Commands::BayaGet { sources, options, batch, creator } => {
+            match (&creator.creator, sources.as_slice(), &batch.batch) {
+                (Some(url), _, _) => baya_download::download_creator_from_baya(
+                    url, &options, &creator, batch.jobs,
+                )?,
+                (None, [source], None) => {
+                    baya_download::download_card_from_baya(source, &options)?
+                }
+                _ => baya_download::download_batch_from_baya(
+                    &sources, &options, &batch,
+                )?,
+            }
+        }
+        Commands::De8 { path, options, placeholders } => {
+            deasterisk::deasterisk_tavern_file(&path, &options, &placeholders)?
+        }
+        Commands::Upgrade { path, options, placeholders } => {
+            actions::upgrade_tavern_file(&path, &options, &placeholders)?
+        }
+        Commands::Downgrade { path, options } => {
+            actions::downgrade_tavern_file(&path, &options)?
+        }
+        Commands::ExportCharx { path, options } => {
+            charx::export_charx_file(&path, &options)?
+        }
+        Commands::ImportCharx { path, options, placeholders } => {
+            charx::import_charx_file(&path, &options, &placeholders)?
+        }
+        Commands::ExportJson { path, avatar, options } => {
+            json_card::export_json_file(&path, avatar, &options)?
+        }
+        Commands::ImportJson { path, image, options, placeholders } => {
+            json_card::import_json_file(
+                &path,
+                image.as_deref(),
+                &options,
+                &placeholders,
+            )?
+        }
+        Commands::ExportBackyard { path, options } => {
+            baya_download::export_backyard_file(&path, &options)?
         }
-        Commands::De8 { path, force } => {
-            deasterisk::deasterisk_tavern_file(&path, force)?
+        Commands::Lorebook { command } => match command {
+            LorebookCommands::Export { path, options } => {
+                world_info::export_lorebook_file(&path, &options)?
+            }
+            LorebookCommands::Import {
+                path,
+                world_info,
+                options,
+                placeholders,
+            } => world_info::import_lorebook_file(
+                &path,
+                &world_info,
+                &options,
+                &placeholders,
+            )?,
+            LorebookCommands::Simulate { path, transcript } => {
+                lorebook_simulate::simulate_lorebook_file(&path, &transcript)?
+            }
+            LorebookCommands::Lint { path, json } => {
+                lorebook_lint::lint_lorebook_file(&path, json)?
+            }
+            LorebookCommands::List { path } => {
+                lorebook_editor::list_entries_file(&path)?
+            }
+            LorebookCommands::Add { path, fields } => {
+                lorebook_editor::add_entry_file(&path, &fields)?
+            }
+            LorebookCommands::Remove { path, entries } => {
+                lorebook_editor::remove_entries_file(&path, &entries)?
+            }
+            LorebookCommands::Edit { path, entry, fields } => {
+                lorebook_editor::edit_entry_file(&path, &entry, &fields)?
+            }
+            LorebookCommands::Enable { path, entries } => {
+                lorebook_editor::set_entries_enabled_file(
+                    &path, &entries, true,
+                )?
+            }
+            LorebookCommands::Disable { path, entries } => {
+                lorebook_editor::set_entries_enabled_file(
+                    &path, &entries, false,
+                )?
+            }
+            LorebookCommands::Reorder { path, entry, position } => {
+                lorebook_editor::reorder_entry_file(&path, &entry, position)?
+            }
+            LorebookCommands::Renumber { path, start, order_step } => {
+                lorebook_editor::renumber_entries_file(
+                    &path, start, order_step,
+                )?
+            }
+            LorebookCommands::Merge { path, source, on_conflict, settings } => {
+                lorebook_merge::merge_lorebook_file(
+                    &path,
+                    &source,
+                    on_conflict,
+                    &settings,
+                )?
+            }
+        },
+        Commands::Chunks { command } => match command {
+            ChunksCommands::List { path } => chunk_editor::list_chunks(&path)?,
+            ChunksCommands::Dump { path, chunk } => {
+                chunk_editor::dump_chunk(&path, &chunk)?
+            }
+            ChunksCommands::Remove { path, chunk } => {
+                chunk_editor::remove_chunks(&path, &chunk)?
+            }
+            ChunksCommands::Rename { path, chunk, new_keyword } => {
+                chunk_editor::rename_chunks(&path, &chunk, &new_keyword)?
+            }
+        },
+        Commands::Validate { path, fix } => {
+            card_validator::validate_card_file(&path, fix)?
         }
         Commands::Print { path } => {
             actions::print_tavern_card_from_path(&path)?
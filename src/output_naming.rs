@@ -0,0 +1,498 @@
+//! Names of output files: templates, file names that are safe on every
+//! system, and what to do when the file exists already.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueHint;
+use serde_json::Value;
+
+use crate::backyard_extension::BACKYARD_EXTENSION_KEY;
+
+/// What to do when the output file exists already
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum IfExists {
+    /// Ask whether to overwrite it
+    #[default]
+    Ask,
+    /// Overwrite it
+    Overwrite,
+    /// Keep it, and don't write the output
+    Skip,
+    /// Add a number to the new file name, like "name (2).png"
+    Number,
+}
+
+/// Options of output file names, shared by commands that write cards
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct OutputOptions {
+    /// Path of the output file
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        conflicts_with_all = ["output_dir", "name_template"]
+    )]
+    pub output: Option<PathBuf>,
+
+    /// Folder to write output files into
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Template of output file names, like "{creator}/{name} ({id})".
+    /// Placeholders are {name}, {id}, {creator} and {stem} (input file
+    /// name); unknown values become "unknown". Extension is added
+    #[arg(long)]
+    pub name_template: Option<String>,
+
+    /// What to do when the output file exists. Defaults to ask, or
+    /// overwrite with --force
+    #[arg(long, value_enum)]
+    pub if_exists: Option<IfExists>,
+}
+
+/// Values of the placeholders in name templates
+#[derive(Debug, Default, Clone)]
+pub struct NameFields {
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub creator: Option<String>,
+    pub stem: Option<String>,
+}
+
+impl NameFields {
+    /// Name, creator and Backyard AI id of a card
+    pub fn for_card(
+        name: Option<&str>,
+        creator: Option<&str>,
+        extensions: Option<&HashMap<String, Value>>,
+    ) -> Self {
+        let id = extensions
+            .and_then(|x| x.get(BACKYARD_EXTENSION_KEY))
+            .and_then(|x| x.get("character_id"))
+            .and_then(Value::as_str);
+        Self {
+            name: name.map(String::from),
+            id: id.map(String::from),
+            creator: creator.map(String::from),
+            stem: None,
+        }
+    }
+
+    /// Name, creator and Backyard AI id from card JSON of any version
+    pub fn from_card_json(json: &Value) -> Self {
+        let data = json.get("data").unwrap_or(json);
+        let text = |key: &str| data.get(key).and_then(Value::as_str);
+        let extensions = data
+            .get("extensions")
+            .and_then(|x| serde_json::from_value(x.clone()).ok());
+        Self::for_card(text("name"), text("creator"), extensions.as_ref())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<&str>> {
+        let value = match key {
+            "name" => &self.name,
+            "id" => &self.id,
+            "creator" => &self.creator,
+            "stem" => &self.stem,
+            _ => bail!(
+                "Unknown placeholder {{{}}} in name template. \
+                Known are {{name}}, {{id}}, {{creator}} and {{stem}}",
+                key
+            ),
+        };
+        Ok(value.as_deref().filter(|x| !x.trim().is_empty()))
+    }
+}
+
+/// Value of a placeholder that the command does not know
+const MISSING_FIELD: &str = "unknown";
+/// Longest file name, in bytes, without the extension
+const MAX_NAME_LENGTH: usize = 200;
+const INVALID_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+    "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+impl OutputOptions {
+    /// Path of the output file, before checking whether it exists
+    ///
+    /// `dir` and `template` are used unless the options set them.
+    /// `extension` is added to the name made by the template.
+    pub fn path(
+        &self,
+        dir: &Path,
+        template: &str,
+        fields: &NameFields,
+        extension: &str,
+    ) -> Result<PathBuf> {
+        if let Some(output) = &self.output {
+            return Ok(output.clone());
+        }
+        let dir = self.output_dir.as_deref().unwrap_or(dir);
+        let template = self.name_template.as_deref().unwrap_or(template);
+        let mut segments = render_template(template, fields)?;
+        let file_name = segments.pop().unwrap();
+        let mut path = dir.to_path_buf();
+        path.extend(segments);
+        path.push(format!("{}.{}", file_name, extension));
+        Ok(path)
+    }
+
+    /// Folder that holds all outputs whose fields differ only in name, id
+    /// and stem
+    pub fn common_dir(
+        &self,
+        dir: &Path,
+        template: &str,
+        fields: &NameFields,
+    ) -> Result<PathBuf> {
+        if let Some(output) = &self.output {
+            let parent = output.parent().unwrap_or(Path::new(""));
+            return Ok(parent.to_path_buf());
+        }
+        let dir = self.output_dir.as_deref().unwrap_or(dir);
+        let template = self.name_template.as_deref().unwrap_or(template);
+        let fixed = template_segments(template)
+            .take_while(|x| {
+                !["{name}", "{id}", "{stem}"].iter().any(|y| x.contains(y))
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut path = dir.to_path_buf();
+        if !fixed.is_empty() {
+            path.extend(render_template(&fixed, fields)?);
+        }
+        Ok(path)
+    }
+
+    /// Finds the output path and applies the policy if the file exists
+    ///
+    /// `policy` is used unless the options set one. Returns None if the
+    /// output is skipped.
+    pub fn claim(
+        &self,
+        dir: &Path,
+        template: &str,
+        fields: &NameFields,
+        extension: &str,
+        policy: IfExists,
+    ) -> Result<Option<PathBuf>> {
+        let paths =
+            self.claim_all(dir, template, fields, &[extension], policy)?;
+        Ok(paths.map(|mut x| x.remove(0)))
+    }
+
+    /// Like `claim`, for outputs of several files with the same name
+    ///
+    /// The files differ only in extension, one for each of `extensions`,
+    /// like `name.json` and `name.png`, or `name.png` and `name.happy.png`.
+    /// The policy decides for all of them at once, so they keep the same
+    /// name.
+    pub fn claim_all(
+        &self,
+        dir: &Path,
+        template: &str,
+        fields: &NameFields,
+        extensions: &[&str],
+        policy: IfExists,
+    ) -> Result<Option<Vec<PathBuf>>> {
+        let path = self.path(dir, template, fields, extensions[0])?;
+        claim_paths(&path, &extensions[1..], self.if_exists.unwrap_or(policy))
+    }
+}
+
+fn template_segments(template: &str) -> impl Iterator<Item = &str> {
+    template.split(['/', '\\']).filter(|x| !x.is_empty())
+}
+
+/// Fills the placeholders and makes each folder and file name safe
+fn render_template(template: &str, fields: &NameFields) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+    for segment in template_segments(template) {
+        let mut rendered = String::new();
+        let mut rest = segment;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                bail!("Name template has unclosed {{ in '{}'", segment);
+            };
+            rendered.push_str(&rest[..start]);
+            let key = &rest[start + 1..start + end];
+            rendered.push_str(fields.get(key)?.unwrap_or(MISSING_FIELD));
+            rest = &rest[start + end + 1..];
+        }
+        rendered.push_str(rest);
+        result.push(sanitize_file_name(&rendered));
+    }
+    if result.is_empty() {
+        bail!("Name template '{}' makes an empty name", template);
+    }
+    Ok(result)
+}
+
+/// Makes the text usable as a file name on Windows, Linux and macOS
+///
+/// Path separators, characters that Windows does not allow and control
+/// characters become `_`, and so do empty names and names of only dots.
+/// Names reserved by Windows, like `CON`, get `_` in front. Long names are
+/// cut.
+pub fn sanitize_file_name(text: &str) -> String {
+    let name: String =
+        text.chars()
+            .map(|x| {
+                if x.is_control() || INVALID_CHARS.contains(&x) {
+                    '_'
+                } else {
+                    x
+                }
+            })
+            .collect();
+    // Windows drops trailing dots and spaces
+    let mut name = name.trim().trim_end_matches('.').to_string();
+    if name.is_empty() {
+        name = "_".to_string();
+    }
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|x| x.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        let mut end = MAX_NAME_LENGTH;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        name = name.trim_end().trim_end_matches('.').to_string();
+    }
+    name
+}
+
+/// Applies the policy if the output file exists
+///
+/// Creates missing folders. With `Number`, the free name is taken at once
+/// by an empty file, so that parallel downloads do not pick the same one.
+/// Returns None if the output is skipped.
+pub fn claim_path(path: &Path, policy: IfExists) -> Result<Option<PathBuf>> {
+    let paths = claim_paths(path, &[], policy)?;
+    Ok(paths.map(|mut x| x.remove(0)))
+}
+
+/// Applies the policy to the files of one output, if any of them exists
+///
+/// The files are `path` and its siblings with the other `extensions`.
+fn claim_paths(
+    path: &Path,
+    extensions: &[&str],
+    policy: IfExists,
+) -> Result<Option<Vec<PathBuf>>> {
+    let paths = sibling_paths(path, extensions);
+    let mut existing = Vec::new();
+    for path in &paths {
+        if let Some(parent) =
+            path.parent().filter(|x| !x.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!("Could not create folder {}", parent.display())
+            })?;
+        }
+        let exists = path.try_exists().with_context(|| {
+            format!("Output path {} is not available", path.display())
+        })?;
+        if exists {
+            existing.push(path.as_path());
+        }
+    }
+    match policy {
+        IfExists::Number => return take_free_paths(path, extensions).map(Some),
+        IfExists::Overwrite => {}
+        _ if existing.is_empty() => {}
+        IfExists::Ask => ask_to_overwrite(&existing)?,
+        IfExists::Skip => {
+            println!("File {} already exists, skipped", existing[0].display());
+            return Ok(None);
+        }
+    }
+    Ok(Some(paths))
+}
+
+/// Returns `path`, followed by its siblings with the other `extensions`
+fn sibling_paths(path: &Path, extensions: &[&str]) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_path_buf()];
+    paths.extend(extensions.iter().map(|x| path.with_extension(x)));
+    paths
+}
+
+/// Asks the user whether to overwrite the files.
+///
+/// The files are left in place, so they survive if writing the new output
+/// fails.
+fn ask_to_overwrite(existing: &[&Path]) -> Result<()> {
+    let names: Vec<String> =
+        existing.iter().map(|x| x.display().to_string()).collect();
+    let message = match existing.len() {
+        1 => format!("File {} already exists", names.join(", ")),
+        _ => format!("Files {} already exist", names.join(", ")),
+    };
+    println!("{}. Overwrite?", message);
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    if input.trim().to_lowercase() != "y" {
+        bail!(message);
+    }
+    Ok(())
+}
+
+/// Finds the first number under which all the files are free, and takes
+/// them by creating empty files
+fn take_free_paths(path: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    for number in 1.. {
+        let candidates = match number {
+            1 => sibling_paths(path, extensions),
+            _ => sibling_paths(&numbered_path(path, number), extensions),
+        };
+        let mut created = Vec::new();
+        for candidate in &candidates {
+            let result = std::fs::File::options()
+                .write(true)
+                .create_new(true)
+                .open(candidate);
+            match result {
+                Ok(_) => created.push(candidate),
+                Err(e) => {
+                    for path in &created {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    if e.kind() == std::io::ErrorKind::AlreadyExists {
+                        break;
+                    }
+                    return Err(e).with_context(|| {
+                        format!("Could not create {}", candidate.display())
+                    });
+                }
+            }
+        }
+        if created.len() == candidates.len() {
+            return Ok(candidates);
+        }
+    }
+    unreachable!()
+}
+
+/// Removes the empty file that claimed the name, when the output failed
+///
+/// Files with content are kept, as they are not placeholders.
+pub fn release_path(path: &Path) {
+    if std::fs::metadata(path).is_ok_and(|x| x.len() == 0) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// `name.png` becomes `name (2).png`
+fn numbered_path(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => {
+            format!("{} ({}).{}", stem, number, extension.to_string_lossy())
+        }
+        None => format!("{} ({})", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(
+            sanitize_file_name("Mira: the/Captain?"),
+            "Mira_ the_Captain_"
+        );
+        assert_eq!(sanitize_file_name(".."), "_");
+        assert_eq!(sanitize_file_name(""), "_");
+        assert_eq!(sanitize_file_name("con"), "_con");
+        assert_eq!(sanitize_file_name("LPT1.backup"), "_LPT1.backup");
+        assert_eq!(sanitize_file_name("Console"), "Console");
+        assert_eq!(sanitize_file_name(" name. "), "name");
+        let long = "ж".repeat(150);
+        assert_eq!(sanitize_file_name(&long).len(), MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn test_templates() -> Result<()> {
+        let fields = NameFields {
+            name: Some("../Mira".to_string()),
+            id: Some("x1".to_string()),
+            creator: Some("rim:writer".to_string()),
+            stem: None,
+        };
+        let options = OutputOptions {
+            name_template: Some("/{creator}/{name} ({id}) {stem}".to_string()),
+            output_dir: Some(PathBuf::from("out")),
+            ..Default::default()
+        };
+        let path = options.path(Path::new("."), "{name}", &fields, "png")?;
+        assert_eq!(path, Path::new("out/rim_writer/.._Mira (x1) unknown.png"));
+        let dir = options.common_dir(Path::new("."), "{name}", &fields)?;
+        assert_eq!(dir, Path::new("out/rim_writer"));
+
+        let options = OutputOptions::default();
+        let path = options.path(Path::new("in"), "de8.{stem}", &fields, "webp");
+        assert_eq!(path?, Path::new("in/de8.unknown.webp"));
+        let error = options.path(Path::new(""), "{nmae}", &fields, "png");
+        assert!(error.unwrap_err().to_string().contains("{nmae}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_claiming_existing_paths() -> Result<()> {
+        let dir = std::env::temp_dir().join("tavern_card_tools_naming_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("sub/card.png");
+        let claimed = claim_path(&path, IfExists::Number)?;
+        assert_eq!(claimed.as_deref(), Some(path.as_path()));
+        let claimed = claim_path(&path, IfExists::Number)?;
+        assert_eq!(claimed.unwrap(), dir.join("sub/card (2).png"));
+        assert_eq!(claim_path(&path, IfExists::Skip)?, None);
+        let claimed = claim_path(&path, IfExists::Overwrite)?;
+        assert_eq!(claimed.as_deref(), Some(path.as_path()));
+
+        // Files of one output get the same number
+        std::fs::write(dir.join("sub/card (3).json"), "{}")?;
+        let options = OutputOptions::default();
+        let claimed = options.claim_all(
+            &dir.join("sub"),
+            "card",
+            &NameFields::default(),
+            &["json", "png"],
+            IfExists::Number,
+        )?;
+        let names: Vec<_> = claimed
+            .unwrap()
+            .iter()
+            .map(|x| x.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["card (4).json", "card (4).png"]);
+        assert!(!dir.join("sub/card (3).png").exists());
+        let claimed = options.claim_all(
+            &dir.join("sub"),
+            "card",
+            &NameFields::default(),
+            &["png", "happy.png"],
+            IfExists::Number,
+        )?;
+        assert_eq!(
+            claimed.unwrap(),
+            [dir.join("sub/card (3).png"), dir.join("sub/card (3).happy.png")]
+        );
+        release_path(&dir.join("sub/card (4).png"));
+        release_path(&dir.join("sub/card (3).json"));
+        assert!(!dir.join("sub/card (4).png").exists());
+        assert!(dir.join("sub/card (3).json").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+//! Normalizing placeholders in imported text.
+//!
+//! Other apps refer to the user and the character in their own ways:
+//! Backyard AI writes `{user}` and `{character}` or just "User", Pygmalion
+//! cards use `<USER>` and `<BOT>`, and many cards spell out the character's
+//! name. Importers turn all of these into the `{{user}}` and `{{char}}`
+//! placeholders of tavern cards.
+
+use std::fmt::Display;
+
+use regex::Regex;
+
+use crate::tavern_card_v2::*;
+use crate::tavern_card_v3::CharacterDataV3;
+
+pub const USER_PLACEHOLDER: &str = "{{user}}";
+pub const CHAR_PLACEHOLDER: &str = "{{char}}";
+
+/// Built-in rules of the normalizer
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    /// The word "User" (also "USER" and "User's") becomes {{user}}
+    UserWord,
+    /// Backyard AI {user} and {character}
+    Backyard,
+    /// Pygmalion <USER> and <BOT>
+    Pygmalion,
+    /// The full name of the character becomes {{char}}
+    CharName,
+}
+
+const ALL_RULES: [Rule; 4] =
+    [Rule::UserWord, Rule::Backyard, Rule::Pygmalion, Rule::CharName];
+
+/// Default rules for cards that already come in V2 or V3 format. Their
+/// "User" words and the name of the character were written on purpose.
+const NATIVE_CARD_RULES: [Rule; 2] = [Rule::Backyard, Rule::Pygmalion];
+
+/// Word that is replaced with a placeholder, given with `--placeholder`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomRule {
+    pub word: String,
+    pub placeholder: &'static str,
+}
+
+/// Parses `WORD=user` or `WORD=char`
+fn parse_custom_rule(text: &str) -> Result<CustomRule, String> {
+    let Some((word, placeholder)) = text.rsplit_once('=') else {
+        return Err("expected WORD=user or WORD=char".to_string());
+    };
+    let placeholder = match placeholder.trim().to_lowercase().as_str() {
+        "user" | "{{user}}" => USER_PLACEHOLDER,
+        "char" | "{{char}}" => CHAR_PLACEHOLDER,
+        _ => {
+            return Err(format!(
+                "unknown placeholder {}, expected user or char",
+                placeholder
+            ))
+        }
+    };
+    let word = word.trim();
+    if word.is_empty() {
+        return Err("the word to replace is empty".to_string());
+    }
+    Ok(CustomRule { word: word.to_string(), placeholder })
+}
+
+/// Options of importers for the placeholders in the text
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct PlaceholderOptions {
+    /// Keep the imported text as it is, without replacing placeholders and
+    /// the character's name
+    #[arg(long, conflicts_with_all = ["placeholder_rules", "custom_rules"])]
+    pub keep_placeholders: bool,
+
+    /// Built-in placeholder rules to apply, separated by commas. By default
+    /// all of them, or only backyard and pygmalion for V2 and V3 cards
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub placeholder_rules: Option<Vec<Rule>>,
+
+    /// Also replace the word with a placeholder, like Anon=user or
+    /// Mira=char. Can be given many times
+    #[arg(
+        long = "placeholder",
+        value_name = "WORD=PLACEHOLDER",
+        value_parser = parse_custom_rule
+    )]
+    pub custom_rules: Vec<CustomRule>,
+}
+
+impl PlaceholderOptions {
+    /// Options for a card that already comes in V2 or V3 format. Unless the
+    /// rules are given, the word "User" and the character's name are kept.
+    pub fn for_native_card(&self) -> Self {
+        let rules = self.placeholder_rules.clone();
+        Self {
+            placeholder_rules: rules.or(Some(NATIVE_CARD_RULES.to_vec())),
+            ..self.clone()
+        }
+    }
+
+    /// Normalizer for the character with the given name. None if the
+    /// placeholders are kept.
+    pub fn normalizer(&self, char_name: Option<&str>) -> Option<Normalizer> {
+        if self.keep_placeholders {
+            return None;
+        }
+        Some(Normalizer::new(
+            self.placeholder_rules.as_deref().unwrap_or(&ALL_RULES),
+            &self.custom_rules,
+            char_name,
+        ))
+    }
+
+    /// Normalizes the text fields of V2 card data and its lorebook
+    pub fn normalize_v2(&self, data: &mut CharacterData) -> PlaceholderReport {
+        let Some(normalizer) = self.normalizer(data.name.as_deref()) else {
+            return PlaceholderReport::default();
+        };
+        let fields = text_fields(
+            [
+                ("description", &mut data.description),
+                ("personality", &mut data.personality),
+                ("scenario", &mut data.scenario),
+                ("first_mes", &mut data.first_mes),
+                ("mes_example", &mut data.mes_example),
+                ("system_prompt", &mut data.system_prompt),
+                (
+                    "post_history_instructions",
+                    &mut data.post_history_instructions,
+                ),
+            ],
+            vec![("alternate_greetings", &mut data.alternate_greetings)],
+            data.character_book.as_mut(),
+        );
+        normalizer.normalize_fields(fields)
+    }
+
+    /// Normalizes the text fields of V3 card data and its lorebook
+    pub fn normalize_v3(
+        &self,
+        data: &mut CharacterDataV3,
+    ) -> PlaceholderReport {
+        let Some(normalizer) = self.normalizer(data.name.as_deref()) else {
+            return PlaceholderReport::default();
+        };
+        let fields = text_fields(
+            [
+                ("description", &mut data.description),
+                ("personality", &mut data.personality),
+                ("scenario", &mut data.scenario),
+                ("first_mes", &mut data.first_mes),
+                ("mes_example", &mut data.mes_example),
+                ("system_prompt", &mut data.system_prompt),
+                (
+                    "post_history_instructions",
+                    &mut data.post_history_instructions,
+                ),
+            ],
+            vec![
+                ("alternate_greetings", &mut data.alternate_greetings),
+                ("group_only_greetings", &mut data.group_only_greetings),
+            ],
+            data.character_book.as_mut(),
+        );
+        normalizer.normalize_fields(fields)
+    }
+
+    /// Normalizes the content of lorebook entries, for the named character
+    pub fn normalize_book(
+        &self,
+        book: &mut CharacterBook,
+        char_name: Option<&str>,
+    ) -> PlaceholderReport {
+        let Some(normalizer) = self.normalizer(char_name) else {
+            return PlaceholderReport::default();
+        };
+        normalizer.normalize_fields(text_fields([], vec![], Some(book)))
+    }
+}
+
+/// Collects the text fields of a card, named by their JSON paths
+///
+/// Creator notes are left out, as they are written for people, not for the
+/// model. Lorebook keys are left out too, as they are matched against the
+/// chat.
+fn text_fields<'a, const N: usize>(
+    texts: [(&str, &'a mut Option<String>); N],
+    lists: Vec<(&str, &'a mut Option<Vec<String>>)>,
+    book: Option<&'a mut CharacterBook>,
+) -> Vec<(String, &'a mut String)> {
+    let mut fields = Vec::new();
+    for (name, text) in texts {
+        if let Some(text) = text {
+            fields.push((name.to_string(), text));
+        }
+    }
+    for (name, list) in lists {
+        for (i, text) in list.iter_mut().flatten().enumerate() {
+            fields.push((format!("{}[{}]", name, i), text));
+        }
+    }
+    for (i, entry) in book.into_iter().flat_map(|x| &mut x.entries).enumerate()
+    {
+        let name = format!("character_book.entries[{}].content", i);
+        fields.push((name, &mut entry.content));
+    }
+    fields
+}
+
+/// Replaces the placeholders of other apps in text
+///
+/// All rules are joined into one regex, so the text is scanned once and
+/// replaced text is never matched again. Words are matched as whole words,
+/// with Unicode word boundaries.
+pub struct Normalizer {
+    regex: Regex,
+    /// Placeholder for each group of the regex. None for the groups that
+    /// are kept as they are.
+    placeholders: Vec<Option<&'static str>>,
+}
+
+impl Normalizer {
+    pub fn new(
+        rules: &[Rule],
+        custom_rules: &[CustomRule],
+        char_name: Option<&str>,
+    ) -> Self {
+        // Macros like {{user}} or {{random:User,Bot}} go first, so that the
+        // words inside them are not replaced.
+        let mut patterns = vec![(r"\{\{[^{}]*\}\}".to_string(), None)];
+        for rule in custom_rules {
+            patterns.push((whole_word(&rule.word), Some(rule.placeholder)));
+        }
+        let char_name = char_name.map(str::trim).filter(|x| !x.is_empty());
+        if let (true, Some(name)) = (rules.contains(&Rule::CharName), char_name)
+        {
+            patterns.push((whole_word(name), Some(CHAR_PLACEHOLDER)));
+        }
+        if rules.contains(&Rule::Backyard) {
+            patterns.push((r"(?i:\{user\})".into(), Some(USER_PLACEHOLDER)));
+            patterns
+                .push((r"(?i:\{character\})".into(), Some(CHAR_PLACEHOLDER)));
+        }
+        if rules.contains(&Rule::Pygmalion) {
+            patterns.push((r"(?i:<user>)".into(), Some(USER_PLACEHOLDER)));
+            patterns.push((r"(?i:<bot>)".into(), Some(CHAR_PLACEHOLDER)));
+        }
+        if rules.contains(&Rule::UserWord) {
+            patterns
+                .push((r"\b(?:User|USER)\b".into(), Some(USER_PLACEHOLDER)));
+        }
+
+        let regex = patterns
+            .iter()
+            .map(|(pattern, _)| format!("({})", pattern))
+            .collect::<Vec<_>>()
+            .join("|");
+        Self {
+            // Patterns are escaped or fixed, so the regex is always valid
+            regex: Regex::new(&regex).unwrap(),
+            placeholders: patterns.into_iter().map(|(_, x)| x).collect(),
+        }
+    }
+
+    /// Replaces placeholders in text and lists the replacements
+    pub fn normalize(&self, text: &str) -> (String, Vec<Replacement>) {
+        let mut replacements: Vec<Replacement> = Vec::new();
+        let result =
+            self.regex.replace_all(text, |captures: &regex::Captures| {
+                let found = &captures[0];
+                let group =
+                    (1..captures.len()).find(|&i| captures.get(i).is_some());
+                let Some(into) = group.and_then(|i| self.placeholders[i - 1])
+                else {
+                    return found.to_string();
+                };
+                match replacements.iter_mut().find(|x| x.from == found) {
+                    Some(replacement) => replacement.count += 1,
+                    None => replacements.push(Replacement {
+                        from: found.to_string(),
+                        into,
+                        count: 1,
+                    }),
+                }
+                into.to_string()
+            });
+        (result.to_string(), replacements)
+    }
+
+    /// Replaces placeholders in named fields, in place
+    pub fn normalize_fields(
+        &self,
+        fields: Vec<(String, &mut String)>,
+    ) -> PlaceholderReport {
+        let mut report = PlaceholderReport::default();
+        for (name, text) in fields {
+            let (result, replacements) = self.normalize(text);
+            if !replacements.is_empty() {
+                *text = result;
+                report.fields.push((name, replacements));
+            }
+        }
+        report
+    }
+}
+
+/// Regex that matches the text as a whole word
+///
+/// Word boundaries are only added at the ends that are word characters,
+/// otherwise names like "Dr." would never match.
+fn whole_word(text: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric());
+    format!(
+        "{}{}{}",
+        if is_word(text.chars().next()) { r"\b" } else { "" },
+        regex::escape(text),
+        if is_word(text.chars().last()) { r"\b" } else { "" },
+    )
+}
+
+/// Text replaced with a placeholder, and how many times
+#[derive(Debug, PartialEq)]
+pub struct Replacement {
+    pub from: String,
+    pub into: &'static str,
+    pub count: usize,
+}
+
+/// Lists the replacements made in each field
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaceholderReport {
+    /// Pairs of (field path, replacements), in the order of the fields
+    pub fields: Vec<(String, Vec<Replacement>)>,
+}
+
+impl PlaceholderReport {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl Display for PlaceholderReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Replaced placeholders:")?;
+        for (field, replacements) in &self.fields {
+            let list: Vec<String> = replacements
+                .iter()
+                .map(|x| match x.count {
+                    1 => format!("{} -> {}", x.from, x.into),
+                    n => format!("{} -> {} ({} times)", x.from, x.into, n),
+                })
+                .collect();
+            writeln!(f, "    {}: {}", field, list.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_text() {
+        let options = PlaceholderOptions::default();
+        let normalizer = options.normalizer(Some("Captain Mira")).unwrap();
+        let normalize = |text: &str| normalizer.normalize(text).0;
+
+        assert_eq!(
+            normalize("User's ship. USER!"),
+            "{{user}}'s ship. {{user}}!"
+        );
+        assert_eq!(normalize("Userland, user, Users"), "Userland, user, Users");
+        assert_eq!(normalize("«User»—User’s"), "«{{user}}»—{{user}}’s");
+        assert_eq!(normalize("ПользовательUser"), "ПользовательUser");
+        assert_eq!(
+            normalize("{User} meets {Character}"),
+            "{{user}} meets {{char}}"
+        );
+        assert_eq!(normalize("<USER>: hi <bot>"), "{{user}}: hi {{char}}");
+        assert_eq!(
+            normalize("Captain Mira waves. Captain Miranda and Mira don't."),
+            "{{char}} waves. Captain Miranda and Mira don't."
+        );
+        // Existing macros are kept as they are
+        assert_eq!(
+            normalize("{{User}} {{random:User,Bot}} {{char}}"),
+            "{{User}} {{random:User,Bot}} {{char}}"
+        );
+
+        let options = PlaceholderOptions {
+            placeholder_rules: Some(vec![Rule::Pygmalion]),
+            custom_rules: vec![parse_custom_rule("Anon=user").unwrap()],
+            ..Default::default()
+        };
+        let normalizer = options.normalizer(Some("Mira")).unwrap();
+        let (text, replacements) = normalizer
+            .normalize("Anon sees Mira. Anon and User wave at <BOT>.");
+        assert_eq!(
+            text,
+            "{{user}} sees Mira. {{user}} and User wave at {{char}}."
+        );
+        assert_eq!(
+            replacements,
+            vec![
+                Replacement {
+                    from: "Anon".to_string(),
+                    into: USER_PLACEHOLDER,
+                    count: 2
+                },
+                Replacement {
+                    from: "<BOT>".to_string(),
+                    into: CHAR_PLACEHOLDER,
+                    count: 1
+                },
+            ]
+        );
+
+        assert!(parse_custom_rule("Anon=friend").is_err());
+        assert!(parse_custom_rule("=user").is_err());
+    }
+
+    #[test]
+    fn test_normalize_card() {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Mira".to_string());
+        card.data.description = Some("Mira likes User.".to_string());
+        card.data.creator_notes = Some("Say hi to User".to_string());
+        card.data.alternate_greetings =
+            Some(vec!["Hi".to_string(), "Hi, {user}".to_string()]);
+        card.data.character_book = Some(CharacterBook {
+            entries: vec![CharacterBookEntry {
+                keys: vec!["User".to_string()],
+                content: "<USER> owes Mira".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let report = PlaceholderOptions::default().normalize_v2(&mut card.data);
+        let data = &card.data;
+        assert_eq!(
+            data.description.as_deref(),
+            Some("{{char}} likes {{user}}.")
+        );
+        assert_eq!(data.creator_notes.as_deref(), Some("Say hi to User"));
+        assert_eq!(
+            data.alternate_greetings.as_ref().unwrap()[1],
+            "Hi, {{user}}"
+        );
+        let entry = &data.character_book.as_ref().unwrap().entries[0];
+        assert_eq!(entry.keys, vec!["User"]);
+        assert_eq!(entry.content, "{{user}} owes {{char}}");
+        let fields: Vec<&str> =
+            report.fields.iter().map(|(x, _)| x.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "description",
+                "alternate_greetings[1]",
+                "character_book.entries[0].content"
+            ]
+        );
+        assert!(report
+            .to_string()
+            .contains("    description: Mira -> {{char}}, User -> {{user}}\n"));
+
+        let options = PlaceholderOptions {
+            keep_placeholders: true,
+            ..Default::default()
+        };
+        let mut data = CharacterData {
+            description: Some("User".to_string()),
+            ..Default::default()
+        };
+        assert!(options.normalize_v2(&mut data).is_empty());
+        assert_eq!(data.description.as_deref(), Some("User"));
+    }
+}
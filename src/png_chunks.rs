@@ -0,0 +1,311 @@
+//! Low level access to PNG chunks.
+//!
+//! Lets us change metadata chunks without decoding and re-encoding the
+//! image, so the pixel data and all unrelated chunks stay exactly as they
+//! were.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+pub const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// One chunk of PNG file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub chunk_type: [u8; 4],
+    pub data: Bytes,
+    /// Position of the chunk in the file it was read from
+    pub offset: usize,
+}
+
+impl Chunk {
+    pub fn new(chunk_type: &[u8; 4], data: Bytes) -> Self {
+        Chunk { chunk_type: *chunk_type, data, offset: 0 }
+    }
+
+    /// Makes tEXt chunk. Both keyword and text must be Latin-1.
+    pub fn new_text(keyword: &str, text: &str) -> Self {
+        let mut data = latin1_bytes(keyword);
+        data.push(0);
+        data.extend(latin1_bytes(text));
+        Chunk::new(b"tEXt", Bytes::from(data))
+    }
+
+    /// Makes zTXt chunk with compressed text. Both keyword and text must be
+    /// Latin-1.
+    pub fn new_compressed_text(keyword: &str, text: &str) -> Result<Self> {
+        let mut data = latin1_bytes(keyword);
+        data.extend([0, 0]); // Separator and compression method
+        let mut encoder = ZlibEncoder::new(data, flate2::Compression::best());
+        encoder.write_all(&latin1_bytes(text))?;
+        Ok(Chunk::new(b"zTXt", Bytes::from(encoder.finish()?)))
+    }
+
+    pub fn type_str(&self) -> String {
+        String::from_utf8_lossy(&self.chunk_type).to_string()
+    }
+
+    /// Returns keyword of a text chunk (tEXt, zTXt or iTXt).
+    pub fn keyword(&self) -> Option<String> {
+        if !self.is_text() {
+            return None;
+        }
+        let end = self.data.iter().position(|x| *x == 0)?;
+        Some(latin1_string(&self.data[..end]))
+    }
+
+    /// Returns text of a text chunk (tEXt, zTXt or iTXt), decompressed.
+    pub fn text(&self) -> Result<Option<String>> {
+        if !self.is_text() {
+            return Ok(None);
+        }
+        let Some(end) = self.data.iter().position(|x| *x == 0) else {
+            bail!("{} chunk has no keyword", self.type_str());
+        };
+        let rest = &self.data[end + 1..];
+        let text = match &self.chunk_type {
+            b"tEXt" => latin1_string(rest),
+            b"zTXt" => {
+                let Some((0, compressed)) = rest.split_first() else {
+                    bail!("zTXt chunk has unknown compression method");
+                };
+                latin1_string(&decompress(compressed)?)
+            }
+            _ => {
+                // iTXt: compression flag and method, then language and
+                // translated keyword, both terminated by zero.
+                if rest.len() < 2 {
+                    bail!("iTXt chunk is truncated");
+                }
+                let (flag, method) = (rest[0], rest[1]);
+                let mut fields = rest[2..].splitn(3, |x| *x == 0);
+                let text = fields.nth(2).context("iTXt chunk is truncated")?;
+                let text = match (flag, method) {
+                    (0, _) => text.to_vec(),
+                    (1, 0) => decompress(text)?,
+                    _ => bail!("iTXt chunk has unknown compression method"),
+                };
+                String::from_utf8(text)?
+            }
+        };
+        Ok(Some(text))
+    }
+
+    /// Checks if the chunk is a text chunk (tEXt, zTXt or iTXt).
+    pub fn is_text(&self) -> bool {
+        matches!(&self.chunk_type, b"tEXt" | b"zTXt" | b"iTXt")
+    }
+
+    /// Computes CRC of the chunk, as written in the file.
+    pub fn crc(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.chunk_type);
+        hasher.update(&self.data);
+        hasher.finalize()
+    }
+}
+
+/// Splits PNG file into chunks
+///
+/// Returns error if the data is not a PNG, is truncated or has chunks with
+/// wrong CRC.
+pub fn read_chunks(image_data: &[u8]) -> Result<Vec<Chunk>> {
+    if !image_data.starts_with(PNG_SIGNATURE) {
+        bail!("Not a PNG image");
+    }
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < image_data.len() {
+        if pos + 12 > image_data.len() {
+            bail!("PNG is truncated at offset {}", pos);
+        }
+        let length =
+            u32::from_be_bytes(image_data[pos..pos + 4].try_into()?) as usize;
+        let data_start = pos + 8;
+        let data_end = data_start + length;
+        if data_end + 4 > image_data.len() {
+            bail!("PNG is truncated at offset {}", pos);
+        }
+        let chunk = Chunk {
+            chunk_type: image_data[pos + 4..pos + 8].try_into()?,
+            data: Bytes::copy_from_slice(&image_data[data_start..data_end]),
+            offset: pos,
+        };
+        let crc =
+            u32::from_be_bytes(image_data[data_end..data_end + 4].try_into()?);
+        if crc != chunk.crc() {
+            bail!("Wrong CRC of {} chunk at offset {}", chunk.type_str(), pos);
+        }
+        let is_end = &chunk.chunk_type == b"IEND";
+        chunks.push(chunk);
+        pos = data_end + 4;
+        if is_end {
+            break;
+        }
+    }
+    if chunks.first().map(|x| &x.chunk_type) != Some(b"IHDR") {
+        bail!("PNG does not start with IHDR chunk");
+    }
+    Ok(chunks)
+}
+
+/// Builds PNG file from chunks, computing their CRC.
+pub fn write_chunks(chunks: &[Chunk]) -> Bytes {
+    let size: usize = chunks.iter().map(|x| x.data.len() + 12).sum();
+    let mut output = Vec::with_capacity(PNG_SIGNATURE.len() + size);
+    output.extend_from_slice(PNG_SIGNATURE);
+    for chunk in chunks {
+        output.extend_from_slice(&(chunk.data.len() as u32).to_be_bytes());
+        output.extend_from_slice(&chunk.chunk_type);
+        output.extend_from_slice(&chunk.data);
+        output.extend_from_slice(&chunk.crc().to_be_bytes());
+    }
+    Bytes::from(output)
+}
+
+/// Replaces text chunks with a given keyword by a new chunk
+///
+/// The new chunk takes the place of the first replaced one. If there was
+/// none, it is put before the image data. Keyword is compared ignoring case.
+pub fn replace_text_chunk(chunks: &mut Vec<Chunk>, new_chunk: Chunk) {
+    let keyword = new_chunk.keyword().unwrap_or_default().to_lowercase();
+    let position = chunks
+        .iter()
+        .position(|x| x.keyword().is_some_and(|k| k.to_lowercase() == keyword))
+        .or_else(|| chunks.iter().position(|x| &x.chunk_type == b"IDAT"))
+        .unwrap_or(chunks.len().saturating_sub(1));
+    remove_text_chunks(chunks, &keyword);
+    chunks.insert(position, new_chunk);
+}
+
+/// Removes all text chunks with a given keyword, ignoring case.
+pub fn remove_text_chunks(chunks: &mut Vec<Chunk>, keyword: &str) {
+    let keyword = keyword.to_lowercase();
+    chunks.retain(|x| x.keyword().is_none_or(|k| k.to_lowercase() != keyword));
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    ZlibDecoder::new(data)
+        .read_to_end(&mut output)
+        .context("Could not decompress text chunk")?;
+    Ok(output)
+}
+
+fn latin1_bytes(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| if (c as u32) < 256 { c as u8 } else { b'?' })
+        .collect()
+}
+
+fn latin1_string(data: &[u8]) -> String {
+    data.iter().map(|x| *x as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools;
+    use anyhow::Result;
+
+    /// Default image (it has sRGB, gAMA and pHYs chunks), with more
+    /// ancillary chunks added after the header.
+    fn create_test_image() -> Result<Bytes> {
+        let mut chunks = read_chunks(&tools::get_default_image())?;
+        let extra = [
+            Chunk::new(b"tIME", Bytes::from_static(&[7, 232, 5, 1, 12, 0, 0])),
+            Chunk::new(
+                b"zTXt",
+                Bytes::from_static(b"Software\0\0x\x9c\x03\0\0\0\0\x01"),
+            ),
+            Chunk::new(b"iTXt", Bytes::from_static(b"XMP\0\0\0\0\0<x/>")),
+            Chunk::new_text("Comment", "Made by another tool"),
+        ];
+        for (i, chunk) in extra.into_iter().enumerate() {
+            chunks.insert(1 + i, chunk);
+        }
+        Ok(write_chunks(&chunks))
+    }
+
+    fn chunk_types(image: &[u8]) -> Result<Vec<String>> {
+        Ok(read_chunks(image)?.iter().map(|x| x.type_str()).collect())
+    }
+
+    fn decode_pixels(image: &[u8]) -> Result<Vec<u8>> {
+        Ok(image::load_from_memory(image)?.into_bytes())
+    }
+
+    #[test]
+    fn test_read_and_write() -> Result<()> {
+        let image = create_test_image()?;
+        assert_eq!(write_chunks(&read_chunks(&image)?), image);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_text_keeps_image() -> Result<()> {
+        let image = create_test_image()?;
+        let edited = tools::write_text_to_png("Chara", "e30=", &image)?;
+
+        // Same chunks, plus the new one right before the image data.
+        let mut expected = chunk_types(&image)?;
+        let idat = expected.iter().position(|x| x == "IDAT").unwrap();
+        expected.insert(idat, "tEXt".to_string());
+        assert_eq!(chunk_types(&edited)?, expected);
+
+        let idat_data = |image: &[u8]| -> Result<Vec<Bytes>> {
+            Ok(read_chunks(image)?
+                .into_iter()
+                .filter(|x| &x.chunk_type == b"IDAT")
+                .map(|x| x.data)
+                .collect())
+        };
+        assert_eq!(idat_data(&edited)?, idat_data(&image)?);
+        assert_eq!(decode_pixels(&edited)?, decode_pixels(&image)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_chunk_kinds() -> Result<()> {
+        let chunk = Chunk::new_text("Chara", "Plain");
+        assert_eq!(chunk.text()?.as_deref(), Some("Plain"));
+
+        let chunk = Chunk::new_compressed_text("Chara", "Compressed")?;
+        assert_eq!(chunk.keyword().as_deref(), Some("Chara"));
+        assert_eq!(chunk.text()?.as_deref(), Some("Compressed"));
+
+        let chunk = Chunk::new(
+            b"iTXt",
+            Bytes::from_static(b"Chara\0\0\0en\0\0{\"a\":\"\xc3\xa9\"}"),
+        );
+        assert_eq!(chunk.text()?.as_deref(), Some("{\"a\":\"\u{e9}\"}"));
+
+        let mut data = b"Chara\0\x01\0\0\0".to_vec();
+        let mut encoder =
+            ZlibEncoder::new(&mut data, flate2::Compression::default());
+        encoder.write_all("Zipped ünicode".as_bytes())?;
+        encoder.finish()?;
+        let chunk = Chunk::new(b"iTXt", Bytes::from(data));
+        assert_eq!(chunk.text()?.as_deref(), Some("Zipped ünicode"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_text_in_place() -> Result<()> {
+        let image = create_test_image()?;
+        let edited = tools::write_text_to_png("comment", "New", &image)?;
+        assert_eq!(chunk_types(&edited)?, chunk_types(&image)?);
+        let chunks = read_chunks(&edited)?;
+        let comment =
+            chunks.iter().find(|x| x.keyword().is_some_and(|k| k == "comment"));
+        assert_eq!(comment.unwrap().data, Bytes::from_static(b"comment\0New"));
+
+        let removed = tools::remove_text_from_png("COMMENT", &edited)?;
+        assert_eq!(chunk_types(&removed)?.len(), chunks.len() - 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! Support for V1 cards, which keep character fields at the top level.
+//!
+//! Besides the TavernAI V1 fields, old Pygmalion cards use their own names
+//! for the same fields. V1 cards are upgraded to V2 when read.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+
+use crate::tavern_card_v2::*;
+use crate::tavern_card_v3::TavernCardV3;
+
+/// Legacy Pygmalion keys and the V2 data fields they map to.
+const LEGACY_KEYS: [(&str, &str); 5] = [
+    ("char_name", "name"),
+    ("char_persona", "description"),
+    ("world_scenario", "scenario"),
+    ("char_greeting", "first_mes"),
+    ("example_dialogue", "mes_example"),
+];
+
+/// V1 fields that V2 spec recommends to keep at the top level.
+pub const V1_MIRRORED_FIELDS: [&str; 6] = [
+    "name",
+    "description",
+    "personality",
+    "scenario",
+    "first_mes",
+    "mes_example",
+];
+
+/// V2 data fields that V1 cards don't have.
+const V2_ONLY_FIELDS: [&str; 9] = [
+    "creator_notes",
+    "system_prompt",
+    "post_history_instructions",
+    "alternate_greetings",
+    "character_book",
+    "tags",
+    "creator",
+    "character_version",
+    "extensions",
+];
+
+/// Lists the V1 keys that were moved into the V2 data.
+#[derive(Debug, Default, PartialEq)]
+pub struct V1Report {
+    /// Pairs of (V1 key, V2 path)
+    pub translated: Vec<(String, String)>,
+}
+
+/// How a card without the V2 envelope was read.
+#[derive(Debug, PartialEq)]
+pub enum LegacyReport {
+    /// V1 card, upgraded to V2
+    V1(V1Report),
+    /// Bare V2 `data` object, put into the V2 envelope
+    DataOnly,
+}
+
+impl LegacyReport {
+    pub fn is_v1(&self) -> bool {
+        matches!(self, LegacyReport::V1(_))
+    }
+}
+
+/// Checks if JSON of a card is a V1 card.
+///
+/// V1 cards have neither the V2 envelope nor `spec`, and have V1 or
+/// Pygmalion fields. Objects with V2-only fields are bare V2 data.
+pub fn is_v1_json(json: &serde_json::Value) -> bool {
+    let Some(object) = json.as_object() else {
+        return false;
+    };
+    if object.contains_key("data") || object.contains_key("spec") {
+        return false;
+    }
+    if has_legacy_keys(json) {
+        return true;
+    }
+    let has_v1 = V1_MIRRORED_FIELDS.iter().any(|x| object.contains_key(*x));
+    let has_v2 = V2_ONLY_FIELDS.iter().any(|x| object.contains_key(*x));
+    has_v1 && !has_v2
+}
+
+/// Checks if JSON of a card has Pygmalion field names.
+pub fn has_legacy_keys(json: &serde_json::Value) -> bool {
+    LEGACY_KEYS.iter().any(|(key, _)| json.get(key).is_some())
+}
+
+impl TavernCardV2 {
+    /// Builds V2 card from V1 card JSON
+    ///
+    /// Pygmalion keys are translated into V2 data fields and removed. The
+    /// mirrored V1 fields and other V1 extras (like `chat` or `create_date`)
+    /// stay at the top level, as V2 spec recommends.
+    pub fn from_v1_json(json: serde_json::Value) -> Result<(Self, V1Report)> {
+        let serde_json::Value::Object(mut top_level) = json else {
+            bail!("V1 card is not a JSON object");
+        };
+        let mut report = V1Report::default();
+
+        for field in V1_MIRRORED_FIELDS {
+            if top_level.contains_key(field) {
+                report
+                    .translated
+                    .push((field.to_string(), format!("data.{}", field)));
+            }
+        }
+
+        for (legacy_key, field) in LEGACY_KEYS {
+            let Some(value) = top_level.remove(legacy_key) else {
+                continue;
+            };
+            // The standard V1 field wins, if both are present.
+            if !top_level.contains_key(field) {
+                top_level.insert(field.to_string(), value);
+            }
+            report
+                .translated
+                .push((legacy_key.to_string(), format!("data.{}", field)));
+        }
+        if !top_level.contains_key("creator_notes") {
+            if let Some(comment) = top_level.get("creatorcomment") {
+                top_level.insert("creator_notes".to_string(), comment.clone());
+                report.translated.push((
+                    "creatorcomment".to_string(),
+                    "data.creator_notes".to_string(),
+                ));
+            }
+        }
+
+        let mut data: CharacterData =
+            serde_json::from_value(serde_json::Value::Object(top_level))?;
+        // Whatever is not a V2 data field is a V1 extra.
+        let mut unknown_fields = std::mem::take(&mut data.unknown_fields);
+        for field in V1_MIRRORED_FIELDS {
+            if let Some(value) = data_field(&data, field) {
+                unknown_fields.insert(field.to_string(), value.clone().into());
+            }
+        }
+
+        let mut card =
+            TavernCardV2 { data, unknown_fields, ..Default::default() };
+        card.spec = Some(SPEC_V2.to_string());
+        card.spec_version = Some(SPEC_VERSION_V2.to_string());
+        Ok((card, report))
+    }
+
+    /// Updates mirrored V1 fields at the top level from the card data.
+    ///
+    /// Only the fields that are already present are updated, unless
+    /// `add_missing` is set.
+    pub fn sync_v1_fields(&mut self, add_missing: bool) {
+        for field in V1_MIRRORED_FIELDS {
+            sync_field(
+                &mut self.unknown_fields,
+                field,
+                data_field(&self.data, field),
+                add_missing,
+            );
+        }
+    }
+}
+
+impl TavernCardV3 {
+    /// Updates mirrored V1 fields at the top level from the card data.
+    ///
+    /// Only the fields that are already present are updated, unless
+    /// `add_missing` is set.
+    pub fn sync_v1_fields(&mut self, add_missing: bool) {
+        let d = &self.data;
+        let values = [
+            &d.name,
+            &d.description,
+            &d.personality,
+            &d.scenario,
+            &d.first_mes,
+            &d.mes_example,
+        ];
+        for (field, value) in V1_MIRRORED_FIELDS.iter().zip(values) {
+            sync_field(
+                &mut self.unknown_fields,
+                field,
+                value.as_ref(),
+                add_missing,
+            );
+        }
+    }
+}
+
+/// Returns value of V2 data field, that has a V1 mirror
+fn data_field<'a>(data: &'a CharacterData, field: &str) -> Option<&'a String> {
+    match field {
+        "name" => data.name.as_ref(),
+        "description" => data.description.as_ref(),
+        "personality" => data.personality.as_ref(),
+        "scenario" => data.scenario.as_ref(),
+        "first_mes" => data.first_mes.as_ref(),
+        "mes_example" => data.mes_example.as_ref(),
+        _ => None,
+    }
+}
+
+fn sync_field(
+    top_level: &mut HashMap<String, serde_json::Value>,
+    field: &str,
+    value: Option<&String>,
+    add_missing: bool,
+) {
+    if !add_missing && !top_level.contains_key(field) {
+        return;
+    }
+    let value = value.cloned().unwrap_or_default();
+    top_level.insert(field.to_string(), value.into());
+}
+
+impl Display for LegacyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LegacyReport::V1(report) => report.fmt(f),
+            LegacyReport::DataOnly => {
+                writeln!(f, "Card has only the V2 data object, read as V2")
+            }
+        }
+    }
+}
+
+impl Display for V1Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Converted from V1 card:")?;
+        for (from, to) in &self.translated {
+            writeln!(f, "    {} -> {}", from, to)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+
+    #[test]
+    fn test_pygmalion_card() -> Result<()> {
+        let json = json!({
+            "char_name": "Test name",
+            "char_persona": "Test persona",
+            "world_scenario": "Test scenario",
+            "char_greeting": "Test greeting",
+            "example_dialogue": "Test dialogue",
+        });
+        assert!(is_v1_json(&json));
+        let (card, report) = TavernCardV2::from_v1_json(json)?;
+        assert_eq!(card.data.name.as_deref(), Some("Test name"));
+        assert_eq!(card.data.description.as_deref(), Some("Test persona"));
+        assert_eq!(card.data.scenario.as_deref(), Some("Test scenario"));
+        assert_eq!(card.data.first_mes.as_deref(), Some("Test greeting"));
+        assert_eq!(card.data.mes_example.as_deref(), Some("Test dialogue"));
+        assert_eq!(card.spec.as_deref(), Some(SPEC_V2));
+        assert!(report
+            .translated
+            .contains(&("char_name".into(), "data.name".into())));
+        assert!(!card.unknown_fields.contains_key("char_name"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tavern_v1_card() -> Result<()> {
+        let json = json!({
+            "name": "Test name",
+            "first_mes": "Test greeting",
+            "chat": "Test name - 2024-05-01",
+            "create_date": "2024-05-01",
+            "talkativeness": "0.5",
+        });
+        let (card, _) = TavernCardV2::from_v1_json(json)?;
+        assert_eq!(card.data.name.as_deref(), Some("Test name"));
+        assert_eq!(card.data.first_mes.as_deref(), Some("Test greeting"));
+        assert!(card.data.unknown_fields.is_empty());
+        assert_eq!(card.unknown_fields["chat"], "Test name - 2024-05-01");
+        assert_eq!(card.unknown_fields["name"], "Test name");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_v1_png() -> Result<()> {
+        use base64::prelude::*;
+        let json = json!({ "char_name": "Test name", "name": "Other" });
+        let image = crate::tools::write_text_to_png(
+            TEXT_KEY_PNG,
+            &BASE64_STANDARD.encode(json.to_string()),
+            &crate::tools::get_default_image(),
+        )?;
+        let (mut card, report) =
+            TavernCardV2::from_png_image_with_report(&image)?;
+        assert_eq!(card.data.name.as_deref(), Some("Other"));
+        assert!(report.is_some_and(|x| x.is_v1()));
+
+        // Mirrored field follows the data when written
+        card.data.name = Some("New name".into());
+        let card = TavernCardV2::from_png_image(&card.into_png_image()?)?;
+        assert_eq!(card.unknown_fields["name"], "New name");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_data_only_png() -> Result<()> {
+        use base64::prelude::*;
+        let json = json!({ "name": "Test name", "creator_notes": "Notes" });
+        assert!(!is_v1_json(&json));
+        let image = crate::tools::write_text_to_png(
+            TEXT_KEY_PNG,
+            &BASE64_STANDARD.encode(json.to_string()),
+            &crate::tools::get_default_image(),
+        )?;
+        let (card, report) = TavernCardV2::from_png_image_with_report(&image)?;
+        assert_eq!(card.data.name.as_deref(), Some("Test name"));
+        assert_eq!(report, Some(LegacyReport::DataOnly));
+        assert!(card.unknown_fields.is_empty());
+
+        assert!(is_v1_json(&json!({ "name": "Test name" })));
+        assert!(!is_v1_json(&json!({ "spec": SPEC_V2, "name": "Test" })));
+        assert!(!is_v1_json(&json!({ "talkativeness": "0.5" })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_v1_fields() {
+        let mut card = TavernCardV2::new();
+        card.data.name = Some("Test name".into());
+        card.unknown_fields.insert("name".into(), "Old name".into());
+        card.sync_v1_fields(false);
+        assert_eq!(card.unknown_fields["name"], "Test name");
+        assert!(!card.unknown_fields.contains_key("first_mes"));
+        card.sync_v1_fields(true);
+        assert_eq!(card.unknown_fields["first_mes"], "");
+    }
+}
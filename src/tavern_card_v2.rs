@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 
 use anyhow::{bail, Result};
@@ -5,66 +6,155 @@ use base64::prelude::*;
 use bytes::Bytes;
 use textwrap::{fill, Options};
 
+use crate::backyard_extension::BACKYARD_EXTENSION_KEY;
+use crate::output_naming::NameFields;
+use crate::tavern_card_v1::{is_v1_json, LegacyReport};
+use crate::tavern_card_v3::{TavernCardV3, TEXT_KEY_PNG_V3};
 use crate::tools;
 
 pub const TEXT_KEY_PNG: &str = "Chara";
+pub const SPEC_V2: &str = "chara_card_v2";
+pub const SPEC_VERSION_V2: &str = "2.0";
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
 pub struct CharacterBook {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub scan_depth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token_budget: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub recursive_scanning: Option<bool>,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
+    pub extensions: HashMap<String, serde_json::Value>,
     pub entries: Vec<CharacterBookEntry>,
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
 pub struct CharacterBookEntry {
     pub keys: Vec<String>,
     pub content: String,
-    pub extensions: std::collections::HashMap<String, serde_json::Value>,
+    pub extensions: HashMap<String, serde_json::Value>,
     pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub insertion_order: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub case_sensitive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub priority: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub selective: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub secondary_keys: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub constant: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub position: Option<String>,
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+impl Default for CharacterBookEntry {
+    /// Empty entry. Unlike other flags, `enabled` is on, because a new entry
+    /// is meant to be used.
+    fn default() -> Self {
+        CharacterBookEntry {
+            keys: Vec::new(),
+            content: String::new(),
+            extensions: HashMap::new(),
+            enabled: true,
+            insertion_order: None,
+            case_sensitive: None,
+            name: None,
+            priority: None,
+            id: None,
+            comment: None,
+            selective: None,
+            secondary_keys: None,
+            constant: None,
+            position: None,
+            unknown_fields: HashMap::new(),
+        }
+    }
+}
+
+/// Entry with the given keys and content, for tests
+#[cfg(test)]
+pub fn test_entry(keys: &[&str], content: &str) -> CharacterBookEntry {
+    CharacterBookEntry {
+        keys: keys.iter().map(|x| x.to_string()).collect(),
+        content: content.to_string(),
+        ..Default::default()
+    }
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
 pub struct TavernCardV2 {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub spec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub spec_version: Option<String>,
     pub data: CharacterData,
     #[serde(skip)]
     pub image_data: Option<Bytes>, // For keeping PNG image along
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
 pub struct CharacterData {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub personality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub scenario: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub first_mes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mes_example: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub creator_notes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub system_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub post_history_instructions: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub alternate_greetings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub character_book: Option<CharacterBook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub character_version: Option<String>,
-    pub extensions:
-        Option<std::collections::HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<HashMap<String, serde_json::Value>>,
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
 }
 
 impl TavernCardV2 {
@@ -74,11 +164,37 @@ impl TavernCardV2 {
         s
     }
 
+    /// Values for output file name templates
+    pub fn name_fields(&self) -> NameFields {
+        NameFields::for_card(
+            self.data.name.as_deref(),
+            self.data.creator.as_deref(),
+            self.data.extensions.as_ref(),
+        )
+    }
+
     /// Writes card into image
     ///
-    /// Makes a copy of PNG image, with card tag added to it.
+    /// Makes a copy of PNG image, with card tag added to it. If the image
+    /// already carries a V3 card, it is updated too, so that readers which
+    /// prefer V3 see the same content.
+    #[allow(clippy::wrong_self_convention)]
     pub fn into_png_image(&self) -> Result<Bytes> {
-        let json_string = serde_json::to_string(self)?;
+        self.into_png_image_with_compression(false)
+    }
+
+    /// Writes card into image, like `into_png_image`
+    ///
+    /// If `compress` is set, card is stored in compressed zTXt chunk, which
+    /// takes less space but is not understood by some apps.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_png_image_with_compression(
+        &self,
+        compress: bool,
+    ) -> Result<Bytes> {
+        let mut card = self.clone();
+        card.sync_v1_fields(false);
+        let json_string = serde_json::to_string(&card)?;
         let base64_json_string = BASE64_STANDARD.encode(json_string);
         let temp_image_holder;
         let image_data;
@@ -89,65 +205,155 @@ impl TavernCardV2 {
                 image_data = &temp_image_holder;
             }
         }
-        let edited_card = tools::write_text_to_png(
-            TEXT_KEY_PNG,
-            &base64_json_string,
-            image_data,
-        )?;
+        let write_text = match compress {
+            true => tools::write_compressed_text_to_png,
+            false => tools::write_text_to_png,
+        };
+        let mut edited_card =
+            write_text(TEXT_KEY_PNG, &base64_json_string, image_data)?;
+        if tools::read_text_chunk(&edited_card, TEXT_KEY_PNG_V3)?.is_some() {
+            edited_card = match TavernCardV3::from_png_image(&edited_card) {
+                Ok(mut card_v3) => {
+                    card_v3.update_from_v2(&card);
+                    card_v3.image_data = Some(edited_card);
+                    card_v3.into_png_image_with_compression(compress)?
+                }
+                // A broken V3 chunk would shadow the card we just wrote.
+                Err(_) => {
+                    tools::remove_text_from_png(TEXT_KEY_PNG_V3, &edited_card)?
+                }
+            };
+        }
         Ok(edited_card)
     }
 
+    /// Reads card from PNG image
+    ///
+    /// If the image carries a V3 card, it is preferred and converted to V2.
+    /// V1 cards are upgraded to V2.
     pub fn from_png_image(image_data: &Bytes) -> Result<Self> {
+        Ok(Self::from_png_image_with_report(image_data)?.0)
+    }
+
+    /// Reads card from PNG image, like `from_png_image`
+    ///
+    /// If the card was upgraded from V1, also returns which V1 fields were
+    /// translated. Bare V2 data objects are reported too.
+    pub fn from_png_image_with_report(
+        image_data: &Bytes,
+    ) -> Result<(Self, Option<LegacyReport>)> {
         let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG)?;
+        if tools::read_text_chunk(image_data, TEXT_KEY_PNG_V3)?.is_some() {
+            match TavernCardV3::from_png_image(image_data) {
+                Ok(card) => return Ok((card.downgrade().0, None)),
+                // The V2 chunk is still there to fall back to
+                Err(e) if raw_text.is_some() => eprintln!(
+                    "Warning: skipped broken {} chunk: {}",
+                    TEXT_KEY_PNG_V3, e
+                ),
+                Err(e) => return Err(e),
+            }
+        }
         if raw_text.is_none() {
-            bail!("No {} entry in PNG tEXt chunks", TEXT_KEY_PNG);
+            bail!("No {} entry in PNG text chunks", TEXT_KEY_PNG);
         };
-        let text = BASE64_STANDARD.decode(raw_text.unwrap())?;
-        if !text.starts_with(&[b'{']) {
+        let text = tools::decode_card_payload(&raw_text.unwrap())?;
+        if !text.starts_with(b"{") {
             bail!(
-                "{} entry in PNG tEXt chunks does not start with '{{'",
+                "{} entry in PNG text chunks does not start with '{{'",
                 TEXT_KEY_PNG
             );
         }
-        // Try to convert tag into tavern card data
-        let mut card = serde_json::from_slice::<TavernCardV2>(&text);
-        if card.is_err() {
+        let json: serde_json::Value = match serde_json::from_slice(&text) {
+            Ok(json) => json,
+            Err(e) => bail!(
+                "Failed to parse {} entry in PNG text chunks: {}",
+                TEXT_KEY_PNG,
+                e
+            ),
+        };
+        // Cards without the V2 envelope are V1 cards, or bare V2 data
+        let (mut card, report) = if is_v1_json(&json) {
+            let (card, report) = TavernCardV2::from_v1_json(json)?;
+            (card, Some(LegacyReport::V1(report)))
+        } else if json.get("data").is_none() {
             // Sometimes the tag contains only the data portion
-            match serde_json::from_slice::<CharacterData>(&text) {
-                Ok(card_data) => {
-                    card = Ok(TavernCardV2 {
-                        data: card_data,
-                        ..Default::default()
-                    });
-                }
-                Err(e) => {
-                    bail!(
-                        "Failed to parse {} entry in PNG tEXt chunks: {}",
-                        TEXT_KEY_PNG,
-                        e
-                    );
+            match serde_json::from_value::<CharacterData>(json) {
+                Ok(data) => {
+                    let card = TavernCardV2 { data, ..Default::default() };
+                    (card, Some(LegacyReport::DataOnly))
                 }
+                Err(e) => bail!(
+                    "Failed to parse {} entry in PNG text chunks: {}",
+                    TEXT_KEY_PNG,
+                    e
+                ),
             }
-        }
-        let mut card = card.unwrap();
+        } else {
+            match serde_json::from_value::<TavernCardV2>(json) {
+                Ok(card) => (card, None),
+                Err(e) => bail!(
+                    "Failed to parse {} entry in PNG text chunks: {}",
+                    TEXT_KEY_PNG,
+                    e
+                ),
+            }
+        };
         card.image_data = Some(image_data.clone());
-        Ok(card)
+        Ok((card, report))
     }
 
     /// Make changes to better conform the specification
     fn improve_card(&mut self) {
         if self.spec.is_none() {
-            self.spec = Some("chara_card_v2".to_string());
+            self.spec = Some(SPEC_V2.to_string());
         }
         if self.spec_version.is_none() {
-            self.spec_version = Some("2.0".to_string());
-        }
-        if self.data.name.is_none() {
-            self.data.name = Some("".to_string());
-        }
-        if self.data.description.is_none() {
-            self.data.description = Some("NO NAME".to_string());
+            self.spec_version = Some(SPEC_VERSION_V2.to_string());
         }
+        fill_required_fields(&mut self.data);
+    }
+}
+
+/// Sets missing required fields of the card data to empty values
+///
+/// Returns the paths of the fields that were set.
+fn fill_required_fields(data: &mut CharacterData) -> Vec<String> {
+    let mut filled = Vec::new();
+    fill_field(&mut filled, "name", &mut data.name);
+    fill_field(&mut filled, "description", &mut data.description);
+    fill_field(&mut filled, "personality", &mut data.personality);
+    fill_field(&mut filled, "scenario", &mut data.scenario);
+    fill_field(&mut filled, "first_mes", &mut data.first_mes);
+    fill_field(&mut filled, "mes_example", &mut data.mes_example);
+    fill_field(&mut filled, "creator_notes", &mut data.creator_notes);
+    fill_field(&mut filled, "system_prompt", &mut data.system_prompt);
+    fill_field(
+        &mut filled,
+        "post_history_instructions",
+        &mut data.post_history_instructions,
+    );
+    fill_field(&mut filled, "creator", &mut data.creator);
+    fill_field(&mut filled, "character_version", &mut data.character_version);
+    fill_field(
+        &mut filled,
+        "alternate_greetings",
+        &mut data.alternate_greetings,
+    );
+    fill_field(&mut filled, "tags", &mut data.tags);
+    fill_field(&mut filled, "extensions", &mut data.extensions);
+    filled
+}
+
+/// Sets the field to empty value if it is missing, noting its path
+pub fn fill_field<T: Default>(
+    filled: &mut Vec<String>,
+    name: &str,
+    field: &mut Option<T>,
+) {
+    if field.is_none() {
+        *field = Some(T::default());
+        filled.push(format!("data.{}", name));
     }
 }
 
@@ -202,6 +408,38 @@ impl Display for TavernCardV2 {
             lines.push(("Lorebook:", &lb_store));
         }
 
+        // Print Backyard AI settings, if the card came from there
+        let backyard_store;
+        let extensions = self.data.extensions.as_ref();
+        if let Some(serde_json::Value::Object(settings)) =
+            extensions.and_then(|x| x.get(BACKYARD_EXTENSION_KEY))
+        {
+            backyard_store = settings
+                .iter()
+                .map(|(key, value)| match value.as_str() {
+                    Some(text) => format!("{} = {}", key, text),
+                    None => format!("{} = {}", key, value),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            lines.push(("Backyard settings:", &backyard_store));
+        }
+
+        // Print timestamps, kept under V3 names
+        let date_store: Vec<(&str, String)> =
+            [("Created:", "creation_date"), ("Modified:", "modification_date")]
+                .into_iter()
+                .filter_map(|(label, key)| {
+                    let timestamp =
+                        self.data.unknown_fields.get(key)?.as_i64()?;
+                    let date = chrono::DateTime::from_timestamp(timestamp, 0)?;
+                    Some((label, date.format("%Y-%m-%d %H:%M UTC").to_string()))
+                })
+                .collect();
+        for (label, date) in &date_store {
+            lines.push((label, date));
+        }
+
         // Now to convert the lines vector into a pretty string
         let mut output = String::new();
         let tw = *[textwrap::termwidth(), 80usize].iter().min().unwrap();
@@ -224,9 +462,6 @@ mod tests {
     use super::*;
     use anyhow::Result;
 
-    #[allow(unused_imports)]
-    use tools;
-
     fn create_test_card() -> TavernCardV2 {
         let mut card = TavernCardV2::new();
         card.data.name = Some(String::from("Test name"));
@@ -236,11 +471,15 @@ mod tests {
         card.data.first_mes = Some(String::from("Test first message"));
         card.data.mes_example = Some(String::from("Test dialog example"));
         card.data.character_book = Some(CharacterBook::default());
-        let mut entry1 = CharacterBookEntry::default();
-        entry1.content = String::from("Test book entry 1");
+        let entry1 = CharacterBookEntry {
+            content: String::from("Test book entry 1"),
+            ..Default::default()
+        };
 
-        let mut entry2 = CharacterBookEntry::default();
-        entry2.content = String::from("Test book entry 2");
+        let entry2 = CharacterBookEntry {
+            content: String::from("Test book entry 2"),
+            ..Default::default()
+        };
 
         card.data.character_book.as_mut().unwrap().entries.push(entry1);
         card.data.character_book.as_mut().unwrap().entries.push(entry2);
@@ -266,4 +505,41 @@ mod tests {
         // tools::write_image_to_file(&image, &std::path::Path::new("testing/test_card.png"))?;
         Ok(())
     }
+
+    #[test]
+    fn test_compressed_and_raw_json_chunks() -> Result<()> {
+        let card = create_test_card();
+        let image = card.into_png_image_with_compression(true)?;
+        assert_eq!(TavernCardV2::from_png_image(&image)?.data, card.data);
+
+        // Raw JSON in compressed chunk, as written by some apps
+        let json = serde_json::to_string(&card)?;
+        let image = tools::write_compressed_text_to_png(
+            TEXT_KEY_PNG,
+            &json,
+            &tools::get_default_image(),
+        )?;
+        let card2 = TavernCardV2::from_png_image(&image)?;
+        assert_eq!(card2.data, card.data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_keeps_unknown_fields() -> Result<()> {
+        let original = include_str!("../testing/fixtures/real_world_card.json");
+        let image = tools::write_text_to_png(
+            TEXT_KEY_PNG,
+            &BASE64_STANDARD.encode(original),
+            &tools::get_default_image(),
+        )?;
+        let card = TavernCardV2::from_png_image(&image)?;
+        let image = card.into_png_image()?;
+
+        let written = tools::read_text_chunk(&image, TEXT_KEY_PNG)?.unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&BASE64_STANDARD.decode(written)?)?;
+        let original: serde_json::Value = serde_json::from_str(original)?;
+        assert_eq!(written, original);
+        Ok(())
+    }
 }
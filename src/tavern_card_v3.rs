@@ -0,0 +1,630 @@
+//! Character Card V3 (`chara_card_v3`), stored in the `ccv3` PNG chunk.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+use base64::prelude::*;
+use bytes::Bytes;
+
+use crate::output_naming::NameFields;
+use crate::tavern_card_v2::*;
+use crate::tools;
+
+pub const TEXT_KEY_PNG_V3: &str = "ccv3";
+pub const SPEC_V3: &str = "chara_card_v3";
+pub const SPEC_VERSION_V3: &str = "3.0";
+/// Asset URI that refers to the image the card is embedded in.
+pub const DEFAULT_ASSET_URI: &str = "ccdefault:";
+/// Asset URI prefix for assets stored in PNG chunks, followed by a number.
+pub const PNG_ASSET_URI_PREFIX: &str = "__asset:";
+/// Key prefix of the PNG chunks that hold assets, followed by a number.
+pub const PNG_ASSET_KEY_PREFIX: &str = "chara-ext-asset_:";
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
+pub struct Asset {
+    #[serde(rename = "type")]
+    pub asset_type: String,
+    pub uri: String,
+    pub name: String,
+    pub ext: String,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
+pub struct TavernCardV3 {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spec_version: Option<String>,
+    pub data: CharacterDataV3,
+    #[serde(skip)]
+    pub image_data: Option<Bytes>, // For keeping PNG image along
+    /// Content of embedded assets, by asset URI
+    #[serde(skip)]
+    pub asset_data: BTreeMap<String, Bytes>,
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
+}
+
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone,
+)]
+pub struct CharacterDataV3 {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub personality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_mes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mes_example: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator_notes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_history_instructions: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alternate_greetings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub character_book: Option<CharacterBook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub character_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<HashMap<String, serde_json::Value>>,
+    // Fields below are new in V3
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assets: Option<Vec<Asset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator_notes_multilingual: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_only_greetings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modification_date: Option<i64>,
+    /// Fields not described by the spec, kept to write them back unchanged
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
+}
+
+/// Lists the fields that were lost or made up when converting a card
+/// between V2 and V3.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConversionReport {
+    pub dropped: Vec<String>,
+    pub synthesized: Vec<String>,
+}
+
+impl TavernCardV3 {
+    pub fn new() -> Self {
+        let mut s = TavernCardV3::default();
+        s.improve_card();
+        s
+    }
+
+    /// Values for output file name templates
+    pub fn name_fields(&self) -> NameFields {
+        NameFields::for_card(
+            self.data.name.as_deref(),
+            self.data.creator.as_deref(),
+            self.data.extensions.as_ref(),
+        )
+    }
+
+    /// Writes card into image
+    ///
+    /// Makes a copy of PNG image with both the V3 card and its V2 version
+    /// added, so that readers which don't know V3 still get the card.
+    /// Embedded assets are written into chunks of their own.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_png_image(&self) -> Result<Bytes> {
+        self.into_png_image_with_compression(false)
+    }
+
+    /// Writes card into image, like `into_png_image`
+    ///
+    /// If `compress` is set, both cards are stored in compressed zTXt
+    /// chunks, which take less space but are not understood by some apps.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_png_image_with_compression(
+        &self,
+        compress: bool,
+    ) -> Result<Bytes> {
+        let image_data =
+            self.image_data.clone().unwrap_or_else(tools::get_default_image);
+        let mut card = self.clone();
+        card.sync_v1_fields(false);
+        let (card_v2, _) = card.clone().downgrade();
+
+        // Replace old asset chunks with the current ones.
+        let mut image_data = remove_asset_chunks(&image_data)?;
+        for (uri, data) in &self.asset_data {
+            let Some(number) = uri.strip_prefix(PNG_ASSET_URI_PREFIX) else {
+                bail!("Asset {} can not be stored in PNG", uri);
+            };
+            image_data = tools::write_text_to_png(
+                &format!("{}{}", PNG_ASSET_KEY_PREFIX, number),
+                &BASE64_STANDARD.encode(data),
+                &image_data,
+            )?;
+        }
+
+        let write_text = match compress {
+            true => tools::write_compressed_text_to_png,
+            false => tools::write_text_to_png,
+        };
+        let json_v2 = serde_json::to_string(&card_v2)?;
+        let image_data = write_text(
+            TEXT_KEY_PNG,
+            &BASE64_STANDARD.encode(json_v2),
+            &image_data,
+        )?;
+        let json_v3 = serde_json::to_string(&card)?;
+        write_text(
+            TEXT_KEY_PNG_V3,
+            &BASE64_STANDARD.encode(json_v3),
+            &image_data,
+        )
+    }
+
+    /// Reads card from PNG image
+    ///
+    /// Prefers the V3 chunk. If there is none, reads the V2 card and
+    /// upgrades it.
+    pub fn from_png_image(image_data: &Bytes) -> Result<Self> {
+        let raw_text = tools::read_text_chunk(image_data, TEXT_KEY_PNG_V3)?;
+        let Some(raw_text) = raw_text else {
+            let card_v2 = TavernCardV2::from_png_image(image_data)?;
+            let (card, _) = TavernCardV3::upgrade(card_v2);
+            return Ok(card);
+        };
+        let text = tools::decode_card_payload(&raw_text)?;
+        if !text.starts_with(b"{") {
+            bail!(
+                "{} entry in PNG text chunks does not start with '{{'",
+                TEXT_KEY_PNG_V3
+            );
+        }
+        let mut card = match serde_json::from_slice::<TavernCardV3>(&text) {
+            Ok(card) => card,
+            Err(e) => bail!(
+                "Failed to parse {} entry in PNG text chunks: {}",
+                TEXT_KEY_PNG_V3,
+                e
+            ),
+        };
+        card.image_data = Some(image_data.clone());
+
+        // Load assets stored in PNG chunks
+        for asset in card.data.assets.iter().flatten() {
+            let Some(number) = asset.uri.strip_prefix(PNG_ASSET_URI_PREFIX)
+            else {
+                continue;
+            };
+            let key = format!("{}{}", PNG_ASSET_KEY_PREFIX, number);
+            let data = match tools::read_text_chunk(image_data, &key)? {
+                Some(text) => BASE64_STANDARD.decode(text)?,
+                None => {
+                    eprintln!(
+                        "Warning: asset {} not found in PNG chunks, skipped",
+                        asset.uri
+                    );
+                    continue;
+                }
+            };
+            card.asset_data.insert(asset.uri.clone(), data.into());
+        }
+        Ok(card)
+    }
+
+    /// Converts V2 card into V3
+    ///
+    /// Fields that V3 requires but the V2 card lacks are filled with empty
+    /// values and listed in the report.
+    pub fn upgrade(card: TavernCardV2) -> (Self, ConversionReport) {
+        let d = card.data;
+        // V3 fields may be already present in V2 card as unknown fields.
+        let mut unknown_fields = d.unknown_fields;
+        let unknown = &mut unknown_fields;
+        let mut new_card = TavernCardV3 {
+            spec: Some(SPEC_V3.to_string()),
+            spec_version: Some(SPEC_VERSION_V3.to_string()),
+            data: CharacterDataV3 {
+                name: d.name,
+                description: d.description,
+                personality: d.personality,
+                scenario: d.scenario,
+                first_mes: d.first_mes,
+                mes_example: d.mes_example,
+                creator_notes: d.creator_notes,
+                system_prompt: d.system_prompt,
+                post_history_instructions: d.post_history_instructions,
+                alternate_greetings: d.alternate_greetings,
+                character_book: d.character_book,
+                tags: d.tags,
+                creator: d.creator,
+                character_version: d.character_version,
+                extensions: d.extensions,
+                assets: take_unknown_field(unknown, "assets"),
+                nickname: take_unknown_field(unknown, "nickname"),
+                creator_notes_multilingual: take_unknown_field(
+                    unknown,
+                    "creator_notes_multilingual",
+                ),
+                source: take_unknown_field(unknown, "source"),
+                group_only_greetings: take_unknown_field(
+                    unknown,
+                    "group_only_greetings",
+                ),
+                creation_date: take_unknown_field(unknown, "creation_date"),
+                modification_date: take_unknown_field(
+                    unknown,
+                    "modification_date",
+                ),
+                unknown_fields,
+            },
+            image_data: card.image_data,
+            asset_data: BTreeMap::new(),
+            unknown_fields: card.unknown_fields,
+        };
+        let report = ConversionReport {
+            synthesized: new_card.fill_required_fields(),
+            ..Default::default()
+        };
+        (new_card, report)
+    }
+
+    /// Converts V3 card into V2
+    ///
+    /// V3-only fields are dropped. Those that had any content are listed in
+    /// the report. The timestamps are kept in the data under their V3 names,
+    /// as V2 has no place for them.
+    pub fn downgrade(self) -> (TavernCardV2, ConversionReport) {
+        let d = self.data;
+        let mut report = ConversionReport::default();
+        let mut drop_field = |name: &str, present: bool| {
+            if present {
+                report.dropped.push(format!("data.{}", name));
+            }
+        };
+        drop_field("assets", d.assets.as_ref().is_some_and(|x| !x.is_empty()));
+        drop_field(
+            "nickname",
+            d.nickname.as_ref().is_some_and(|x| !x.is_empty()),
+        );
+        drop_field(
+            "creator_notes_multilingual",
+            d.creator_notes_multilingual
+                .as_ref()
+                .is_some_and(|x| !x.is_empty()),
+        );
+        drop_field("source", d.source.as_ref().is_some_and(|x| !x.is_empty()));
+        drop_field(
+            "group_only_greetings",
+            d.group_only_greetings.as_ref().is_some_and(|x| !x.is_empty()),
+        );
+        let mut unknown_fields = d.unknown_fields;
+        let dates = [
+            ("creation_date", d.creation_date),
+            ("modification_date", d.modification_date),
+        ];
+        for (key, date) in dates {
+            if let Some(date) = date {
+                unknown_fields.insert(key.to_string(), date.into());
+            }
+        }
+
+        let new_card = TavernCardV2 {
+            spec: Some(SPEC_V2.to_string()),
+            spec_version: Some(SPEC_VERSION_V2.to_string()),
+            data: CharacterData {
+                name: d.name,
+                description: d.description,
+                personality: d.personality,
+                scenario: d.scenario,
+                first_mes: d.first_mes,
+                mes_example: d.mes_example,
+                creator_notes: d.creator_notes,
+                system_prompt: d.system_prompt,
+                post_history_instructions: d.post_history_instructions,
+                alternate_greetings: d.alternate_greetings,
+                character_book: d.character_book,
+                tags: d.tags,
+                creator: d.creator,
+                character_version: d.character_version,
+                extensions: d.extensions,
+                unknown_fields,
+            },
+            image_data: self.image_data,
+            unknown_fields: self.unknown_fields,
+        };
+        (new_card, report)
+    }
+
+    /// Copies the fields shared with V2 from a V2 card, keeping V3-only
+    /// fields as they are.
+    ///
+    /// Top level fields of V2 card, like the extras of V1 cards, are copied
+    /// too, as V2 chunk is written from the V3 card.
+    pub fn update_from_v2(&mut self, card: &TavernCardV2) {
+        let d = card.data.clone();
+        self.data.name = d.name;
+        self.data.description = d.description;
+        self.data.personality = d.personality;
+        self.data.scenario = d.scenario;
+        self.data.first_mes = d.first_mes;
+        self.data.mes_example = d.mes_example;
+        self.data.creator_notes = d.creator_notes;
+        self.data.system_prompt = d.system_prompt;
+        self.data.post_history_instructions = d.post_history_instructions;
+        self.data.alternate_greetings = d.alternate_greetings;
+        self.data.character_book = d.character_book;
+        self.data.tags = d.tags;
+        self.data.creator = d.creator;
+        self.data.character_version = d.character_version;
+        self.data.extensions = d.extensions;
+        let mut unknown_fields = d.unknown_fields;
+        let unknown = &mut unknown_fields;
+        self.data.creation_date = take_unknown_field(unknown, "creation_date");
+        self.data.modification_date =
+            take_unknown_field(unknown, "modification_date");
+        self.data.unknown_fields = unknown_fields;
+        self.unknown_fields = card.unknown_fields.clone();
+    }
+
+    /// Make changes to better conform the specification
+    fn improve_card(&mut self) {
+        if self.spec.is_none() {
+            self.spec = Some(SPEC_V3.to_string());
+        }
+        if self.spec_version.is_none() {
+            self.spec_version = Some(SPEC_VERSION_V3.to_string());
+        }
+        self.fill_required_fields();
+    }
+
+    /// Sets missing required fields to empty values.
+    ///
+    /// Returns the paths of the fields that were set.
+    fn fill_required_fields(&mut self) -> Vec<String> {
+        let mut filled = Vec::new();
+        let data = &mut self.data;
+        fill_field(&mut filled, "name", &mut data.name);
+        fill_field(&mut filled, "description", &mut data.description);
+        fill_field(&mut filled, "personality", &mut data.personality);
+        fill_field(&mut filled, "scenario", &mut data.scenario);
+        fill_field(&mut filled, "first_mes", &mut data.first_mes);
+        fill_field(&mut filled, "mes_example", &mut data.mes_example);
+        fill_field(&mut filled, "creator_notes", &mut data.creator_notes);
+        fill_field(&mut filled, "system_prompt", &mut data.system_prompt);
+        fill_field(
+            &mut filled,
+            "post_history_instructions",
+            &mut data.post_history_instructions,
+        );
+        fill_field(&mut filled, "creator", &mut data.creator);
+        fill_field(
+            &mut filled,
+            "character_version",
+            &mut data.character_version,
+        );
+        fill_field(
+            &mut filled,
+            "alternate_greetings",
+            &mut data.alternate_greetings,
+        );
+        fill_field(&mut filled, "tags", &mut data.tags);
+        fill_field(
+            &mut filled,
+            "group_only_greetings",
+            &mut data.group_only_greetings,
+        );
+        fill_field(&mut filled, "extensions", &mut data.extensions);
+        filled
+    }
+}
+
+/// Removes the chunks of embedded assets from PNG image
+pub fn remove_asset_chunks(image_data: &Bytes) -> Result<Bytes> {
+    let mut image_data = image_data.clone();
+    for key in tools::list_text_keys(&image_data)? {
+        if key.starts_with(PNG_ASSET_KEY_PREFIX) {
+            image_data = tools::remove_text_from_png(&key, &image_data)?;
+        }
+    }
+    Ok(image_data)
+}
+
+/// Removes a field from the map of unknown fields and parses it.
+///
+/// If the value does not parse, it stays in the map.
+fn take_unknown_field<T: serde::de::DeserializeOwned>(
+    unknown_fields: &mut HashMap<String, serde_json::Value>,
+    key: &str,
+) -> Option<T> {
+    let value = unknown_fields.remove(key)?;
+    match serde_json::from_value(value.clone()) {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            unknown_fields.insert(key.to_string(), value);
+            None
+        }
+    }
+}
+
+impl Display for ConversionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.dropped.is_empty() && self.synthesized.is_empty() {
+            return writeln!(f, "No fields were dropped or synthesized.");
+        }
+        for field in &self.dropped {
+            writeln!(f, "Dropped: {}", field)?;
+        }
+        for field in &self.synthesized {
+            writeln!(f, "Synthesized: {}", field)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn create_test_card() -> TavernCardV3 {
+        let mut card = TavernCardV3::new();
+        card.data.name = Some(String::from("Test name"));
+        card.data.description = Some(String::from("Test description"));
+        card.data.nickname = Some(String::from("Testy"));
+        card.data.group_only_greetings =
+            Some(vec![String::from("Hello, everyone")]);
+        card.data.creation_date = Some(1_700_000_000);
+        card.data.assets = Some(vec![Asset {
+            asset_type: String::from("icon"),
+            uri: String::from("ccdefault:"),
+            name: String::from("main"),
+            ext: String::from("png"),
+        }]);
+        card.image_data = Some(tools::get_default_image());
+        card
+    }
+
+    #[test]
+    fn test_write_and_read() -> Result<()> {
+        let card = create_test_card();
+        let image = card.into_png_image()?;
+        let mut card2 = TavernCardV3::from_png_image(&image)?;
+        card2.image_data = card.image_data.clone();
+        assert_eq!(card, card2);
+
+        // Older readers get the V2 version of the same card
+        let raw_v2 = tools::read_text_chunk(&image, TEXT_KEY_PNG)?.unwrap();
+        let card_v2: TavernCardV2 =
+            serde_json::from_slice(&BASE64_STANDARD.decode(raw_v2)?)?;
+        assert_eq!(card_v2.spec.as_deref(), Some(SPEC_V2));
+        assert_eq!(card_v2.data.name.as_deref(), Some("Test name"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_downgrade_report() {
+        let (card_v2, report) = create_test_card().downgrade();
+        assert_eq!(
+            card_v2.data.description.as_deref(),
+            Some("Test description")
+        );
+        assert_eq!(
+            report.dropped,
+            vec!["data.assets", "data.nickname", "data.group_only_greetings",]
+        );
+        assert!(report.synthesized.is_empty());
+        assert_eq!(card_v2.data.unknown_fields["creation_date"], 1_700_000_000);
+        assert!(card_v2.to_string().contains("2023-11-14 22:13 UTC"));
+    }
+
+    #[test]
+    fn test_upgrade_report() {
+        let mut card_v2 = TavernCardV2::default();
+        card_v2.data.name = Some(String::from("Test name"));
+        card_v2.data.tags = Some(vec![String::from("tag")]);
+        let (card, report) = TavernCardV3::upgrade(card_v2);
+        assert_eq!(card.spec.as_deref(), Some(SPEC_V3));
+        assert_eq!(card.data.group_only_greetings, Some(vec![]));
+        assert!(report.dropped.is_empty());
+        assert!(report
+            .synthesized
+            .contains(&"data.group_only_greetings".into()));
+        assert!(!report.synthesized.contains(&"data.name".into()));
+        assert!(!report.synthesized.contains(&"data.tags".into()));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_unknown_fields() {
+        let mut card_v2 = TavernCardV2::default();
+        let unknown = &mut card_v2.data.unknown_fields;
+        unknown.insert("nickname".into(), "Testy".into());
+        unknown.insert("utilityBot".into(), false.into());
+        card_v2.unknown_fields.insert("chat".into(), "Test chat".into());
+
+        let (card, _) = TavernCardV3::upgrade(card_v2);
+        assert_eq!(card.data.nickname.as_deref(), Some("Testy"));
+        assert!(!card.data.unknown_fields.contains_key("nickname"));
+        assert_eq!(card.data.unknown_fields["utilityBot"], false);
+        assert_eq!(card.unknown_fields["chat"], "Test chat");
+    }
+
+    #[test]
+    fn test_v2_edit_keeps_v3_fields() -> Result<()> {
+        let image = create_test_card().into_png_image()?;
+        let mut card_v2 = TavernCardV2::from_png_image(&image)?;
+        card_v2.data.description = Some(String::from("Edited"));
+        let image = card_v2.into_png_image()?;
+
+        let card = TavernCardV3::from_png_image(&image)?;
+        assert_eq!(card.data.description.as_deref(), Some("Edited"));
+        assert_eq!(card.data.nickname.as_deref(), Some("Testy"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_edit_keeps_top_level_fields() -> Result<()> {
+        let image = create_test_card().into_png_image()?;
+        let mut card_v2 = TavernCardV2::from_png_image(&image)?;
+        card_v2.unknown_fields.insert("talkativeness".into(), "0.5".into());
+        let image = card_v2.into_png_image()?;
+
+        let raw_v2 = tools::read_text_chunk(&image, TEXT_KEY_PNG)?.unwrap();
+        let card_v2: TavernCardV2 =
+            serde_json::from_slice(&BASE64_STANDARD.decode(raw_v2)?)?;
+        assert_eq!(card_v2.unknown_fields["talkativeness"], "0.5");
+        let card_v2 = TavernCardV2::from_png_image(&image)?;
+        assert_eq!(card_v2.unknown_fields["talkativeness"], "0.5");
+        let card = TavernCardV3::from_png_image(&image)?;
+        assert_eq!(card.data.nickname.as_deref(), Some("Testy"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_v3_chunks() -> Result<()> {
+        // Missing asset chunk skips only the asset
+        let mut card = create_test_card();
+        card.data.assets.as_mut().unwrap().push(Asset {
+            asset_type: String::from("emotion"),
+            uri: format!("{}1", PNG_ASSET_URI_PREFIX),
+            name: String::from("happy"),
+            ext: String::from("png"),
+        });
+        let image = card.into_png_image()?;
+        let card = TavernCardV3::from_png_image(&image)?;
+        assert!(card.asset_data.is_empty());
+        assert_eq!(card.data.assets.map(|x| x.len()), Some(2));
+
+        // Broken V3 chunk falls back to the V2 card
+        let image = TavernCardV2::from_png_image(&image)?.into_png_image()?;
+        let image = tools::remove_text_from_png(TEXT_KEY_PNG_V3, &image)?;
+        let image =
+            tools::write_text_to_png(TEXT_KEY_PNG_V3, "notbase64!!", &image)?;
+        let card_v2 = TavernCardV2::from_png_image(&image)?;
+        assert_eq!(card_v2.data.name.as_deref(), Some("Test name"));
+        Ok(())
+    }
+}
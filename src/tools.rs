@@ -1,26 +1,143 @@
 //! Functions that will likely be useful for multiple tasks
 use anyhow::{bail, Context, Result};
+use base64::prelude::*;
 use bytes::Bytes;
-use png::text_metadata::TEXtChunk;
-use std::path::Path;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
 
-/// Download web page by URL, return contents
-pub fn download_page(url: &str) -> Result<String> {
-    let response = reqwest::blocking::get(url)?;
-    if response.status().is_success() {
-        let body = response.text()?;
-        return Ok(body);
-    } else {
-        bail!("Failed to download the web page: {:?}", response.status());
+use crate::embedded_card;
+use crate::http_client::HttpClient;
+use crate::output_naming::{IfExists, NameFields, OutputOptions};
+use crate::png_chunks::{self, Chunk};
+use crate::tavern_card_v2::TEXT_KEY_PNG;
+use crate::tavern_card_v3::TEXT_KEY_PNG_V3;
+
+/// Options shared by commands that write cards into images
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct WriteOptions {
+    /// Overwrite output file if it exists already
+    #[arg(long)]
+    pub force: bool,
+
+    /// Also write V1 fields at the top level, for old apps
+    #[arg(long)]
+    pub v1_fields: bool,
+
+    /// Store the card compressed (zTXt chunk). Some apps can't read it
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Save the card as WebP image (card in EXIF), instead of PNG
+    #[arg(long)]
+    pub webp: bool,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+}
+
+impl WriteOptions {
+    /// Works out where to write the card made from `source`.
+    ///
+    /// By default the card goes next to `source`, named by `template`, with
+    /// the extension of the output format. Returns None if the file exists
+    /// and is skipped.
+    pub fn card_path(
+        &self,
+        source: &Path,
+        template: &str,
+        fields: NameFields,
+    ) -> Result<Option<PathBuf>> {
+        let extension = if self.webp { "webp" } else { "png" };
+        let paths = claim_next_to(
+            source,
+            &self.output,
+            self.force,
+            template,
+            fields,
+            &[extension],
+        )?;
+        Ok(paths.map(|mut x| x.remove(0)))
+    }
+
+    /// Converts PNG card into the output format.
+    pub fn output_image(&self, png_data: Bytes) -> Result<Bytes> {
+        match self.webp {
+            true => embedded_card::png_card_to_webp(&png_data),
+            false => Ok(png_data),
+        }
     }
 }
 
+/// Options shared by commands that export cards into other formats
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// Overwrite output file if it exists already
+    #[arg(long)]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub output: OutputOptions,
+}
+
+impl ExportOptions {
+    /// Works out where to write the files exported from `source`, one for
+    /// each of `extensions`.
+    ///
+    /// By default the files go next to `source`, named by `template`.
+    /// Returns None if they exist and are skipped.
+    pub fn output_paths(
+        &self,
+        source: &Path,
+        template: &str,
+        fields: NameFields,
+        extensions: &[&str],
+    ) -> Result<Option<Vec<PathBuf>>> {
+        claim_next_to(
+            source,
+            &self.output,
+            self.force,
+            template,
+            fields,
+            extensions,
+        )
+    }
+
+    /// Like `output_paths`, for a single file
+    pub fn output_path(
+        &self,
+        source: &Path,
+        template: &str,
+        fields: NameFields,
+        extension: &str,
+    ) -> Result<Option<PathBuf>> {
+        let paths =
+            self.output_paths(source, template, fields, &[extension])?;
+        Ok(paths.map(|mut x| x.remove(0)))
+    }
+}
+
+/// Claims the output files made from `source`, placed next to it by default
+fn claim_next_to(
+    source: &Path,
+    output: &OutputOptions,
+    force: bool,
+    template: &str,
+    fields: NameFields,
+    extensions: &[&str],
+) -> Result<Option<Vec<PathBuf>>> {
+    let stem = source.file_stem().map(|x| x.to_string_lossy().to_string());
+    let fields = NameFields { stem, ..fields };
+    let dir = source.parent().unwrap_or(Path::new(""));
+    let policy = if force { IfExists::Overwrite } else { IfExists::Ask };
+    output.claim_all(dir, template, &fields, extensions, policy)
+}
+
 /// Download image from URL.
-pub fn download_image(url: &str) -> Result<Bytes> {
+pub fn download_image(url: &str, client: &HttpClient) -> Result<Bytes> {
     let downloaded_data;
     // Try to download the image.
-    let response = reqwest::blocking::get(url)
-        .context("No response when downloading image!")?;
+    let response =
+        client.get(url).context("No response when downloading image!")?;
     if response.status().is_success() {
         downloaded_data =
             response.bytes().context("Could not read the downloaded data.")?;
@@ -38,8 +155,23 @@ pub fn write_image_to_file(
     image_data: &Bytes,
     image_path: &Path,
 ) -> Result<()> {
-    let mut file = std::fs::File::create(image_path)?;
-    std::io::Write::write_all(&mut file, &image_data)?;
+    replace_file(image_path, image_data)
+}
+
+/// Replaces content of the file, keeping the old one if writing fails.
+///
+/// The data is written into a temporary file next to `path` first, which
+/// then takes its place.
+pub fn replace_file(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp", name));
+    let result = std::fs::write(&temp_path, data)
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e)
+            .with_context(|| format!("Could not write {}", path.display()));
+    }
     Ok(())
 }
 
@@ -48,6 +180,13 @@ pub fn read_image_from_file(image_path: &Path) -> Result<Bytes> {
     Ok(Bytes::from(image_data))
 }
 
+/// Reads card image from file, as PNG.
+///
+/// Cards embedded in WebP or JPEG images are converted to PNG cards.
+pub fn read_card_image_from_file(image_path: &Path) -> Result<Bytes> {
+    embedded_card::to_png_card(&read_image_from_file(image_path)?)
+}
+
 /// Convert an image to PNG format.
 ///
 /// Take an image in any supported format and convert it to PNG.
@@ -80,67 +219,103 @@ pub fn get_default_image() -> Bytes {
 /// Adds a key-value tEXt chunk to PNG.
 ///
 /// Returns error if the data is not a proper PNG. Makes sure not to duplicate
-/// the text chunk with the same key.
+/// the text chunk with the same key. The image itself and all other chunks
+/// are copied as they are.
 pub fn write_text_to_png(
     key: &str,
     value: &str,
     image_data: &Bytes,
 ) -> Result<Bytes> {
-    // # Decode
-    // The decoder is a build for reader and can be used to set various decoding options
-    // via `Transformations`. The default output transformation is `Transformations::IDENTITY`.
-    let decoder = png::Decoder::new(image_data.as_ref());
-    let mut reader = decoder.read_info()?;
-    let png_info = reader.info().clone();
-
-    // # Encode
-    // let path_out = image_path.with_file_name("output2");
-    let mut output_vec: Vec<u8> = Vec::new();
-
-    // Get defaults for interlaced parameter.
-    let mut info_out = png_info.clone();
-    let info_default = png::Info::default();
-    info_out.interlaced = info_default.interlaced;
-
-    // Add text entry. Make sure only one text entry with that key exists.
-    info_out
-        .uncompressed_latin1_text
-        .retain(|x| x.keyword.to_lowercase() != key.to_lowercase());
-    let new_text_entry =
-        TEXtChunk { keyword: key.to_string(), text: value.to_string() };
-    info_out.uncompressed_latin1_text.push(new_text_entry);
-
-    let mut encoder = png::Encoder::with_info(&mut output_vec, info_out)?;
-    encoder.set_depth(png_info.bit_depth);
-
-    // Save picture with changed info. Copy frames from reader.
-    let mut writer = encoder.write_header()?;
-    let mut buf = vec![0; reader.output_buffer_size()];
-    while let Ok(info) = reader.next_frame(&mut buf) {
-        let frame_bytes = &buf[..info.buffer_size()];
-        writer.write_image_data(&frame_bytes)?;
-    }
-    drop(buf);
-    drop(writer);
-    Ok(Bytes::from(output_vec))
+    let mut chunks = png_chunks::read_chunks(image_data)?;
+    png_chunks::replace_text_chunk(&mut chunks, Chunk::new_text(key, value));
+    Ok(png_chunks::write_chunks(&chunks))
+}
+
+/// Removes all text chunks with a given key from PNG.
+pub fn remove_text_from_png(key: &str, image_data: &Bytes) -> Result<Bytes> {
+    let mut chunks = png_chunks::read_chunks(image_data)?;
+    png_chunks::remove_text_chunks(&mut chunks, key);
+    Ok(png_chunks::write_chunks(&chunks))
+}
+
+/// Adds a key-value text chunk to PNG, compressed as zTXt.
+///
+/// Works like `write_text_to_png`. Note that some apps only read tEXt.
+pub fn write_compressed_text_to_png(
+    key: &str,
+    value: &str,
+    image_data: &Bytes,
+) -> Result<Bytes> {
+    let mut chunks = png_chunks::read_chunks(image_data)?;
+    let new_chunk = Chunk::new_compressed_text(key, value)?;
+    png_chunks::replace_text_chunk(&mut chunks, new_chunk);
+    Ok(png_chunks::write_chunks(&chunks))
+}
+
+/// Lists keys of all text chunks (tEXt, zTXt and iTXt) in PNG image
+pub fn list_text_keys(image_data: &Bytes) -> Result<Vec<String>> {
+    let chunks = png_chunks::read_chunks(image_data)?;
+    Ok(chunks.iter().filter_map(|x| x.keyword()).collect())
 }
 
-/// Searches PNG image for a tEXt chunk with a given key
+/// Searches PNG image for a text chunk with a given key
+///
+/// Looks into tEXt, zTXt and iTXt chunks, and returns the text of the first
+/// one found. Key is compared ignoring case.
 pub fn read_text_chunk(
     image_data: &Bytes,
     chunk_key: &str,
 ) -> Result<Option<String>> {
-    // Create a decoder
-    let decoder = png::Decoder::new(image_data.as_ref());
-    let mut reader = decoder.read_info()?;
-    reader.finish()?;
-    let png_info = reader.info();
-
-    for text_chunk in &png_info.uncompressed_latin1_text {
-        if text_chunk.keyword.to_lowercase() == chunk_key.to_lowercase() {
-            return Ok(Some(text_chunk.text.clone()));
+    let chunks = png_chunks::read_chunks(image_data)?;
+    let chunk_key = chunk_key.to_lowercase();
+    for chunk in &chunks {
+        if chunk.keyword().is_some_and(|x| x.to_lowercase() == chunk_key) {
+            return chunk.text();
         }
     }
     // If we didn't find the chunk, return None
     Ok(None)
 }
+
+/// Decodes card payload of a text chunk
+///
+/// Cards are normally stored as base64 of JSON, but some apps write the
+/// JSON as it is.
+pub fn decode_card_payload(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim();
+    if text.starts_with('{') {
+        return Ok(text.as_bytes().to_vec());
+    }
+    Ok(BASE64_STANDARD.decode(text)?)
+}
+
+/// Finds card chunks and parses their JSON
+///
+/// Returns indices of the chunks with their JSON. V3 chunk comes first.
+pub fn read_card_chunks(chunks: &[Chunk]) -> Result<Vec<(usize, Value)>> {
+    let mut found = Vec::new();
+    for key in [TEXT_KEY_PNG_V3, TEXT_KEY_PNG] {
+        let Some(index) = chunks.iter().position(|x| {
+            x.keyword().is_some_and(|k| k.eq_ignore_ascii_case(key))
+        }) else {
+            continue;
+        };
+        let text = chunks[index].text()?.unwrap_or_default();
+        let json: Value = serde_json::from_slice(&decode_card_payload(&text)?)
+            .with_context(|| format!("{} chunk is not valid JSON", key))?;
+        found.push((index, json));
+    }
+    Ok(found)
+}
+
+/// Makes a card chunk to replace `chunk`, with the new card JSON
+///
+/// Keyword and compression are kept. The JSON is stored as base64.
+pub fn card_chunk_with_json(chunk: &Chunk, json: &Value) -> Result<Chunk> {
+    let keyword = chunk.keyword().unwrap_or_default();
+    let encoded = BASE64_STANDARD.encode(serde_json::to_string(json)?);
+    match &chunk.chunk_type {
+        b"zTXt" => Chunk::new_compressed_text(&keyword, &encoded),
+        _ => Ok(Chunk::new_text(&keyword, &encoded)),
+    }
+}
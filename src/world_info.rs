@@ -0,0 +1,435 @@
+//! Conversion between card lorebooks and SillyTavern World Info files.
+//!
+//! World Info files (`worlds/*.json` in SillyTavern) keep entries in an
+//! object keyed by uid, with their own field names. The mapping follows the
+//! one SillyTavern uses when it embeds World Info into cards: fields that
+//! have no counterpart in `CharacterBookEntry` go into its `extensions`.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+use crate::placeholders::PlaceholderOptions;
+use crate::tavern_card_v2::*;
+use crate::tools::{self, ExportOptions, WriteOptions};
+
+/// World Info entry fields and the extension keys they are kept under.
+const EXTENSION_KEYS: [(&str, &str); 22] = [
+    ("position", "position"),
+    ("excludeRecursion", "exclude_recursion"),
+    ("preventRecursion", "prevent_recursion"),
+    ("delayUntilRecursion", "delay_until_recursion"),
+    ("displayIndex", "display_index"),
+    ("probability", "probability"),
+    ("useProbability", "useProbability"),
+    ("depth", "depth"),
+    ("selectiveLogic", "selectiveLogic"),
+    ("group", "group"),
+    ("groupOverride", "group_override"),
+    ("groupWeight", "group_weight"),
+    ("scanDepth", "scan_depth"),
+    ("caseSensitive", "case_sensitive"),
+    ("matchWholeWords", "match_whole_words"),
+    ("useGroupScoring", "use_group_scoring"),
+    ("automationId", "automation_id"),
+    ("role", "role"),
+    ("vectorized", "vectorized"),
+    ("sticky", "sticky"),
+    ("cooldown", "cooldown"),
+    ("delay", "delay"),
+];
+
+/// World Info entry fields that map to `CharacterBookEntry` fields.
+const CORE_KEYS: [&str; 10] = [
+    "uid",
+    "key",
+    "keysecondary",
+    "comment",
+    "content",
+    "constant",
+    "selective",
+    "order",
+    "disable",
+    "addMemo",
+];
+
+/// Position numbers of World Info: before and after character definitions
+const POSITION_BEFORE_CHAR: u64 = 0;
+const POSITION_AFTER_CHAR: u64 = 1;
+
+/// Converts card lorebook into World Info file JSON
+///
+/// The original lorebook is kept in `originalData`, like SillyTavern does.
+pub fn book_to_world_info(book: &CharacterBook) -> Result<Value> {
+    let mut entries = Map::new();
+    let mut taken = Vec::new();
+    for (index, entry) in book.entries.iter().enumerate() {
+        // Entries are keyed by uid, so it must be unique.
+        let mut uid = entry.id.unwrap_or(index as u32);
+        while taken.contains(&uid) {
+            uid = taken.iter().max().unwrap() + 1;
+        }
+        taken.push(uid);
+        let wi_entry = entry_to_world_info(entry, uid, index);
+        entries.insert(uid.to_string(), Value::Object(wi_entry));
+    }
+    let mut world_info = json!({ "entries": entries });
+    if let Some(name) = &book.name {
+        world_info["name"] = name.clone().into();
+    }
+    world_info["originalData"] = serde_json::to_value(book)?;
+    Ok(world_info)
+}
+
+/// Converts World Info file JSON into card lorebook
+///
+/// Lorebook settings are taken from `originalData`, if the file has it.
+/// Entries are ordered by uid.
+pub fn world_info_to_book(world_info: &Value) -> Result<CharacterBook> {
+    let entries: Vec<&Value> = match world_info.get("entries") {
+        Some(Value::Object(entries)) => entries.values().collect(),
+        Some(Value::Array(entries)) => entries.iter().collect(),
+        _ => bail!("World Info file has no entries"),
+    };
+    let mut entries = entries
+        .into_iter()
+        .map(|x| match x {
+            Value::Object(x) => Ok(world_info_to_entry(x)),
+            _ => bail!("World Info entry is not an object"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|x| x.id);
+
+    let original = world_info.get("originalData").cloned();
+    let mut book: CharacterBook = original
+        .and_then(|x| serde_json::from_value(x).ok())
+        .unwrap_or_default();
+    if let Some(name) = world_info.get("name").and_then(|x| x.as_str()) {
+        book.name = Some(name.to_string());
+    }
+    book.entries = entries;
+    Ok(book)
+}
+
+fn entry_to_world_info(
+    entry: &CharacterBookEntry,
+    uid: u32,
+    index: usize,
+) -> Map<String, Value> {
+    let position = match entry.position.as_deref() {
+        Some("before_char") => POSITION_BEFORE_CHAR,
+        _ => POSITION_AFTER_CHAR,
+    };
+    let comment = entry.comment.as_ref().or(entry.name.as_ref());
+    let mut wi = json!({
+        "uid": uid,
+        "key": entry.keys,
+        "keysecondary": entry.secondary_keys.clone().unwrap_or_default(),
+        "comment": comment.cloned().unwrap_or_default(),
+        "content": entry.content,
+        "constant": entry.constant.unwrap_or(false),
+        "selective": entry.selective.unwrap_or(false),
+        "order": entry.insertion_order.unwrap_or(100),
+        "position": position,
+        "disable": !entry.enabled,
+        "addMemo": comment.is_some(),
+        "excludeRecursion": false,
+        "preventRecursion": false,
+        "delayUntilRecursion": false,
+        "displayIndex": index,
+        "probability": 100,
+        "useProbability": true,
+        "depth": 4,
+        "selectiveLogic": 0,
+        "group": "",
+        "groupOverride": false,
+        "groupWeight": 100,
+        "scanDepth": null,
+        "caseSensitive": entry.case_sensitive,
+        "matchWholeWords": null,
+        "useGroupScoring": null,
+        "automationId": "",
+        "role": null,
+        "vectorized": false,
+        "sticky": 0,
+        "cooldown": 0,
+        "delay": 0,
+    });
+    let wi = wi.as_object_mut().unwrap();
+
+    let mut keys: Vec<&String> = entry.extensions.keys().collect();
+    keys.sort();
+    for key in keys {
+        let wi_key = EXTENSION_KEYS
+            .iter()
+            .find(|(_, ext)| ext == key)
+            .map_or(key.as_str(), |(wi_key, _)| wi_key);
+        if CORE_KEYS.contains(&wi_key) {
+            continue;
+        }
+        if wi_key == "caseSensitive" && entry.case_sensitive.is_some() {
+            continue;
+        }
+        wi.insert(wi_key.to_string(), entry.extensions[key].clone());
+    }
+    std::mem::take(wi)
+}
+
+fn world_info_to_entry(wi: &Map<String, Value>) -> CharacterBookEntry {
+    let mut entry = CharacterBookEntry::default();
+    let mut extensions = HashMap::new();
+    for (key, value) in wi {
+        match key.as_str() {
+            "uid" => entry.id = value.as_u64().map(|x| x as u32),
+            "key" => entry.keys = string_list(value),
+            "keysecondary" => entry.secondary_keys = Some(string_list(value)),
+            "comment" => {
+                let comment = value.as_str().unwrap_or_default();
+                entry.comment =
+                    (!comment.is_empty()).then(|| comment.to_string());
+            }
+            "content" => {
+                entry.content = value.as_str().unwrap_or_default().to_string()
+            }
+            "constant" => entry.constant = value.as_bool(),
+            "selective" => entry.selective = value.as_bool(),
+            "order" => entry.insertion_order = value.as_u64().map(|x| x as u32),
+            "disable" => entry.enabled = !value.as_bool().unwrap_or(false),
+            "addMemo" => (),
+            _ => {
+                let ext_key = EXTENSION_KEYS
+                    .iter()
+                    .find(|(wi_key, _)| wi_key == key)
+                    .map_or(key.as_str(), |(_, ext)| ext);
+                extensions.insert(ext_key.to_string(), value.clone());
+            }
+        }
+    }
+    if let Some(position) = wi.get("position").and_then(|x| x.as_u64()) {
+        entry.position = Some(match position {
+            POSITION_BEFORE_CHAR => "before_char".to_string(),
+            _ => "after_char".to_string(),
+        });
+    }
+    entry.case_sensitive = wi.get("caseSensitive").and_then(|x| x.as_bool());
+    entry.extensions = extensions;
+    entry
+}
+
+/// Reads list of keys, which old files store as comma separated string.
+fn string_list(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| x.to_string())
+            .collect(),
+        Value::String(text) => text
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads lorebook from a card image or a World Info `.json` file
+pub fn read_lorebook_file(path: &Path) -> Result<CharacterBook> {
+    if path.extension().is_some_and(|x| x == "json") {
+        let text = std::fs::read_to_string(path)?;
+        let world_info: Value =
+            serde_json::from_str(&text).with_context(|| {
+                format!("{} is not a valid JSON", path.display())
+            })?;
+        return world_info_to_book(&world_info);
+    }
+    let image = tools::read_card_image_from_file(path)?;
+    let card = TavernCardV2::from_png_image(&image)?;
+    card.data
+        .character_book
+        .with_context(|| format!("{} has no lorebook", path.display()))
+}
+
+/// Saves lorebook of a card as World Info file <old_name>.lorebook.json
+pub fn export_lorebook_file(
+    path: &Path,
+    options: &ExportOptions,
+) -> Result<()> {
+    let image = tools::read_card_image_from_file(path)?;
+    let card = TavernCardV2::from_png_image(&image)?;
+    let Some(book) = &card.data.character_book else {
+        bail!("{} has no lorebook", path.display());
+    };
+    let world_info = book_to_world_info(book)?;
+
+    let fields = card.name_fields();
+    let Some(new_path) =
+        options.output_path(path, "{stem}.lorebook", fields, "json")?
+    else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    let json = serde_json::to_string_pretty(&world_info)?;
+    tools::replace_file(&new_path, json.as_bytes())?;
+    println!("Exported {} entries", book.entries.len());
+    println!("Done");
+    Ok(())
+}
+
+/// Embeds World Info file into a card, replacing its lorebook
+///
+/// Saves the result to lorebook.<old_name.png>. Lorebook settings of the
+/// card are kept, unless the World Info file carries its own.
+pub fn import_lorebook_file(
+    path: &Path,
+    world_info_path: &Path,
+    options: &WriteOptions,
+    placeholders: &PlaceholderOptions,
+) -> Result<()> {
+    let text = std::fs::read_to_string(world_info_path)?;
+    let world_info: Value = serde_json::from_str(&text).with_context(|| {
+        format!("{} is not a valid JSON", world_info_path.display())
+    })?;
+    let mut imported = world_info_to_book(&world_info)?;
+
+    let image = tools::read_card_image_from_file(path)?;
+    let (mut card, legacy_report) =
+        TavernCardV2::from_png_image_with_report(&image)?;
+    if let Some(legacy_report) = &legacy_report {
+        print!("{}", legacy_report);
+    }
+    // V1 cards come from apps with their own placeholders
+    if legacy_report.is_some_and(|x| x.is_v1()) {
+        let report = placeholders.normalize_v2(&mut card.data);
+        if !report.is_empty() {
+            print!("{}", report);
+        }
+    }
+    if world_info.get("originalData").is_none() {
+        if let Some(old_book) = card.data.character_book.take() {
+            imported = CharacterBook { entries: imported.entries, ..old_book };
+        }
+    }
+    if imported.name.is_none() {
+        imported.name = world_info_path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string());
+    }
+    println!("Imported {} entries", imported.entries.len());
+    // World Info files come from Tavern apps
+    let report = placeholders
+        .for_native_card()
+        .normalize_book(&mut imported, card.data.name.as_deref());
+    if !report.is_empty() {
+        print!("{}", report);
+    }
+    card.data.character_book = Some(imported);
+    card.sync_v1_fields(options.v1_fields);
+
+    let fields = card.name_fields();
+    let new_image = card.into_png_image_with_compression(options.compress)?;
+    let new_image = options.output_image(new_image)?;
+    let Some(new_path) = options.card_path(path, "lorebook.{stem}", fields)?
+    else {
+        return Ok(());
+    };
+    println!("Output file name: {}", new_path.display());
+    tools::write_image_to_file(&new_image, &new_path)?;
+    println!("Done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_world_info_to_book() -> Result<()> {
+        let world_info = json!({
+            "entries": {
+                "5": {
+                    "uid": 5,
+                    "key": ["dragon", "wyrm"],
+                    "keysecondary": [],
+                    "comment": "Dragons",
+                    "content": "Dragons are big.",
+                    "constant": false,
+                    "selective": true,
+                    "order": 90,
+                    "position": 4,
+                    "disable": true,
+                    "depth": 2,
+                    "caseSensitive": true,
+                    "outletName": "lore",
+                },
+                "1": {
+                    "uid": 1,
+                    "key": "castle, tower",
+                    "content": "The castle.",
+                    "position": 0,
+                },
+            }
+        });
+        let book = world_info_to_book(&world_info)?;
+        let [castle, dragon] = &book.entries[..] else {
+            panic!("Expected two entries");
+        };
+        assert_eq!(castle.keys, vec!["castle", "tower"]);
+        assert_eq!(castle.position.as_deref(), Some("before_char"));
+        assert!(castle.enabled);
+        assert_eq!(dragon.id, Some(5));
+        assert_eq!(dragon.insertion_order, Some(90));
+        assert_eq!(dragon.comment.as_deref(), Some("Dragons"));
+        assert!(!dragon.enabled);
+        assert_eq!(dragon.case_sensitive, Some(true));
+        assert_eq!(dragon.extensions["position"], 4);
+        assert_eq!(dragon.extensions["depth"], 2);
+        assert_eq!(dragon.extensions["outletName"], "lore");
+
+        // Nothing is lost when written back
+        let world_info2 = book_to_world_info(&book)?;
+        for (uid, entry) in world_info["entries"].as_object().unwrap() {
+            for (key, value) in entry.as_object().unwrap() {
+                let value2 = &world_info2["entries"][uid][key];
+                match (uid.as_str(), key.as_str()) {
+                    ("1", "key") => assert_eq!(value2, &json!(castle.keys)),
+                    _ => assert_eq!(value2, value, "{}.{}", uid, key),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_book_round_trip() -> Result<()> {
+        let card: TavernCardV2 = serde_json::from_str(include_str!(
+            "../testing/fixtures/real_world_card.json"
+        ))?;
+        let book = card.data.character_book.unwrap();
+        let book2 = world_info_to_book(&book_to_world_info(&book)?)?;
+        assert_eq!(book2.name, book.name);
+        assert_eq!(book2.scan_depth, book.scan_depth);
+        for (entry, entry2) in book.entries.iter().zip(&book2.entries) {
+            assert_eq!(entry2.keys, entry.keys);
+            assert_eq!(entry2.content, entry.content);
+            assert_eq!(entry2.enabled, entry.enabled);
+            assert_eq!(entry2.insertion_order, entry.insertion_order);
+            // World Info always has secondary keys and position.
+            assert_eq!(
+                entry2.secondary_keys.clone().unwrap_or_default(),
+                entry.secondary_keys.clone().unwrap_or_default()
+            );
+            assert_eq!(
+                entry2.position.as_deref(),
+                Some(entry.position.as_deref().unwrap_or("after_char"))
+            );
+            for (key, value) in &entry.extensions {
+                assert_eq!(&entry2.extensions[key], value, "{}", key);
+            }
+        }
+        Ok(())
+    }
+}